@@ -0,0 +1,139 @@
+//! Compiles the built frontend into the binary. Every file in `../frontend/build` becomes an
+//! entry of `assets.rs` in `OUT_DIR`, text files come with gzip and brotli versions compressed
+//! here once, so the server never compresses them per request.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+const FRONTEND_BUILD: &str = "../frontend/build";
+
+fn mime_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json" | "map") => "application/json",
+        Some("webmanifest") => "application/manifest+json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Images other than icons and svg, and fonts, are compressed already.
+fn compressible(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.starts_with("application/json")
+        || mime.starts_with("application/manifest")
+        || mime == "image/svg+xml"
+        || mime == "image/x-icon"
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn brotli(data: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let params = brotli::enc::BrotliEncoderParams {
+        quality: 11,
+        ..Default::default()
+    };
+    brotli::BrotliCompress(&mut &data[..], &mut compressed, &params).unwrap();
+    compressed
+}
+
+fn files(dir: &Path, found: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.map(Result::unwrap) {
+        let path = entry.path();
+        if path.is_dir() {
+            files(&path, found);
+        } else {
+            found.push(path);
+        }
+    }
+}
+
+/// Writes `data` to `OUT_DIR` under `name` and returns the expression that embeds it.
+fn embed(out_dir: &Path, name: String, data: &[u8]) -> String {
+    let path = out_dir.join(name);
+    fs::write(&path, data).unwrap();
+    format!(
+        "Some(include_bytes!({:?}) as &[u8])",
+        path.display().to_string()
+    )
+}
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let build = manifest_dir.join(FRONTEND_BUILD);
+    // watching a directory that does not exist would rebuild every time
+    if build.exists() {
+        println!("cargo:rerun-if-changed={FRONTEND_BUILD}");
+    } else {
+        println!("cargo:rerun-if-changed=../frontend");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let mut found = Vec::new();
+    files(&build, &mut found);
+    let mut found: Vec<(String, PathBuf)> = found
+        .into_iter()
+        .map(|path| {
+            let name = path
+                .strip_prefix(&build)
+                .unwrap()
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            (name, path)
+        })
+        .collect();
+    // in the order `assets::find` searches, which compares names and not path components
+    found.sort();
+    let mut assets = String::from("&[\n");
+    for (i, (name, path)) in found.iter().enumerate() {
+        let data = fs::read(path).unwrap();
+        let mime = mime_type(path);
+        let hash = hex::encode(&Sha256::digest(&data)[..16]);
+        let (mut gzip_data, mut brotli_data) = ("None".to_string(), "None".to_string());
+        if compressible(mime) {
+            let compressed = gzip(&data);
+            if compressed.len() < data.len() {
+                gzip_data = embed(&out_dir, format!("asset{i}.gz"), &compressed);
+            }
+            let compressed = brotli(&data);
+            if compressed.len() < data.len() {
+                brotli_data = embed(&out_dir, format!("asset{i}.br"), &compressed);
+            }
+        }
+        writeln!(
+            assets,
+            "    Asset {{ path: {name:?}, mime: {mime:?}, hash: {hash:?}, \
+             data: include_bytes!({:?}), gzip: {gzip_data}, brotli: {brotli_data} }},",
+            path.display().to_string()
+        )
+        .unwrap();
+    }
+    assets.push(']');
+    fs::write(out_dir.join("assets.rs"), assets).unwrap();
+}
@@ -0,0 +1,560 @@
+use salvo::prelude::*;
+use schemars::gen::SchemaSettings;
+use schemars::schema_for;
+use serde_json::{json, Value};
+
+use crate::api_tokens::{ApiTokenInfo, CreatedApiToken, NewApiToken};
+use crate::audit::{AuditOutcome, AuditRecord, DEFAULT_QUERY_LIMIT, MAX_QUERY_LIMIT};
+use crate::auth::{NewUser, User, UserUpdate, SESSION_COOKIE};
+use crate::page_interface::{PrinterUpdate, StatusJson, PRINT_ACTIONS};
+use crate::rest_api::{ActionBody, ApiError, ClaimBody, ErrorBody, LoginBody};
+use crate::ws_protocol::{ClientMessage, ServerMessage};
+
+/// Version of the REST API and websocket messages, shared by every document below.
+/// Bump it whenever a schema changes, the major version is the one in `/api/v1`.
+pub const API_VERSION: &str = "1.13.1";
+
+/// The OpenAPI 3 description of `/api/v1`, with schemas generated from the types the handlers use.
+pub fn openapi() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let statuses = generator.subschema_for::<Vec<StatusJson>>();
+    let status = generator.subschema_for::<StatusJson>();
+    let files = generator.subschema_for::<Vec<String>>();
+    let action_body = generator.subschema_for::<ActionBody>();
+    let claim_body = generator.subschema_for::<ClaimBody>();
+    let update = generator.subschema_for::<PrinterUpdate>();
+    let error = generator.subschema_for::<ErrorBody>();
+    let login_body = generator.subschema_for::<LoginBody>();
+    let user = generator.subschema_for::<User>();
+    let users = generator.subschema_for::<Vec<User>>();
+    let new_user = generator.subschema_for::<NewUser>();
+    let user_update = generator.subschema_for::<UserUpdate>();
+    let tokens = generator.subschema_for::<Vec<ApiTokenInfo>>();
+    let new_token = generator.subschema_for::<NewApiToken>();
+    let created_token = generator.subschema_for::<CreatedApiToken>();
+    let audit_records = generator.subschema_for::<Vec<AuditRecord>>();
+    let audit_outcome = generator.subschema_for::<AuditOutcome>();
+    let ok = |description: &str, schema: &_| {
+        json!({
+            "description": description,
+            "content": {"application/json": {"schema": schema}},
+        })
+    };
+    let failed = |description: &str| ok(description, &error);
+    let body = |schema: &_| {
+        json!({
+            "required": true,
+            "content": {"application/json": {"schema": schema}},
+        })
+    };
+    let name = json!({
+        "name": "name",
+        "in": "path",
+        "required": true,
+        "schema": {"type": "string"},
+    });
+    let username = json!({
+        "name": "username",
+        "in": "path",
+        "required": true,
+        "schema": {"type": "string"},
+    });
+    let unauthorized = failed("Not logged in");
+    let forbidden = failed("The user's role does not allow this");
+    // served next to `/api/v1` rather than under it
+    let root = json!([{"url": "/"}]);
+    let query = |name: &str, description: &str, schema: Value| {
+        json!({
+            "name": name,
+            "in": "query",
+            "required": false,
+            "description": description,
+            "schema": schema,
+        })
+    };
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Rusty Printer Monitor",
+            "version": API_VERSION,
+        },
+        "servers": [{"url": "/api/v1"}],
+        "security": [{"session": []}, {"token": []}],
+        "paths": {
+            "/login": {
+                "post": {
+                    "operationId": "login",
+                    "summary": "Starts a session and sets its cookie",
+                    "security": [],
+                    "requestBody": body(&login_body),
+                    "responses": {
+                        "200": ok("The logged in user", &user),
+                        "401": failed("Wrong username or password"),
+                    },
+                },
+            },
+            "/logout": {
+                "post": {
+                    "operationId": "logout",
+                    "summary": "Ends the session and clears its cookie",
+                    "security": [],
+                    "responses": {"204": {"description": "Logged out"}},
+                },
+            },
+            "/me": {
+                "get": {
+                    "operationId": "getCurrentUser",
+                    "summary": "The logged in user",
+                    "responses": {
+                        "200": ok("The user", &user),
+                        "401": unauthorized,
+                    },
+                },
+            },
+            "/tokens": {
+                "get": {
+                    "operationId": "listApiTokens",
+                    "summary": "Every API token without its secret, for admins",
+                    "responses": {
+                        "200": ok("Every token", &tokens),
+                        "401": unauthorized,
+                        "403": forbidden,
+                    },
+                },
+                "post": {
+                    "operationId": "createApiToken",
+                    "summary": "Creates an API token, read-only unless a role is given",
+                    "requestBody": body(&new_token),
+                    "responses": {
+                        "201": ok("The token, which is not shown again", &created_token),
+                        "400": failed("Invalid body or an empty name"),
+                        "401": unauthorized,
+                        "403": forbidden,
+                        "409": failed("The random id is already used, try again"),
+                    },
+                },
+            },
+            "/audit": {
+                "get": {
+                    "operationId": "queryAudit",
+                    "summary": "Who ran which command or changed the config, newest first, for admins",
+                    "parameters": [
+                        {"name": "actor", "in": "query", "schema": {"type": "string"}},
+                        {"name": "printer", "in": "query", "schema": {"type": "string"}},
+                        {"name": "action", "in": "query", "schema": {"type": "string"}},
+                        {"name": "outcome", "in": "query", "schema": audit_outcome},
+                        {"name": "from", "in": "query", "schema": {"type": "string", "format": "date-time"}},
+                        {
+                            "name": "to",
+                            "in": "query",
+                            "description": "Exclusive",
+                            "schema": {"type": "string", "format": "date-time"},
+                        },
+                        {
+                            "name": "limit",
+                            "in": "query",
+                            "schema": {"type": "integer", "minimum": 0, "maximum": MAX_QUERY_LIMIT, "default": DEFAULT_QUERY_LIMIT},
+                        },
+                    ],
+                    "responses": {
+                        "200": ok("The matching records", &audit_records),
+                        "400": failed("Invalid query"),
+                        "401": unauthorized,
+                        "403": forbidden,
+                    },
+                },
+            },
+            "/tokens/{id}": {
+                "delete": {
+                    "operationId": "revokeApiToken",
+                    "summary": "Revokes an API token",
+                    "parameters": [{
+                        "name": "id",
+                        "in": "path",
+                        "required": true,
+                        "schema": {"type": "string"},
+                    }],
+                    "responses": {
+                        "204": {"description": "The token was revoked"},
+                        "401": unauthorized,
+                        "403": forbidden,
+                        "404": failed("No token has that id"),
+                    },
+                },
+            },
+            "/users": {
+                "get": {
+                    "operationId": "listUsers",
+                    "summary": "Every account, for admins",
+                    "responses": {
+                        "200": ok("Every account", &users),
+                        "401": unauthorized,
+                        "403": forbidden,
+                    },
+                },
+                "post": {
+                    "operationId": "addUser",
+                    "summary": "Creates an account, a viewer unless a role is given",
+                    "requestBody": body(&new_user),
+                    "responses": {
+                        "201": {"description": "The account was created"},
+                        "400": failed("Invalid body, or an empty username or password"),
+                        "401": unauthorized,
+                        "403": forbidden,
+                        "409": failed("The username is taken"),
+                    },
+                },
+            },
+            "/users/{username}": {
+                "patch": {
+                    "operationId": "updateUser",
+                    "summary": "Changes the password or role of an account",
+                    "parameters": [username],
+                    "requestBody": body(&user_update),
+                    "responses": {
+                        "204": {"description": "The account was updated"},
+                        "400": failed("Invalid body or an empty password"),
+                        "401": unauthorized,
+                        "403": failed("Only admins can change roles or other accounts"),
+                        "404": failed("No account has that username"),
+                        "409": failed("No admin would be left"),
+                    },
+                },
+                "delete": {
+                    "operationId": "removeUser",
+                    "summary": "Deletes an account and ends its sessions",
+                    "parameters": [username],
+                    "responses": {
+                        "204": {"description": "The account was deleted"},
+                        "401": unauthorized,
+                        "403": forbidden,
+                        "404": failed("No account has that username"),
+                        "409": failed("It is the last admin"),
+                    },
+                },
+            },
+            "/printers": {
+                "get": {
+                    "operationId": "listPrinters",
+                    "summary": "The status of every printer as of the last poll",
+                    "responses": {
+                        "200": ok("Every configured printer", &statuses),
+                        "401": unauthorized,
+                    },
+                },
+            },
+            "/printers/{name}": {
+                "get": {
+                    "operationId": "getPrinter",
+                    "summary": "The current status of one printer",
+                    "parameters": [name],
+                    "responses": {
+                        "200": ok("The printer", &status),
+                        "401": unauthorized,
+                        "404": failed("No printer has that name"),
+                    },
+                },
+                "patch": {
+                    "operationId": "updatePrinter",
+                    "summary": "Renames a printer or changes its settings",
+                    "parameters": [name],
+                    "requestBody": body(&update),
+                    "responses": {
+                        "204": {"description": "The printer was updated"},
+                        "400": failed("Invalid body or an empty name"),
+                        "401": unauthorized,
+                        "403": failed("Only admins can change printers"),
+                        "404": failed("No printer has that name"),
+                        "409": failed("The new name or address is used by another printer"),
+                    },
+                },
+            },
+            "/printers/{name}/files": {
+                "get": {
+                    "operationId": "listPrinterFiles",
+                    "summary": "The files stored on a printer",
+                    "parameters": [name],
+                    "responses": {
+                        "200": ok("File names, empty when the printer is offline", &files),
+                        "401": unauthorized,
+                        "404": failed("No printer has that name"),
+                    },
+                },
+            },
+            "/printers/{name}/actions/{action}": {
+                "post": {
+                    "operationId": "runPrinterAction",
+                    "summary": "Controls the print on a printer",
+                    "parameters": [
+                        name,
+                        {
+                            "name": "action",
+                            "in": "path",
+                            "required": true,
+                            "schema": {"type": "string", "enum": PRINT_ACTIONS},
+                        },
+                    ],
+                    "requestBody": {
+                        "required": false,
+                        "content": {"application/json": {"schema": action_body}},
+                    },
+                    "responses": {
+                        "204": {"description": "The printer accepted the command"},
+                        "400": failed("Unsupported action, invalid body or `start` without a file"),
+                        "401": unauthorized,
+                        "403": failed("Viewers can not control printers"),
+                        "404": failed("No printer has that name"),
+                        "409": failed("Someone else claimed the printer"),
+                        "428": failed(
+                            "A stop has to be confirmed, send it again with the `confirmation` of this answer",
+                        ),
+                        "502": failed("The printer did not answer or refused the command"),
+                    },
+                },
+            },
+            "/printers/{name}/claim": {
+                "post": {
+                    "operationId": "claimPrinter",
+                    "summary": "Holds a printer so nobody else can control its prints, or renews the claim",
+                    "parameters": [name],
+                    "requestBody": {
+                        "required": false,
+                        "content": {"application/json": {"schema": claim_body}},
+                    },
+                    "responses": {
+                        "204": {"description": "The user holds the printer"},
+                        "400": failed("Invalid body or length"),
+                        "401": unauthorized,
+                        "403": failed("Viewers can not claim printers"),
+                        "404": failed("No printer has that name"),
+                        "409": failed("Someone else claimed the printer"),
+                    },
+                },
+                "delete": {
+                    "operationId": "releasePrinter",
+                    "summary": "Releases a claim, admins can release the claims of others",
+                    "parameters": [name],
+                    "responses": {
+                        "204": {"description": "Nobody holds the printer"},
+                        "401": unauthorized,
+                        "403": failed("Someone else claimed the printer"),
+                    },
+                },
+            },
+            "/openapi.json": {
+                "get": {
+                    "operationId": "getOpenApi",
+                    "summary": "This document",
+                    "security": [],
+                    "responses": {
+                        "200": {
+                            "description": "The OpenAPI description of the API",
+                            "content": {"application/json": {"schema": {"type": "object"}}},
+                        },
+                    },
+                },
+            },
+            "/schemas/{name}": {
+                "get": {
+                    "operationId": "getWebsocketSchema",
+                    "summary": "The JSON Schema of the messages a websocket client sends or receives",
+                    "security": [],
+                    "parameters": [{
+                        "name": "name",
+                        "in": "path",
+                        "required": true,
+                        "schema": {"type": "string", "enum": ["client_message.json", "server_message.json"]},
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "The schema",
+                            "content": {"application/json": {"schema": {"type": "object"}}},
+                        },
+                        "404": failed("No schema has that name"),
+                    },
+                },
+            },
+            "/events": {
+                "servers": root,
+                "get": {
+                    "operationId": "streamEvents",
+                    "summary": "The websocket broadcasts as Server-Sent Events named by their `type`",
+                    "parameters": [{
+                        "name": "Last-Event-ID",
+                        "in": "header",
+                        "required": false,
+                        "description": "Replays the broadcasts after this one, if they are still kept",
+                        "schema": {"type": "integer"},
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "A `printers` snapshot or the missed broadcasts, then every new one",
+                            "content": {"text/event-stream": {"schema": {"type": "string"}}},
+                        },
+                        "401": unauthorized,
+                    },
+                },
+            },
+            "/history/export": {
+                "servers": root,
+                "get": {
+                    "operationId": "exportHistory",
+                    "summary": "The finished, stopped and failed jobs",
+                    "parameters": [
+                        query("printer", "Only the jobs of this printer", json!({"type": "string"})),
+                        query("from", "RFC 3339, jobs started at or after it", json!({"type": "string", "format": "date-time"})),
+                        query("to", "RFC 3339, jobs started before it", json!({"type": "string", "format": "date-time"})),
+                        query("outcome", "Only jobs that ended this way", json!({"type": "string", "enum": ["finished", "stopped", "failed"]})),
+                        query("format", "`csv` by default", json!({"type": "string", "enum": ["csv", "ndjson"]})),
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "A job per line",
+                            "content": {
+                                "text/csv": {"schema": {"type": "string"}},
+                                "application/x-ndjson": {"schema": {"type": "string"}},
+                            },
+                        },
+                        "400": {"description": "A time or outcome that does not parse"},
+                        "401": unauthorized,
+                    },
+                },
+            },
+            "/metrics": {
+                "servers": root,
+                "get": {
+                    "operationId": "getMetrics",
+                    "summary": "Prometheus metrics of the printers, their UDP requests and the websocket",
+                    "responses": {
+                        "200": {
+                            "description": "The Prometheus text format",
+                            "content": {"text/plain": {"schema": {"type": "string"}}},
+                        },
+                        "401": unauthorized,
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": generator.definitions(),
+            "securitySchemes": {
+                "session": {"type": "apiKey", "in": "cookie", "name": SESSION_COOKIE},
+                "token": {"type": "http", "scheme": "bearer"},
+            },
+        },
+    })
+}
+
+/// JSON Schemas of the websocket messages, by the name they are served under.
+pub fn websocket_schemas() -> Vec<(&'static str, Value)> {
+    let mut client = json!(schema_for!(ClientMessage));
+    client["$comment"] = json!(format!("API version {API_VERSION}"));
+    let mut server = json!(schema_for!(ServerMessage));
+    server["$comment"] = json!(format!("API version {API_VERSION}"));
+    vec![
+        ("client_message.json", client),
+        ("server_message.json", server),
+    ]
+}
+
+/// `GET /api/v1/openapi.json`
+#[handler]
+pub async fn openapi_json() -> Json<Value> {
+    Json(openapi())
+}
+
+/// `GET /api/v1/schemas/{client_message.json|server_message.json}`
+#[handler]
+pub async fn json_schema(req: &mut Request) -> Result<Json<Value>, ApiError> {
+    let name = req.param::<String>("name").unwrap_or_default();
+    websocket_schemas()
+        .into_iter()
+        .find(|(schema_name, _)| *schema_name == name)
+        .map(|(_, schema)| Json(schema))
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("No schema called {name}")))
+}
+
+/// Compares the documents with the copies in `api/schemas`, which record the last released
+/// contract. Run with `UPDATE_SCHEMAS=1` after bumping `API_VERSION` to record the new one.
+#[test]
+fn test_schemas_match_version() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("schemas");
+    let mut documents = websocket_schemas();
+    documents.push(("openapi.json", openapi()));
+    let recorded_version = std::fs::read_to_string(dir.join("openapi.json"))
+        .ok()
+        .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+        .and_then(|openapi| openapi["info"]["version"].as_str().map(String::from));
+    let changed: Vec<_> = documents
+        .iter()
+        .filter(|(name, document)| {
+            let recorded = std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+            recorded != serde_json::to_string_pretty(document).unwrap() + "\n"
+        })
+        .collect();
+    if changed.is_empty() {
+        return;
+    }
+    let names: Vec<_> = changed.iter().map(|(name, _)| *name).collect();
+    assert_ne!(
+        recorded_version.as_deref(),
+        Some(API_VERSION),
+        "{names:?} changed, bump API_VERSION and run the tests with UPDATE_SCHEMAS=1"
+    );
+    assert!(
+        std::env::var_os("UPDATE_SCHEMAS").is_some(),
+        "{names:?} changed for version {API_VERSION}, run the tests with UPDATE_SCHEMAS=1 to record them"
+    );
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, document) in changed {
+        std::fs::write(
+            dir.join(name),
+            serde_json::to_string_pretty(document).unwrap() + "\n",
+        )
+        .unwrap();
+    }
+}
+
+/// Every route the server answers, except the websocket which the message schemas describe and
+/// the assets, must be in the document.
+#[test]
+fn test_openapi_covers_routes() {
+    fn routes(router: &Router, path: &str, method: &str, found: &mut Vec<(String, String)>) {
+        let mut path = path.to_string();
+        let mut method = method.to_string();
+        for filter in router.filters() {
+            let filter = format!("{filter:?}");
+            if let Some(segment) = filter.strip_prefix("path:") {
+                path = format!("{path}/{}", segment.trim_matches('/'));
+            } else if let Some(name) = filter.strip_prefix("method:") {
+                method = name.to_lowercase();
+            }
+        }
+        if router.goal.is_some() {
+            found.push((path.clone(), method.clone()));
+        }
+        for child in router.routers() {
+            routes(child, &path, &method, found);
+        }
+    }
+    let mut found = Vec::new();
+    routes(
+        &crate::router(&crate::cli::Settings::default()),
+        "",
+        "",
+        &mut found,
+    );
+    let document = openapi();
+    let mut missing = Vec::new();
+    for (path, method) in found {
+        if path == "/ws" || path.contains("<**") {
+            continue;
+        }
+        let path = path.strip_prefix("/api/v1").unwrap_or(&path);
+        let path = path.replace('<', "{").replace('>', "}");
+        if document["paths"][&path][&method].is_null() {
+            missing.push(format!("{method} {path}"));
+        }
+    }
+    assert!(missing.is_empty(), "not in the document: {missing:?}");
+}
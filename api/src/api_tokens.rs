@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::auth::{Role, User};
+use crate::config_file::ConfigVersion;
+use crate::page_interface::CommandError;
+use crate::{audit, config_file};
+
+/// Every token starts with this, so they are easy to spot in scripts and secret scanners.
+const TOKEN_PREFIX: &str = "rpm_";
+
+/// How often `last_used` is written to the config file, so a busy script does not rewrite the
+/// file on every request.
+const LAST_USED_FLUSH: std::time::Duration = std::time::Duration::from_secs(60);
+
+type Tokens = BTreeMap<String, ApiTokenConfig>;
+
+/// The tokens of the config file, read again only once the file has changed.
+static TOKENS: Lazy<Mutex<Option<(ConfigVersion, Tokens)>>> = Lazy::new(|| Mutex::new(None));
+
+/// When tokens were used since `last_used` was last written to the config file, by id.
+static LAST_USED: Lazy<Mutex<HashMap<String, OffsetDateTime>>> = Lazy::new(Mutex::default);
+
+/// A token for scripts and integrations, stored under `"api_tokens": {"<id>": {...}}` in the
+/// config file. Only a hash of the secret is kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiTokenConfig {
+    pub name: String,
+    /// Hex SHA-256 of the secret part of the token.
+    pub token_hash: String,
+    pub role: Role,
+    /// Limits the printers the token can change, all of them if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub printers: Vec<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(
+        default,
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub last_used: Option<OffsetDateTime>,
+}
+
+/// What admins see of a token, never the token itself.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub role: Role,
+    pub printers: Vec<String>,
+    /// RFC 3339 timestamp.
+    pub created_at: String,
+    /// RFC 3339 timestamp, accurate to a minute, missing if the token was never used.
+    pub last_used: Option<String>,
+}
+
+/// A token to create, read-only unless `role` says otherwise.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+pub struct NewApiToken {
+    pub name: String,
+    #[serde(default)]
+    pub role: Option<Role>,
+    /// Limits the printers the token can change, all of them if empty.
+    #[serde(default)]
+    pub printers: Vec<String>,
+}
+
+/// A new token, the only time `token` is shown.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct CreatedApiToken {
+    pub id: String,
+    /// Send as `Authorization: Bearer <token>`.
+    pub token: String,
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Splits `rpm_<id>_<secret>` into its id and secret.
+fn parse_token(token: &str) -> Option<(&str, &str)> {
+    token
+        .strip_prefix(TOKEN_PREFIX)?
+        .split_once('_')
+        .filter(|(id, secret)| !id.is_empty() && !secret.is_empty())
+}
+
+/// The user a token acts as, if `token` matches one of `tokens`.
+fn token_user(tokens: &BTreeMap<String, ApiTokenConfig>, token: &str) -> Option<(String, User)> {
+    let (id, secret) = parse_token(token)?;
+    let config = tokens
+        .get(id)
+        .filter(|config| config.token_hash == hash_secret(secret))?;
+    let user = User {
+        username: format!("token:{id}"),
+        display_name: Some(config.name.clone()),
+        role: config.role,
+        printers: config.printers.clone(),
+        address: None,
+    };
+    Some((id.to_string(), user))
+}
+
+/// Checks a bearer token against the tokens of the config file and notes that it was used. The
+/// file is only read again once it has changed.
+pub fn authenticate(token: &str) -> Option<User> {
+    // malformed tokens are refused without looking at the config file
+    parse_token(token)?;
+    let version = config_file::config_version();
+    let mut cache = TOKENS.lock().unwrap();
+    if cache.as_ref().is_none_or(|(cached, _)| *cached != version) {
+        let tokens = config_file::read_config_file().ok()?.api_tokens;
+        *cache = Some((version, tokens));
+    }
+    let (_, tokens) = cache.as_ref()?;
+    let (id, user) = token_user(tokens, token)?;
+    drop(cache);
+    LAST_USED
+        .lock()
+        .unwrap()
+        .insert(id, OffsetDateTime::now_utc());
+    Some(user)
+}
+
+/// Sets `last_used` of the tokens in `used` that still exist.
+///
+/// # Returns
+/// Whether any token was changed.
+fn apply_last_used(tokens: &mut Tokens, used: &HashMap<String, OffsetDateTime>) -> bool {
+    let mut changed = false;
+    for (id, at) in used {
+        if let Some(config) = tokens.get_mut(id) {
+            config.last_used = Some(*at);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Writes when tokens were last used to the config file every `LAST_USED_FLUSH`.
+pub async fn flush_last_used() {
+    let mut interval = tokio::time::interval(LAST_USED_FLUSH);
+    loop {
+        interval.tick().await;
+        let used = std::mem::take(&mut *LAST_USED.lock().unwrap());
+        if used.is_empty() {
+            continue;
+        }
+        if let Err(e) = config_file::update_api_tokens(|tokens| apply_last_used(tokens, &used)) {
+            tracing::warn!("Unable to note the use of API tokens: {e}");
+        }
+    }
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default()
+}
+
+/// Every token, without their secrets.
+///
+/// # Errors
+/// `Forbidden` unless `caller` is an admin of every printer, `Config` if the config file can not be read.
+pub fn list_tokens(caller: &User) -> Result<Vec<ApiTokenInfo>, CommandError> {
+    caller.require_unscoped(Role::Admin)?;
+    let mut tokens = config_file::read_config_file()
+        .map_err(|e| CommandError::Config(e.to_string()))?
+        .api_tokens;
+    // uses that have not been written yet
+    apply_last_used(&mut tokens, &LAST_USED.lock().unwrap());
+    Ok(tokens
+        .into_iter()
+        .map(|(id, config)| ApiTokenInfo {
+            id,
+            name: config.name,
+            role: config.role,
+            printers: config.printers,
+            created_at: format_time(config.created_at),
+            last_used: config.last_used.map(format_time),
+        })
+        .collect())
+}
+
+/// Creates a token and returns it, it can not be shown again.
+///
+/// # Errors
+/// `Forbidden` unless `caller` is an admin of every printer, `MissingField` for an empty name, `Conflict`
+/// in the rare case that the random id is already used, `Config` if the config file can not be written.
+pub fn create_token(
+    caller: &User,
+    new_token: NewApiToken,
+) -> Result<CreatedApiToken, CommandError> {
+    let id = random_hex(4);
+    let role = new_token.role.unwrap_or(Role::Viewer);
+    let params = json!({
+        "id": id,
+        "name": new_token.name,
+        "role": role,
+        "printers": new_token.printers,
+    });
+    audit::audited_sync(caller, "create_token", None, params, || {
+        caller.require_unscoped(Role::Admin)?;
+        if new_token.name.trim().is_empty() {
+            return Err(CommandError::MissingField("name"));
+        }
+        let secret = random_hex(32);
+        let config = ApiTokenConfig {
+            name: new_token.name.clone(),
+            token_hash: hash_secret(&secret),
+            role,
+            printers: new_token.printers,
+            created_at: OffsetDateTime::now_utc(),
+            last_used: None,
+        };
+        let mut added = false;
+        config_file::update_api_tokens(|tokens| {
+            // never replace a token whose random id came up again
+            added = !tokens.contains_key(&id);
+            if added {
+                tokens.insert(id.clone(), config);
+            }
+            added
+        })
+        .map_err(|e| CommandError::Config(e.to_string()))?;
+        if !added {
+            return Err(CommandError::Conflict(format!(
+                "The API token id {id} is taken, try again"
+            )));
+        }
+        tracing::info!(
+            "{} created the API token {id} called {}",
+            caller.username,
+            new_token.name
+        );
+        Ok(CreatedApiToken {
+            token: format!("{TOKEN_PREFIX}{id}_{secret}"),
+            id,
+        })
+    })
+}
+
+/// Deletes a token, requests with it are refused from then on.
+///
+/// # Errors
+/// `Forbidden` unless `caller` is an admin of every printer, `UnknownToken` if there is no token with `id`,
+/// `Config` if the config file can not be written.
+pub fn revoke_token(caller: &User, id: &str) -> Result<(), CommandError> {
+    let params = json!({ "id": id });
+    audit::audited_sync(caller, "revoke_token", None, params, || {
+        caller.require_unscoped(Role::Admin)?;
+        let mut found = false;
+        config_file::update_api_tokens(|tokens| {
+            found = tokens.remove(id).is_some();
+            found
+        })
+        .map_err(|e| CommandError::Config(e.to_string()))?;
+        if !found {
+            return Err(CommandError::UnknownToken(id.to_string()));
+        }
+        tracing::info!("{} revoked the API token {id}", caller.username);
+        Ok(())
+    })
+}
+
+#[test]
+fn test_tokens() {
+    let secret = "5ecret";
+    let tokens = BTreeMap::from([(
+        "ab12".to_string(),
+        ApiTokenConfig {
+            name: "ci".to_string(),
+            token_hash: hash_secret(secret),
+            role: Role::Operator,
+            printers: vec!["printer1".to_string()],
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            last_used: None,
+        },
+    )]);
+    let (id, user) = token_user(&tokens, "rpm_ab12_5ecret").unwrap();
+    assert_eq!(id, "ab12");
+    assert_eq!(user.username, "token:ab12");
+    assert_eq!(user.display_name.as_deref(), Some("ci"));
+    assert_eq!(user.require_printer(Role::Operator, "printer1"), Ok(()));
+    assert!(matches!(
+        user.require_printer(Role::Operator, "printer2"),
+        Err(CommandError::Forbidden(_))
+    ));
+    assert_eq!(token_user(&tokens, "rpm_ab12_wrong"), None);
+    assert_eq!(token_user(&tokens, "rpm_cd34_5ecret"), None);
+    assert_eq!(token_user(&tokens, "ab12_5ecret"), None);
+    assert_eq!(token_user(&tokens, "rpm_ab12_"), None);
+
+    let mut tokens = tokens;
+    let used = HashMap::from([
+        ("ab12".to_string(), OffsetDateTime::UNIX_EPOCH),
+        ("gone".to_string(), OffsetDateTime::UNIX_EPOCH),
+    ]);
+    assert!(apply_last_used(&mut tokens, &used));
+    assert_eq!(tokens["ab12"].last_used, Some(OffsetDateTime::UNIX_EPOCH));
+    assert!(!tokens.contains_key("gone"));
+    assert!(!apply_last_used(&mut tokens, &HashMap::new()));
+}
@@ -0,0 +1,154 @@
+use salvo::http::header::{
+    ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY,
+};
+use salvo::http::HeaderValue;
+use salvo::prelude::*;
+
+/// A file of the built frontend, compiled into the binary by `build.rs`.
+#[derive(Debug)]
+pub struct Asset {
+    /// Relative to `frontend/build`, with `/` between directories.
+    pub path: &'static str,
+    pub mime: &'static str,
+    /// Hex of the start of the SHA-256 of `data`, the ETag is made from it.
+    pub hash: &'static str,
+    pub data: &'static [u8],
+    /// `data` compressed at build time, missing when it would not get smaller.
+    pub gzip: Option<&'static [u8]>,
+    pub brotli: Option<&'static [u8]>,
+}
+
+/// Sorted by path.
+static ASSETS: &[Asset] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+/// The embedded file for a request path, `index.html` for directories.
+fn find(path: &str) -> Option<&'static Asset> {
+    let path = path.trim_start_matches('/');
+    let path = if path.is_empty() || path.ends_with('/') {
+        format!("{path}index.html")
+    } else {
+        path.to_string()
+    };
+    ASSETS
+        .binary_search_by(|asset| asset.path.cmp(&path))
+        .ok()
+        .map(|i| &ASSETS[i])
+}
+
+/// Whether the frontend was built before the binary, without it only the API is served.
+pub fn embedded() -> bool {
+    !ASSETS.is_empty()
+}
+
+/// Whether an `Accept-Encoding` header allows `encoding`.
+fn accepts(accept_encoding: &str, encoding: &str) -> bool {
+    accept_encoding.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        parts.next() == Some(encoding)
+            && parts.all(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_none_or(|q| q > 0.0)
+            })
+    })
+}
+
+/// The body, content encoding and ETag to answer with, preferring brotli over gzip.
+fn representation(
+    asset: &Asset,
+    accept_encoding: &str,
+) -> (&'static [u8], Option<&'static str>, String) {
+    match (asset.brotli, asset.gzip) {
+        (Some(data), _) if accepts(accept_encoding, "br") => {
+            (data, Some("br"), format!("\"{}-br\"", asset.hash))
+        }
+        (_, Some(data)) if accepts(accept_encoding, "gzip") => {
+            (data, Some("gzip"), format!("\"{}-gz\"", asset.hash))
+        }
+        _ => (asset.data, None, format!("\"{}\"", asset.hash)),
+    }
+}
+
+/// Whether an `If-None-Match` header names `etag`, so the client has it already.
+fn not_modified(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+/// Serves the embedded frontend. Files under `static/` have the hash of their contents in
+/// their name, so browsers keep them for a year, everything else is revalidated by its ETag.
+#[handler]
+pub async fn serve_asset(req: &mut Request, res: &mut Response) {
+    let Some(asset) = find(req.uri().path()) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let header = |req: &Request, name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let (data, encoding, etag) = representation(asset, &header(req, ACCEPT_ENCODING));
+    let cache_control = if asset.path.starts_with("static/") {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    };
+    let headers = res.headers_mut();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    headers.insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        headers.insert(ETAG, value);
+    }
+    if not_modified(&header(req, IF_NONE_MATCH), &etag) {
+        res.status_code(StatusCode::NOT_MODIFIED);
+        return;
+    }
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(asset.mime));
+    if let Some(encoding) = encoding {
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    }
+    let _ = res.write_body(data);
+}
+
+#[test]
+fn test_assets() {
+    assert!(ASSETS.windows(2).all(|pair| pair[0].path < pair[1].path));
+    assert!(find("/../config.txt").is_none());
+
+    let asset = Asset {
+        path: "static/js/main.1234.js",
+        mime: "text/javascript; charset=utf-8",
+        hash: "abcd",
+        data: b"console.log(1)",
+        gzip: Some(b"gz"),
+        brotli: Some(b"br"),
+    };
+    assert_eq!(
+        representation(&asset, "gzip, deflate, br"),
+        (&b"br"[..], Some("br"), "\"abcd-br\"".to_string())
+    );
+    assert_eq!(representation(&asset, "gzip, br;q=0").1, Some("gzip"));
+    assert_eq!(representation(&asset, "gzip;q=0.5").1, Some("gzip"));
+    assert_eq!(
+        representation(&asset, ""),
+        (&b"console.log(1)"[..], None, "\"abcd\"".to_string())
+    );
+    let uncompressed = Asset {
+        brotli: None,
+        gzip: None,
+        ..asset
+    };
+    assert_eq!(representation(&uncompressed, "br").1, None);
+
+    assert!(not_modified("\"abcd-br\"", "\"abcd-br\""));
+    assert!(not_modified("\"old\", W/\"abcd\"", "\"abcd\""));
+    assert!(!not_modified("\"abcd\"", "\"abcd-gz\""));
+    assert!(!not_modified("", "\"abcd\""));
+}
@@ -0,0 +1,376 @@
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+use crate::auth::{Role, User};
+use crate::config_file;
+use crate::page_interface::CommandError;
+use crate::paths;
+
+static AUDIT_FILE: Lazy<PathBuf> = Lazy::new(|| paths::data_file("audit.jsonl"));
+
+/// Records a query returns unless it asks for another number.
+pub const DEFAULT_QUERY_LIMIT: usize = 100;
+
+/// Most records a query returns.
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+/// Keeps appends and the pruning that rewrites the file from interleaving.
+static AUDIT_LOCK: Mutex<()> = Mutex::new(());
+
+fn default_retention_days() -> u32 {
+    365
+}
+
+fn is_default_retention_days(days: &u32) -> bool {
+    *days == default_retention_days()
+}
+
+/// Settings of the audit log, stored under `"audit"` in the config file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditConfig {
+    /// Records older than this many days are dropped, once a day.
+    #[serde(
+        default = "default_retention_days",
+        skip_serializing_if = "is_default_retention_days"
+    )]
+    pub retention_days: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            retention_days: default_retention_days(),
+        }
+    }
+}
+
+impl AuditConfig {
+    pub fn is_default(&self) -> bool {
+        *self == AuditConfig::default()
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Ok,
+    /// The user or token was not allowed to do it.
+    Denied,
+    /// A destructive command that is waiting to be confirmed.
+    Unconfirmed,
+    Failed,
+}
+
+/// One command or config change, stored as a line of JSON in `audit.jsonl`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// RFC 3339 timestamp.
+    #[serde(with = "time::serde::rfc3339")]
+    #[schemars(with = "String")]
+    pub at: OffsetDateTime,
+    /// The username, `token:<id>` for API tokens, `cli:<login>` for the command line client or
+    /// `system` for the monitor itself.
+    pub actor: String,
+    /// Where the request came from, missing for changes the monitor made itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<IpAddr>,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub printer: Option<String>,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
+    pub outcome: AuditOutcome,
+    /// Why it was denied or failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new<T>(
+        user: &User,
+        action: &str,
+        printer: Option<&str>,
+        params: Value,
+        result: &Result<T, CommandError>,
+    ) -> Self {
+        let (outcome, error) = match result {
+            Ok(_) => (AuditOutcome::Ok, None),
+            Err(e @ (CommandError::Forbidden(_) | CommandError::Unauthorized)) => {
+                (AuditOutcome::Denied, Some(e.to_string()))
+            }
+            Err(e @ CommandError::ConfirmationRequired(_)) => {
+                (AuditOutcome::Unconfirmed, Some(e.to_string()))
+            }
+            Err(e) => (AuditOutcome::Failed, Some(e.to_string())),
+        };
+        AuditRecord {
+            at: OffsetDateTime::now_utc(),
+            actor: user.username.clone(),
+            address: user.address,
+            action: action.to_string(),
+            printer: printer.map(str::to_string),
+            params,
+            outcome,
+            error,
+        }
+    }
+}
+
+/// The user the monitor acts as when it changes the config file by itself.
+pub fn system_user() -> User {
+    User::new("system", Role::Admin)
+}
+
+/// Records of the commands run by tests, which do not touch the audit log.
+#[cfg(test)]
+pub static RECORDED: Mutex<Vec<AuditRecord>> = Mutex::new(Vec::new());
+
+#[cfg(not(test))]
+fn write_record(record: &AuditRecord) -> Result<(), io::Error> {
+    use std::io::Write;
+
+    let _lock = AUDIT_LOCK.lock().unwrap();
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&*AUDIT_FILE)?;
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+}
+
+#[cfg(test)]
+fn write_record(record: &AuditRecord) -> Result<(), io::Error> {
+    RECORDED.lock().unwrap().push(record.clone());
+    Ok(())
+}
+
+/// Appends `record` to the audit log. A log that can not be written does not stop the command,
+/// it is only reported.
+pub fn append(record: &AuditRecord) {
+    if let Err(e) = write_record(record) {
+        tracing::error!("Unable to write to the audit log: {e}, lost {record:?}");
+    }
+}
+
+/// Runs a command and records who ran it and how it went.
+pub async fn audited<T>(
+    user: &User,
+    action: &str,
+    printer: Option<&str>,
+    params: Value,
+    command: impl Future<Output = Result<T, CommandError>>,
+) -> Result<T, CommandError> {
+    let result = command.await;
+    append(&AuditRecord::new(user, action, printer, params, &result));
+    result
+}
+
+/// `audited` for changes that do not wait on anything.
+pub fn audited_sync<T>(
+    user: &User,
+    action: &str,
+    printer: Option<&str>,
+    params: Value,
+    change: impl FnOnce() -> Result<T, CommandError>,
+) -> Result<T, CommandError> {
+    let result = change();
+    append(&AuditRecord::new(user, action, printer, params, &result));
+    result
+}
+
+/// Criteria for selecting audit records, every unset field matches all records. Read from the
+/// query string of `GET /api/v1/audit`.
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub printer: Option<String>,
+    pub action: Option<String>,
+    pub outcome: Option<AuditOutcome>,
+    /// RFC 3339 timestamp.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub from: Option<OffsetDateTime>,
+    /// RFC 3339 timestamp, exclusive.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub to: Option<OffsetDateTime>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.actor
+            .as_ref()
+            .is_none_or(|actor| &record.actor == actor)
+            && self
+                .printer
+                .as_ref()
+                .is_none_or(|printer| record.printer.as_ref() == Some(printer))
+            && self
+                .action
+                .as_ref()
+                .is_none_or(|action| &record.action == action)
+            && self.outcome.is_none_or(|outcome| record.outcome == outcome)
+            && self.from.is_none_or(|from| record.at >= from)
+            && self.to.is_none_or(|to| record.at < to)
+    }
+}
+
+fn read_records() -> Result<Vec<AuditRecord>, io::Error> {
+    let file = match fs::File::open(&*AUDIT_FILE) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| match serde_json::from_str(&line) {
+            Ok(record) => Some(record),
+            Err(e) => {
+                tracing::warn!("Skipping unreadable audit entry: {e}");
+                None
+            }
+        })
+        .collect())
+}
+
+/// The newest `limit` records accepted by `filter`, newest first.
+///
+/// # Errors
+/// `Forbidden` unless `caller` is an admin of every printer, `Config` if the audit log can not be read.
+pub fn query(
+    caller: &User,
+    filter: &AuditFilter,
+    limit: usize,
+) -> Result<Vec<AuditRecord>, CommandError> {
+    caller.require_unscoped(Role::Admin)?;
+    let records = {
+        let _lock = AUDIT_LOCK.lock().unwrap();
+        read_records().map_err(|e| CommandError::Config(e.to_string()))?
+    };
+    Ok(records
+        .into_iter()
+        .rev()
+        .filter(|record| filter.matches(record))
+        .take(limit.min(MAX_QUERY_LIMIT))
+        .collect())
+}
+
+/// Whether `record` is still kept at `now`.
+fn retained(record: &AuditRecord, now: OffsetDateTime, retention_days: u32) -> bool {
+    now - record.at < time::Duration::days(retention_days.into())
+}
+
+/// Drops the records older than the retention. The remaining ones are written next to the log
+/// and moved into place, like the config file.
+///
+/// # Errors
+/// Returns an error if the log can not be read or written.
+fn prune(now: OffsetDateTime, retention_days: u32) -> Result<usize, io::Error> {
+    let _lock = AUDIT_LOCK.lock().unwrap();
+    let records = read_records()?;
+    let before = records.len();
+    let kept: Vec<_> = records
+        .into_iter()
+        .filter(|record| retained(record, now, retention_days))
+        .collect();
+    if kept.len() == before {
+        return Ok(0);
+    }
+    let mut data = String::new();
+    for record in &kept {
+        data += &serde_json::to_string(record)?;
+        data.push('\n');
+    }
+    let tmp = paths::data_file("audit.jsonl.tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, &*AUDIT_FILE)?;
+    Ok(before - kept.len())
+}
+
+/// Applies the retention of the config file at startup and then once a day.
+pub async fn enforce_retention() {
+    let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+    loop {
+        interval.tick().await;
+        let retention_days = config_file::read_config_file()
+            .map(|config| config.audit.retention_days)
+            .unwrap_or_else(|_| default_retention_days());
+        match prune(OffsetDateTime::now_utc(), retention_days) {
+            Ok(0) => {}
+            Ok(dropped) => tracing::info!("Dropped {dropped} audit records past the retention"),
+            Err(e) => tracing::error!("Unable to apply the audit log retention: {e}"),
+        }
+    }
+}
+
+#[test]
+fn test_audit_records() {
+    let mut user = User::new("op", Role::Operator);
+    user.address = Some(IpAddr::from([192, 168, 1, 20]));
+    let params = serde_json::json!({"file": "part.ctb"});
+    let record = AuditRecord::new(
+        &user,
+        "start",
+        Some("printer1"),
+        params.clone(),
+        &Ok::<_, CommandError>(()),
+    );
+    assert_eq!(record.outcome, AuditOutcome::Ok);
+    assert_eq!(record.error, None);
+    let line = serde_json::to_string(&record).unwrap();
+    assert!(line.contains(r#""address":"192.168.1.20""#));
+    assert_eq!(serde_json::from_str::<AuditRecord>(&line).unwrap(), record);
+    let denied = AuditRecord::new(
+        &user,
+        "add_printer",
+        Some("printer2"),
+        Value::Null,
+        &Err::<(), _>(CommandError::Forbidden("no".to_string())),
+    );
+    assert_eq!(denied.outcome, AuditOutcome::Denied);
+    assert!(!serde_json::to_string(&denied).unwrap().contains("params"));
+    let failed = AuditRecord::new(
+        &user,
+        "stop",
+        Some("printer1"),
+        Value::Null,
+        &Err::<(), _>(CommandError::UnknownPrinter("printer1".to_string())),
+    );
+    assert_eq!(failed.outcome, AuditOutcome::Failed);
+
+    assert!(AuditFilter::default().matches(&record));
+    assert!(AuditFilter {
+        actor: Some("op".to_string()),
+        printer: Some("printer1".to_string()),
+        action: Some("start".to_string()),
+        outcome: Some(AuditOutcome::Ok),
+        from: Some(record.at),
+        to: Some(record.at + time::Duration::SECOND),
+    }
+    .matches(&record));
+    assert!(!AuditFilter {
+        printer: Some("printer1".to_string()),
+        ..Default::default()
+    }
+    .matches(&denied));
+    assert!(!AuditFilter {
+        outcome: Some(AuditOutcome::Denied),
+        ..Default::default()
+    }
+    .matches(&failed));
+
+    assert!(retained(&record, record.at + time::Duration::days(29), 30));
+    assert!(!retained(&record, record.at + time::Duration::days(30), 30));
+}
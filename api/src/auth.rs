@@ -0,0 +1,563 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use salvo::http::cookie::{Cookie, SameSite};
+use salvo::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use time::{Duration, OffsetDateTime};
+
+use crate::page_interface::CommandError;
+use crate::rest_api::ApiError;
+use crate::{api_tokens, audit, config_file};
+
+/// The cookie that holds the session token of a logged in browser.
+pub const SESSION_COOKIE: &str = "session";
+
+/// How long a login lasts.
+const SESSION_LIFETIME: Duration = Duration::days(7);
+
+/// What a user may do, each role can do everything the ones before it can.
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Sees the printers but can not change anything.
+    Viewer,
+    /// Starts, pauses, resumes and stops prints and acknowledges notifications.
+    Operator,
+    /// Adds, removes and edits printers and manages users.
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// An account, stored under `"users": {"<username>": {...}}` in the config file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserConfig {
+    /// An argon2 hash in PHC format, never the password itself.
+    pub password_hash: String,
+    pub role: Role,
+}
+
+/// Who is behind a request or a websocket message.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct User {
+    /// Unique, `token:<id>` for API tokens.
+    pub username: String,
+    /// What to show instead of `username`, the name of an API token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    pub role: Role,
+    /// Limits the printers the user can change, all of them if empty. Only API tokens have it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub printers: Vec<String>,
+    /// Where the request or websocket came from, for the audit log.
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub address: Option<IpAddr>,
+}
+
+impl User {
+    pub fn new(username: impl Into<String>, role: Role) -> Self {
+        User {
+            username: username.into(),
+            display_name: None,
+            role,
+            printers: Vec::new(),
+            address: None,
+        }
+    }
+
+    /// # Errors
+    /// `Forbidden` unless the user has `role` or a higher one and may change `printer`.
+    pub fn require_printer(&self, role: Role, printer: &str) -> Result<(), CommandError> {
+        self.require(role)?;
+        if self.printers.is_empty() || self.printers.iter().any(|name| name == printer) {
+            return Ok(());
+        }
+        Err(CommandError::Forbidden(format!(
+            "{} can not change {printer}",
+            self.username
+        )))
+    }
+
+    /// Accounts, API tokens and the audit log concern every printer, so a caller limited to some
+    /// printers may not manage them even with the admin role.
+    ///
+    /// # Errors
+    /// `Forbidden` unless the user has `role` or a higher one and is not limited to some printers.
+    pub fn require_unscoped(&self, role: Role) -> Result<(), CommandError> {
+        self.require(role)?;
+        if self.printers.is_empty() {
+            return Ok(());
+        }
+        Err(CommandError::Forbidden(format!(
+            "{} is limited to some printers, this needs access to all of them",
+            self.username
+        )))
+    }
+
+    /// # Errors
+    /// `Forbidden` unless the user has `role` or a higher one.
+    pub fn require(&self, role: Role) -> Result<(), CommandError> {
+        if self.role >= role {
+            return Ok(());
+        }
+        Err(CommandError::Forbidden(format!(
+            "{} has the {} role, this needs {role}",
+            self.username, self.role
+        )))
+    }
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 with default parameters")
+        .to_string()
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// Logged in users by session token. Sessions are kept in memory, so a restart logs everyone out.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<String, (User, OffsetDateTime)>,
+}
+
+impl Sessions {
+    /// Starts a session for `user` and returns its token.
+    pub fn start(&mut self, user: User) -> String {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        let expires = OffsetDateTime::now_utc() + SESSION_LIFETIME;
+        self.sessions
+            .retain(|_, (_, expires)| *expires > OffsetDateTime::now_utc());
+        self.sessions.insert(token.clone(), (user, expires));
+        token
+    }
+
+    /// The user of a session that has not expired.
+    pub fn get(&self, token: &str) -> Option<User> {
+        self.sessions
+            .get(token)
+            .filter(|(_, expires)| *expires > OffsetDateTime::now_utc())
+            .map(|(user, _)| user.clone())
+    }
+
+    pub fn end(&mut self, token: &str) {
+        self.sessions.remove(token);
+    }
+
+    /// Gives every session of `username` a new role, or ends them if `role` is `None`.
+    pub fn change_user(&mut self, username: &str, role: Option<Role>) {
+        match role {
+            Some(role) => self
+                .sessions
+                .values_mut()
+                .filter(|(user, _)| user.username == username)
+                .for_each(|(user, _)| user.role = role),
+            None => self
+                .sessions
+                .retain(|_, (user, _)| user.username != username),
+        }
+    }
+}
+
+pub static SESSIONS: Lazy<Mutex<Sessions>> = Lazy::new(|| Mutex::new(Sessions::default()));
+
+/// How a request or connection proves who it is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credential {
+    /// The token in the session cookie of a browser.
+    Session(String),
+    /// An API token sent as `Authorization: Bearer <token>`.
+    ApiToken(String),
+}
+
+impl Credential {
+    /// The user behind the credential, `None` once the session ended or the token was revoked.
+    pub fn user(&self) -> Option<User> {
+        match self {
+            Credential::Session(token) => SESSIONS.lock().unwrap().get(token),
+            Credential::ApiToken(token) => api_tokens::authenticate(token),
+        }
+    }
+}
+
+/// The credential of a request, a bearer token taking precedence over the session cookie.
+pub fn request_credential(req: &Request) -> Option<Credential> {
+    if let Some(header) = req.header::<String>("authorization") {
+        return header
+            .strip_prefix("Bearer ")
+            .map(|token| Credential::ApiToken(token.trim().to_string()));
+    }
+    req.cookie(SESSION_COOKIE)
+        .map(|cookie| Credential::Session(cookie.value().to_string()))
+}
+
+/// The IP address a request came from.
+pub fn request_address(req: &Request) -> Option<IpAddr> {
+    req.remote_addr()
+        .clone()
+        .into_std()
+        .map(|address| address.ip())
+}
+
+/// The user behind a request, from its bearer token or session cookie.
+pub fn request_user(req: &Request) -> Option<User> {
+    let mut user = request_credential(req)?.user()?;
+    user.address = request_address(req);
+    Some(user)
+}
+
+/// Checks a username and password against the config file and starts a session.
+///
+/// # Errors
+/// `Unauthorized` if there is no such user or the password is wrong, `Config` if the config
+/// file can not be read.
+pub fn login(username: &str, password: &str) -> Result<(User, String), CommandError> {
+    let users = config_file::read_config_file()
+        .map_err(|e| CommandError::Config(e.to_string()))?
+        .users;
+    let account = users
+        .get(username)
+        .filter(|account| verify_password(password, &account.password_hash))
+        .ok_or(CommandError::Unauthorized)?;
+    let user = User::new(username, account.role);
+    let token = SESSIONS.lock().unwrap().start(user.clone());
+    tracing::info!("{username} logged in");
+    Ok((user, token))
+}
+
+/// The cookie that keeps a browser logged in with `token`, or logs it out when `token` is empty.
+/// It is `secure` when issued over HTTPS, so the browser never sends it back over plain HTTP.
+pub fn session_cookie(token: &str, secure: bool) -> Cookie<'static> {
+    let max_age = if token.is_empty() {
+        Duration::ZERO
+    } else {
+        SESSION_LIFETIME
+    };
+    Cookie::build((SESSION_COOKIE, token.to_string()))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(secure)
+        .max_age(max_age)
+        .build()
+}
+
+/// Answers `401 Unauthorized` for requests without a valid session or API token, and makes the `User` of
+/// the others available to handlers through the depot.
+#[handler]
+pub async fn require_login(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+    ctrl: &mut FlowCtrl,
+) {
+    match request_user(req) {
+        Some(user) => {
+            depot.inject(user);
+        }
+        None => {
+            ApiError::from(CommandError::Unauthorized)
+                .write(req, depot, res)
+                .await;
+            ctrl.skip_rest();
+        }
+    }
+}
+
+/// The user `require_login` found for this request.
+pub fn depot_user(depot: &Depot) -> Result<User, ApiError> {
+    depot
+        .obtain::<User>()
+        .cloned()
+        .map_err(|_| ApiError::from(CommandError::Unauthorized))
+}
+
+/// Changes to an account, every field is optional.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+pub struct UserUpdate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+/// A new account.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+pub struct NewUser {
+    pub username: String,
+    pub password: String,
+    pub role: Option<Role>,
+}
+
+/// Every account, without their password hashes.
+///
+/// # Errors
+/// `Forbidden` unless `caller` is an admin of every printer, `Config` if the config file can not be read.
+pub fn list_users(caller: &User) -> Result<Vec<User>, CommandError> {
+    caller.require_unscoped(Role::Admin)?;
+    Ok(read_users()?
+        .into_iter()
+        .map(|(username, account)| User::new(username, account.role))
+        .collect())
+}
+
+/// # Errors
+/// `MissingField` for an empty username, `InvalidValue` for one with a `:`, which would pass for
+/// an API token or the command line client.
+fn check_username(username: &str) -> Result<(), CommandError> {
+    if username.trim().is_empty() {
+        return Err(CommandError::MissingField("username"));
+    }
+    if username.contains(':') {
+        return Err(CommandError::InvalidValue(
+            "A username can not contain ':'".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Creates an account, a viewer unless `role` says otherwise.
+///
+/// # Errors
+/// `Forbidden` unless `caller` is an admin of every printer, `MissingField` for an empty username or password,
+/// `InvalidValue` for a username with a `:`, `Conflict` if the username is taken, `Config` if the
+/// config file can not be written.
+pub fn add_user(caller: &User, new_user: NewUser) -> Result<(), CommandError> {
+    let role = new_user.role.unwrap_or(Role::Viewer);
+    let params = json!({ "username": new_user.username, "role": role });
+    audit::audited_sync(caller, "add_user", None, params, || {
+        caller.require_unscoped(Role::Admin)?;
+        check_username(&new_user.username)?;
+        if new_user.password.is_empty() {
+            return Err(CommandError::MissingField("password"));
+        }
+        change_users(|users| {
+            if users.contains_key(&new_user.username) {
+                return Err(CommandError::Conflict(format!(
+                    "A user called {} already exists",
+                    new_user.username
+                )));
+            }
+            users.insert(
+                new_user.username.clone(),
+                UserConfig {
+                    password_hash: hash_password(&new_user.password),
+                    role,
+                },
+            );
+            Ok(())
+        })?;
+        tracing::info!("{} added the user {}", caller.username, new_user.username);
+        Ok(())
+    })
+}
+
+/// Changes the password or role of an account. Users can change their own password, everything
+/// else needs an admin. A new role applies to open sessions straight away.
+///
+/// # Errors
+/// `Forbidden` if `caller` may not make the change, `UnknownUser` if there is no such account,
+/// `MissingField` for an empty password, `Conflict` if it would leave no admin, `Config` if the
+/// config file can not be written.
+pub fn update_user(caller: &User, username: &str, update: UserUpdate) -> Result<(), CommandError> {
+    // never the password itself
+    let params = json!({
+        "username": username,
+        "role": update.role,
+        "password_changed": update.password.is_some(),
+    });
+    audit::audited_sync(caller, "update_user", None, params, || {
+        if caller.username != username || update.role.is_some() {
+            caller.require_unscoped(Role::Admin)?;
+        } else {
+            // a token limited to some printers does not own the account it was made by
+            caller.require_unscoped(Role::Viewer)?;
+        }
+        if update.password.as_deref() == Some("") {
+            return Err(CommandError::MissingField("password"));
+        }
+        change_users(|users| {
+            let account = users
+                .get_mut(username)
+                .ok_or_else(|| CommandError::UnknownUser(username.to_string()))?;
+            if let Some(password) = &update.password {
+                account.password_hash = hash_password(password);
+            }
+            if let Some(role) = update.role {
+                account.role = role;
+            }
+            check_admin_left(users)
+        })?;
+        let mut sessions = SESSIONS.lock().unwrap();
+        if update.role.is_some() {
+            sessions.change_user(username, update.role);
+        }
+        if update.password.is_some() && caller.username != username {
+            // someone else knew the old password, log them out
+            sessions.change_user(username, None);
+        }
+        tracing::info!("{} updated the user {username}", caller.username);
+        Ok(())
+    })
+}
+
+/// Deletes an account and ends its sessions.
+///
+/// # Errors
+/// `Forbidden` unless `caller` is an admin of every printer, `UnknownUser` if there is no such account,
+/// `Conflict` if it is the last admin, `Config` if the config file can not be written.
+pub fn remove_user(caller: &User, username: &str) -> Result<(), CommandError> {
+    let params = json!({ "username": username });
+    audit::audited_sync(caller, "remove_user", None, params, || {
+        caller.require_unscoped(Role::Admin)?;
+        change_users(|users| {
+            users
+                .remove(username)
+                .ok_or_else(|| CommandError::UnknownUser(username.to_string()))?;
+            check_admin_left(users)
+        })?;
+        SESSIONS.lock().unwrap().change_user(username, None);
+        tracing::info!("{} removed the user {username}", caller.username);
+        Ok(())
+    })
+}
+
+fn check_admin_left(users: &BTreeMap<String, UserConfig>) -> Result<(), CommandError> {
+    if users.values().any(|account| account.role == Role::Admin) {
+        return Ok(());
+    }
+    Err(CommandError::Conflict(
+        "There has to be at least one admin".to_string(),
+    ))
+}
+
+fn read_users() -> Result<BTreeMap<String, UserConfig>, CommandError> {
+    config_file::read_config_file()
+        .map(|config| config.users)
+        .map_err(|e| CommandError::Config(e.to_string()))
+}
+
+fn change_users(
+    change: impl FnOnce(&mut BTreeMap<String, UserConfig>) -> Result<(), CommandError>,
+) -> Result<(), CommandError> {
+    let mut result = Ok(());
+    config_file::update_users(|users| {
+        result = change(users);
+        result.is_ok()
+    })
+    .map_err(|e| CommandError::Config(e.to_string()))?;
+    result
+}
+
+/// Creates an `admin` account with a random password when the config file has no users, so a
+/// fresh install is never open to everyone. The password is only shown in the log.
+pub fn ensure_admin_exists() {
+    if !read_users().is_ok_and(|users| users.is_empty()) {
+        return;
+    }
+    let mut bytes = [0u8; 12];
+    OsRng.fill_bytes(&mut bytes);
+    let password = hex::encode(bytes);
+    let params = json!({ "username": "admin", "role": Role::Admin });
+    let created = audit::audited_sync(&audit::system_user(), "add_user", None, params, || {
+        config_file::update_users(|users| {
+            users.insert(
+                "admin".to_string(),
+                UserConfig {
+                    password_hash: hash_password(&password),
+                    role: Role::Admin,
+                },
+            );
+            true
+        })
+        .map_err(|e| CommandError::Config(e.to_string()))
+    });
+    match created {
+        Ok(()) => tracing::warn!(
+            "No users were configured, log in as admin with the password {password} and change it"
+        ),
+        Err(e) => tracing::error!("Unable to create the first admin: {e}"),
+    }
+}
+
+#[test]
+fn test_passwords() {
+    let hash = hash_password("hunter2");
+    assert!(hash.starts_with("$argon2id$"));
+    assert!(verify_password("hunter2", &hash));
+    assert!(!verify_password("hunter3", &hash));
+    assert!(!verify_password("hunter2", "not a hash"));
+}
+
+#[test]
+fn test_roles_and_sessions() {
+    let operator = User::new("op", Role::Operator);
+    assert_eq!(operator.require(Role::Viewer), Ok(()));
+    assert_eq!(operator.require(Role::Operator), Ok(()));
+    assert!(matches!(
+        operator.require(Role::Admin),
+        Err(CommandError::Forbidden(_))
+    ));
+    let scoped = User {
+        printers: vec!["printer1".to_string()],
+        ..User::new("token", Role::Admin)
+    };
+    assert_eq!(scoped.require_printer(Role::Admin, "printer1"), Ok(()));
+    assert!(matches!(
+        scoped.require_unscoped(Role::Admin),
+        Err(CommandError::Forbidden(_))
+    ));
+    assert_eq!(
+        User::new("admin", Role::Admin).require_unscoped(Role::Admin),
+        Ok(())
+    );
+    assert_eq!(check_username("bay1"), Ok(()));
+    assert!(check_username(" ").is_err());
+    assert!(check_username("token:ab12").is_err());
+    let mut sessions = Sessions::default();
+    let token = sessions.start(operator.clone());
+    assert_eq!(token.len(), 64);
+    assert_eq!(sessions.get(&token), Some(operator));
+    sessions.change_user("op", Some(Role::Viewer));
+    assert_eq!(sessions.get(&token).unwrap().role, Role::Viewer);
+    sessions.change_user("op", None);
+    assert_eq!(sessions.get(&token), None);
+    let token = sessions.start(User::new("admin", Role::Admin));
+    sessions.end(&token);
+    assert_eq!(sessions.get(&token), None);
+    assert_eq!(session_cookie(&token, true).secure(), Some(true));
+    assert_ne!(session_cookie(&token, false).secure(), Some(true));
+}
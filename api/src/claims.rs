@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+
+use crate::auth::{Role, User};
+use crate::page_interface::CommandError;
+
+/// How long a claim lasts unless the claimant asks for another time.
+pub const DEFAULT_CLAIM_MINUTES: u32 = 30;
+
+/// The longest a printer can be claimed for at once, a claim can be renewed before it runs out.
+pub const MAX_CLAIM_MINUTES: u32 = 12 * 60;
+
+/// An exclusive hold on a printer. Only the holder can start, pause, resume or stop prints on
+/// it until the claim expires or is released.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct Claim {
+    /// The username, or `token:<id>` for API tokens.
+    pub holder: String,
+    /// RFC 3339 timestamp.
+    #[serde(with = "time::serde::rfc3339")]
+    #[schemars(with = "String")]
+    pub expires_at: OffsetDateTime,
+}
+
+impl Claim {
+    /// When the claim runs out, as `HH:MM UTC`.
+    pub fn expires(&self) -> String {
+        let format = time::macros::format_description!("[hour]:[minute] UTC");
+        self.expires_at.format(format).unwrap_or_default()
+    }
+}
+
+/// Claims by printer name. They are kept in memory, so a restart releases every printer.
+#[derive(Debug, Default)]
+pub struct Claims {
+    claims: HashMap<String, Claim>,
+}
+
+pub static CLAIMS: Lazy<Mutex<Claims>> = Lazy::new(|| Mutex::new(Claims::default()));
+
+impl Claims {
+    /// The claim on `printer`, if it has not expired at `now`.
+    pub fn get(&self, printer: &str, now: OffsetDateTime) -> Option<&Claim> {
+        self.claims
+            .get(printer)
+            .filter(|claim| claim.expires_at > now)
+    }
+
+    /// Claims `printer` for `user` until `expires_at`, or renews their claim.
+    ///
+    /// # Errors
+    /// `Claimed` if someone else holds the printer.
+    pub fn claim(
+        &mut self,
+        printer: &str,
+        user: &User,
+        expires_at: OffsetDateTime,
+        now: OffsetDateTime,
+    ) -> Result<(), CommandError> {
+        self.check(printer, user, now)?;
+        self.claims.insert(
+            printer.to_string(),
+            Claim {
+                holder: user.username.clone(),
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    /// Releases the claim on `printer`, nothing happens if there is none.
+    ///
+    /// # Errors
+    /// `Forbidden` if someone else holds the printer and `user` is not an admin.
+    pub fn release(
+        &mut self,
+        printer: &str,
+        user: &User,
+        now: OffsetDateTime,
+    ) -> Result<(), CommandError> {
+        if let Some(claim) = self.get(printer, now) {
+            if claim.holder != user.username && user.role < Role::Admin {
+                return Err(CommandError::Forbidden(format!(
+                    "{printer} is claimed by {}, only they or an admin can release it",
+                    claim.holder
+                )));
+            }
+        }
+        self.claims.remove(printer);
+        Ok(())
+    }
+
+    /// # Errors
+    /// `Claimed` if someone other than `user` holds `printer`.
+    pub fn check(
+        &self,
+        printer: &str,
+        user: &User,
+        now: OffsetDateTime,
+    ) -> Result<(), CommandError> {
+        match self.get(printer, now) {
+            Some(claim) if claim.holder != user.username => {
+                Err(CommandError::Claimed(printer.to_string(), claim.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn forget(&mut self, printer: &str) {
+        self.claims.remove(printer);
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(claim) = self.claims.remove(old) {
+            self.claims.insert(new.to_string(), claim);
+        }
+    }
+}
+
+/// When a claim of `minutes`, or the default length, taken at `now` runs out.
+///
+/// # Errors
+/// `InvalidValue` for a length of zero or more than `MAX_CLAIM_MINUTES`.
+pub fn claim_expiry(
+    minutes: Option<u32>,
+    now: OffsetDateTime,
+) -> Result<OffsetDateTime, CommandError> {
+    match minutes.unwrap_or(DEFAULT_CLAIM_MINUTES) {
+        minutes @ 1..=MAX_CLAIM_MINUTES => Ok(now + Duration::minutes(minutes.into())),
+        _ => Err(CommandError::InvalidValue(format!(
+            "A claim lasts between 1 and {MAX_CLAIM_MINUTES} minutes"
+        ))),
+    }
+}
+
+#[test]
+fn test_claims() {
+    let now = OffsetDateTime::UNIX_EPOCH;
+    let alice = User::new("alice", Role::Operator);
+    let bob = User::new("bob", Role::Operator);
+    let admin = User::new("admin", Role::Admin);
+    let mut claims = Claims::default();
+    let until = claim_expiry(None, now).unwrap();
+    assert_eq!(until, now + Duration::minutes(30));
+    claims.claim("printer1", &alice, until, now).unwrap();
+    assert_eq!(claims.check("printer1", &alice, now), Ok(()));
+    assert_eq!(claims.check("printer2", &bob, now), Ok(()));
+    let Err(e) = claims.check("printer1", &bob, now) else {
+        panic!("bob should not get past the claim of alice");
+    };
+    assert_eq!(
+        e.to_string(),
+        "printer1 is claimed by alice until 00:30 UTC"
+    );
+    assert!(claims.claim("printer1", &bob, until, now).is_err());
+    assert!(matches!(
+        claims.release("printer1", &bob, now),
+        Err(CommandError::Forbidden(_))
+    ));
+    // renewing
+    let later = claim_expiry(Some(60), now).unwrap();
+    claims.claim("printer1", &alice, later, now).unwrap();
+    assert_eq!(claims.get("printer1", now).unwrap().expires_at, later);
+    claims.rename("printer1", "printer3");
+    assert!(claims.check("printer3", &bob, now).is_err());
+    claims.release("printer3", &admin, now).unwrap();
+    assert_eq!(claims.check("printer3", &bob, now), Ok(()));
+    // expired claims do not count
+    claims.claim("printer1", &alice, until, now).unwrap();
+    assert_eq!(claims.check("printer1", &bob, until), Ok(()));
+    claims.claim("printer1", &bob, later, until).unwrap();
+    assert!(claim_expiry(Some(0), now).is_err());
+    assert!(claim_expiry(Some(MAX_CLAIM_MINUTES + 1), now).is_err());
+}
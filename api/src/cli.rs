@@ -0,0 +1,401 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use once_cell::sync::OnceCell;
+use tracing::Level;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Pretty,
+}
+
+/// How the server runs, from the command line, the environment or the defaults, in that order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub address: IpAddr,
+    /// The port of plain HTTP, HTTPS has its own in the config file.
+    pub port: u16,
+    pub data_dir: PathBuf,
+    /// Serves the frontend from disk instead of the copy built into the binary.
+    pub asset_dir: Option<PathBuf>,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    pub poll_interval: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8000,
+            data_dir: PathBuf::from("./data"),
+            asset_dir: None,
+            log_level: Level::INFO,
+            log_format: LogFormat::Full,
+            poll_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A command of the headless client, run instead of the server.
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum ClientCommand {
+    /// Progress of every printer, or of one
+    Status { printer: Option<String> },
+    /// Files stored on a printer
+    Files { printer: String },
+    /// Start printing a file
+    Start { printer: String, file: String },
+    /// Pause the print
+    Pause { printer: String },
+    /// Resume the print
+    Resume { printer: String },
+    /// Stop the print, --yes confirms stopping a print in progress
+    Stop { printer: String },
+    /// Send gcode and print what the printer answers, only directly
+    SendGcode { printer: String, gcode: String },
+    /// Print every change of progress until interrupted
+    Watch { printer: Option<String> },
+}
+
+impl ClientCommand {
+    /// Whether the command changes what a printer does, rather than only looking at it.
+    pub fn changes_printer(&self) -> bool {
+        matches!(
+            self,
+            ClientCommand::Start { .. }
+                | ClientCommand::Pause { .. }
+                | ClientCommand::Resume { .. }
+                | ClientCommand::Stop { .. }
+                | ClientCommand::SendGcode { .. }
+        )
+    }
+}
+
+/// A running server the client goes through instead of talking to the printers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Server {
+    /// Where the server is, without `/api/v1`.
+    pub url: String,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub command: ClientCommand,
+    /// Print JSON instead of tables.
+    pub json: bool,
+    /// Go ahead with changing a printer directly, and confirm stopping a print.
+    pub yes: bool,
+    pub server: Option<Server>,
+}
+
+const COMMANDS: &str = "\
+Without a command the server is started. With --server the commands go through the API of a
+running server, so its claims and confirmations apply. Without it they talk to the printers in
+config.txt directly, which ignores claims, so commands that change a printer need --yes.";
+
+/// Monitors CHITU resin printers and serves a web page and API to control them.
+#[derive(Parser, Debug)]
+#[command(version, after_help = COMMANDS)]
+struct Args {
+    /// Address to listen on
+    #[arg(
+        long,
+        global = true,
+        env = "RUSTY_PRINTER_ADDRESS",
+        default_value = "0.0.0.0"
+    )]
+    address: IpAddr,
+    /// Port for plain HTTP
+    #[arg(
+        long,
+        global = true,
+        env = "RUSTY_PRINTER_PORT",
+        default_value_t = 8000
+    )]
+    port: u16,
+    /// Where config.txt, the history, the audit log and certificates are kept
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        env = "RUSTY_PRINTER_DATA_DIR",
+        default_value = "./data"
+    )]
+    data_dir: PathBuf,
+    /// Serve the frontend from this directory instead of the built in copy
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        env = "RUSTY_PRINTER_ASSET_DIR"
+    )]
+    asset_dir: Option<PathBuf>,
+    /// error, warn, info, debug or trace [default: info, warn for commands]
+    #[arg(
+        long,
+        global = true,
+        value_name = "LEVEL",
+        env = "RUSTY_PRINTER_LOG_LEVEL"
+    )]
+    log_level: Option<Level>,
+    /// How log lines are laid out
+    #[arg(
+        long,
+        global = true,
+        value_name = "FORMAT",
+        env = "RUSTY_PRINTER_LOG_FORMAT",
+        value_enum,
+        default_value = "full"
+    )]
+    log_format: LogFormat,
+    /// Seconds between polls of the printers
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        env = "RUSTY_PRINTER_POLL_INTERVAL",
+        default_value = "10",
+        value_parser = poll_interval
+    )]
+    poll_interval: Duration,
+    /// Go through the server at this URL, like https://printers:8443
+    #[arg(long, global = true, value_name = "URL", env = "RUSTY_PRINTER_SERVER")]
+    server: Option<String>,
+    /// API token for --server
+    #[arg(
+        long,
+        global = true,
+        env = "RUSTY_PRINTER_TOKEN",
+        hide_env_values = true
+    )]
+    token: Option<String>,
+    /// Print JSON instead of tables, a line per change for watch
+    #[arg(long, global = true)]
+    json: bool,
+    /// Change a printer directly, or confirm stopping a print
+    #[arg(long, global = true)]
+    yes: bool,
+    #[command(subcommand)]
+    command: Option<ClientCommand>,
+}
+
+fn poll_interval(value: &str) -> Result<Duration, String> {
+    match value
+        .parse()
+        .map_err(|e: std::num::ParseIntError| e.to_string())?
+    {
+        0 => Err("must be at least 1 second".to_string()),
+        seconds => Ok(Duration::from_secs(seconds)),
+    }
+}
+
+/// Reads the command line, starting with the program name, falling back to the environment for
+/// every flag that is not given. Arguments after `--` are never flags, for gcode or files
+/// starting with `-`.
+///
+/// # Errors
+/// Returns the error clap reports, which also stands for `--help` and `--version`, or one for
+/// client flags without a command and a server without a token.
+pub fn parse<T: Into<std::ffi::OsString> + Clone>(
+    args: impl IntoIterator<Item = T>,
+) -> Result<(Settings, Option<Client>), clap::Error> {
+    let matches = Args::command().try_get_matches_from(args)?;
+    let args = Args::from_arg_matches(&matches)?;
+    let given = |id| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let client = match args.command {
+        None if args.json || args.yes || given("server") || given("token") => {
+            return Err(Args::command().error(
+                ErrorKind::MissingSubcommand,
+                "--json, --yes, --server and --token need a command",
+            ))
+        }
+        None => None,
+        Some(command) => Some(Client {
+            command,
+            json: args.json,
+            yes: args.yes,
+            server: match (args.server, args.token) {
+                (None, None) => None,
+                (Some(url), Some(token)) => Some(Server {
+                    url: url.trim_end_matches('/').to_string(),
+                    token,
+                }),
+                (Some(_), None) => {
+                    return Err(Args::command().error(
+                        ErrorKind::MissingRequiredArgument,
+                        "--server needs an API token from --token",
+                    ))
+                }
+                (None, Some(_)) => {
+                    return Err(Args::command().error(
+                        ErrorKind::MissingRequiredArgument,
+                        "--token needs the --server to send it to",
+                    ))
+                }
+            },
+        }),
+    };
+    let settings = Settings {
+        address: args.address,
+        port: args.port,
+        data_dir: args.data_dir,
+        asset_dir: args.asset_dir,
+        // the client only reports what goes wrong, unless asked for more
+        log_level: args.log_level.unwrap_or(match client {
+            Some(_) => Level::WARN,
+            None => Level::INFO,
+        }),
+        log_format: args.log_format,
+        poll_interval: args.poll_interval,
+    };
+    Ok((settings, client))
+}
+
+static SETTINGS: OnceCell<Settings> = OnceCell::new();
+
+/// Reads the command line and environment of the process and returns the client command to
+/// run, if any. Prints help or the version, or the error and usage, and exits when there is
+/// nothing to run.
+pub fn init() -> Option<Client> {
+    let (settings, client) = parse(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    let _ = SETTINGS.set(settings);
+    client
+}
+
+/// The settings `init` read, the defaults before that and in tests.
+pub fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+/// Starts logging at the level and in the format of the settings. The client logs to stderr to
+/// keep its output clean.
+pub fn init_logging(settings: &Settings, client: bool) {
+    let writer = if client {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(settings.log_level)
+        .with_writer(writer);
+    match settings.log_format {
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Pretty => subscriber.pretty().init(),
+    }
+}
+
+#[test]
+fn test_parse() {
+    let run = |args: &[&str]| parse(["rusty_printer_monitor"].iter().chain(args));
+    assert_eq!(run(&[]).unwrap(), (Settings::default(), None));
+    let kind = |args: &[&str]| run(args).unwrap_err().kind();
+    assert_eq!(kind(&["--port", "80", "--help"]), ErrorKind::DisplayHelp);
+    assert_eq!(kind(&["-V"]), ErrorKind::DisplayVersion);
+
+    std::env::set_var("RUSTY_PRINTER_PORT", "9000");
+    std::env::set_var("RUSTY_PRINTER_LOG_LEVEL", "debug");
+    let parsed = run(&[
+        "--address",
+        "127.0.0.1",
+        "--port=8080",
+        "--data-dir",
+        "/var/lib/printers",
+        "--log-format",
+        "compact",
+        "--poll-interval",
+        "30",
+    ]);
+    std::env::remove_var("RUSTY_PRINTER_PORT");
+    std::env::remove_var("RUSTY_PRINTER_LOG_LEVEL");
+    let (settings, None) = parsed.unwrap() else {
+        panic!("the flags should not be a client command");
+    };
+    assert_eq!(settings.address, IpAddr::from([127, 0, 0, 1]));
+    // flags win over the environment
+    assert_eq!(settings.port, 8080);
+    assert_eq!(settings.log_level, Level::DEBUG);
+    assert_eq!(settings.data_dir, PathBuf::from("/var/lib/printers"));
+    assert_eq!(settings.asset_dir, None);
+    assert_eq!(settings.log_format, LogFormat::Compact);
+    assert_eq!(settings.poll_interval, Duration::from_secs(30));
+
+    assert_eq!(kind(&["--verbose"]), ErrorKind::UnknownArgument);
+    assert_eq!(kind(&["--port"]), ErrorKind::InvalidValue);
+    assert_eq!(kind(&["--poll-interval", "0"]), ErrorKind::ValueValidation);
+    assert_eq!(kind(&["--log-format", "json"]), ErrorKind::InvalidValue);
+
+    let client = |args: &[&str]| {
+        run(args).map(|(settings, client)| {
+            assert_eq!(settings.log_level, Level::WARN);
+            client.expect("should be a client command")
+        })
+    };
+    assert_eq!(
+        client(&["status"]).unwrap(),
+        Client {
+            command: ClientCommand::Status { printer: None },
+            json: false,
+            yes: false,
+            server: None,
+        }
+    );
+    assert_eq!(
+        client(&["--json", "watch", "printer1"]).unwrap(),
+        Client {
+            command: ClientCommand::Watch {
+                printer: Some("printer1".to_string())
+            },
+            json: true,
+            yes: false,
+            server: None,
+        }
+    );
+    let stop = client(&["stop", "printer1", "--yes"]).unwrap();
+    assert_eq!(
+        stop.command,
+        ClientCommand::Stop {
+            printer: "printer1".to_string()
+        }
+    );
+    assert!(stop.yes && stop.command.changes_printer());
+    let remote = client(&[
+        "status",
+        "--server",
+        "https://printers:8443/",
+        "--token",
+        "rpm_abc",
+    ])
+    .unwrap();
+    assert_eq!(
+        remote.server,
+        Some(Server {
+            url: "https://printers:8443".to_string(),
+            token: "rpm_abc".to_string()
+        })
+    );
+    assert!(client(&["status", "--server", "https://printers:8443"]).is_err());
+    assert!(run(&["--token", "rpm_abc"]).is_err());
+    assert_eq!(
+        client(&["send-gcode", "printer1", "--", "-M4000"])
+            .unwrap()
+            .command,
+        ClientCommand::SendGcode {
+            printer: "printer1".to_string(),
+            gcode: "-M4000".to_string()
+        }
+    );
+    assert!(client(&["start", "printer1"]).is_err());
+    assert!(client(&["pause", "printer1", "printer2"]).is_err());
+    assert_eq!(kind(&["print"]), ErrorKind::InvalidSubcommand);
+    assert!(run(&["--json"]).is_err());
+}
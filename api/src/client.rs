@@ -0,0 +1,423 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Method, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+
+use crate::auth::{Role, User};
+use crate::cli::{self, Client, ClientCommand, Server};
+use crate::config_file::{self, PrinterConfig};
+use crate::confirmations::ConfirmationRequest;
+use crate::page_interface::{self, CommandError, StatusJson};
+use crate::{audit, paths, printer_interface};
+
+/// Why a command that changes a printer is refused without `--server` or `--yes`.
+const DIRECT_CHANGE: &str = "without --server the printer is changed directly, which ignores the \
+claims and confirmations of a running server. Pass --server and --token to go through it, or --yes \
+to go ahead";
+
+static HTTP: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .expect("Unable to build the API client")
+});
+
+/// Why the server did not carry out a request.
+enum Refusal {
+    /// `428 Precondition Required`, the request has to be sent again with the confirmation.
+    Confirm(ConfirmationRequest),
+    Failed(String),
+}
+
+impl Refusal {
+    fn into_message(self) -> String {
+        match self {
+            Refusal::Confirm(request) => request.description,
+            Refusal::Failed(message) => message,
+        }
+    }
+}
+
+/// The body of an error answer of the server.
+#[derive(Deserialize)]
+struct ErrorAnswer {
+    error: String,
+}
+
+/// A status printed by `watch --json`.
+#[derive(Serialize)]
+struct WatchLine<'a> {
+    #[serde(with = "time::serde::rfc3339")]
+    at: OffsetDateTime,
+    #[serde(flatten)]
+    status: &'a StatusJson,
+}
+
+/// Who the commands of the client are recorded as in the audit log, `cli:<login>`.
+fn cli_user() -> User {
+    let login = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    User::new(format!("cli:{login}"), Role::Admin)
+}
+
+/// The configured printers, or only `name`.
+///
+/// # Errors
+/// Returns an error if there is no config file in the data directory or no printer `name`.
+fn printers(name: Option<&str>) -> Result<BTreeMap<String, PrinterConfig>, String> {
+    let path = paths::data_file("config.txt");
+    if !path.exists() {
+        return Err(format!(
+            "There is no config file at {}, pass the --data-dir of the server",
+            path.display()
+        ));
+    }
+    let mut printers = config_file::read_config_file()
+        .map_err(|e| e.to_string())?
+        .printers;
+    match name {
+        None => Ok(printers),
+        Some(name) => match printers.remove(name) {
+            Some(config) => Ok(BTreeMap::from([(name.to_string(), config)])),
+            None => Err(CommandError::UnknownPrinter(name.to_string()).to_string()),
+        },
+    }
+}
+
+fn printer(name: &str) -> Result<PrinterConfig, String> {
+    printers(Some(name))?
+        .remove(name)
+        .ok_or_else(|| CommandError::UnknownPrinter(name.to_string()).to_string())
+}
+
+/// Sends a request to the API of `server` at the path made of `segments`, answering with the body
+/// of a success. Runs on the blocking thread of the client, so it waits for the answer.
+fn call(
+    server: &Server,
+    method: Method,
+    segments: &[&str],
+    body: Option<Value>,
+) -> Result<String, Refusal> {
+    let mut url = Url::parse(&server.url)
+        .ok()
+        .filter(|url| !url.cannot_be_a_base())
+        .ok_or_else(|| Refusal::Failed(format!("{} is not a URL for --server", server.url)))?;
+    url.path_segments_mut()
+        .expect("checked above")
+        .pop_if_empty()
+        .extend(["api", "v1"])
+        .extend(segments);
+    tokio::runtime::Handle::current().block_on(async {
+        let mut request = HTTP.request(method, url).bearer_auth(&server.token);
+        if let Some(body) = body {
+            request = request
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.to_string());
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Refusal::Failed(format!("{} can not be reached: {e}", server.url)))?;
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .map_err(|e| Refusal::Failed(e.to_string()))?;
+        if status.is_success() {
+            return Ok(text);
+        }
+        if status == StatusCode::PRECONDITION_REQUIRED {
+            if let Ok(request) = serde_json::from_str(&text) {
+                return Err(Refusal::Confirm(request));
+            }
+        }
+        let error = serde_json::from_str::<ErrorAnswer>(&text).map_or(text, |answer| answer.error);
+        Err(Refusal::Failed(format!(
+            "the server answered {status}: {error}"
+        )))
+    })
+}
+
+fn get<T: DeserializeOwned>(server: &Server, segments: &[&str]) -> Result<T, String> {
+    let body = call(server, Method::GET, segments, None).map_err(Refusal::into_message)?;
+    serde_json::from_str(&body).map_err(|e| format!("unexpected answer from {}: {e}", server.url))
+}
+
+/// The statuses of every printer, or only `name`. Asked directly, the files on the printers are
+/// left out, as listing them takes another request to every printer.
+fn statuses(client: &Client, name: Option<&str>) -> Result<Vec<StatusJson>, String> {
+    if let Some(server) = &client.server {
+        return match name {
+            Some(name) => Ok(vec![get(server, &["printers", name])?]),
+            None => get(server, &["printers"]),
+        };
+    }
+    Ok(printers(name)?
+        .iter()
+        .map(|(name, config)| {
+            let status = printer_interface::get_print_status(config.address());
+            page_interface::status_json(name.clone(), config, status, None, false)
+        })
+        .collect())
+}
+
+/// Lines up `rows` under `header`, columns are as wide as their widest cell.
+fn table(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|cell| cell.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let mut line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        line.truncate(line.trim_end().len());
+        line + "\n"
+    };
+    let mut output = line(header.to_vec());
+    for row in rows {
+        output += &line(row.iter().map(String::as_str).collect());
+    }
+    output
+}
+
+/// The progress with a `%` when the printer is printing.
+fn progress(status: &StatusJson) -> String {
+    match status.progress.parse::<f64>() {
+        Ok(_) => format!("{}%", status.progress),
+        Err(_) => status.progress.clone(),
+    }
+}
+
+fn status_row(status: &StatusJson) -> Vec<String> {
+    vec![
+        status.printer_name.clone(),
+        status.ip_address.clone(),
+        progress(status),
+        if status.paused { "yes" } else { "" }.to_string(),
+    ]
+}
+
+fn print_json(value: &impl Serialize) {
+    println!("{}", serde_json::to_string(value).unwrap_or_default());
+}
+
+/// Sends `gcode` and records it in the audit log.
+fn send(
+    user: &User,
+    name: &str,
+    config: &PrinterConfig,
+    action: &str,
+    params: Value,
+    gcode: impl FnOnce() -> Result<Vec<String>, String>,
+) -> Result<Vec<String>, String> {
+    audit::audited_sync(user, action, Some(name), params, || {
+        gcode().map_err(CommandError::Printer)
+    })
+    .map_err(|e| format!("{e} ({})", config.address()))
+}
+
+/// Runs a print action through the server, which checks claims and asks to confirm a `stop`.
+/// The confirmation is only given with `yes`.
+fn server_action(
+    server: &Server,
+    name: &str,
+    action: &str,
+    file: Option<String>,
+    yes: bool,
+) -> Result<(), String> {
+    let path = ["printers", name, "actions", action];
+    match call(server, Method::POST, &path, Some(json!({ "file": file }))) {
+        Ok(_) => Ok(()),
+        Err(Refusal::Confirm(request)) if yes => {
+            let body = json!({ "confirmation": request.confirmation });
+            call(server, Method::POST, &path, Some(body))
+                .map(drop)
+                .map_err(Refusal::into_message)
+        }
+        Err(Refusal::Confirm(request)) => {
+            Err(format!("{}, pass --yes to go ahead", request.description))
+        }
+        Err(Refusal::Failed(message)) => Err(message),
+    }
+}
+
+/// Runs a print action and prints what the printer answered, when it was sent directly.
+fn print_action(
+    client: &Client,
+    name: &str,
+    action: &str,
+    file: Option<String>,
+) -> Result<(), String> {
+    let reply = match &client.server {
+        Some(server) => {
+            server_action(server, name, action, file, client.yes)?;
+            None
+        }
+        None => {
+            let config = printer(name)?;
+            let params = file
+                .as_ref()
+                .map_or(Value::Null, |file| json!({ "file": file }));
+            let reply = send(&cli_user(), name, &config, action, params, || {
+                printer_interface::print_action(config.address(), action.to_string(), file)
+                    .map(|reply| vec![reply])
+            })?;
+            Some(reply.join("\n"))
+        }
+    };
+    if client.json {
+        print_json(&json!({ "printer": name, "action": action, "reply": reply }));
+    } else if let Some(reply) = reply {
+        println!("{name}: {action} sent, the printer answered {reply}");
+    } else {
+        println!("{name}: {action} sent");
+    }
+    Ok(())
+}
+
+/// Prints every change of the statuses until the process is stopped.
+fn watch(client: &Client, name: Option<&str>) -> Result<(), String> {
+    let mut last: BTreeMap<String, StatusJson> = BTreeMap::new();
+    let format = time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+    loop {
+        let at = OffsetDateTime::now_utc();
+        for status in statuses(client, name)? {
+            if last.get(&status.printer_name) == Some(&status) {
+                continue;
+            }
+            if client.json {
+                print_json(&WatchLine {
+                    at,
+                    status: &status,
+                });
+            } else {
+                let row = status_row(&status);
+                let paused = if status.paused { " paused" } else { "" };
+                println!(
+                    "{} {} {}{paused}",
+                    at.format(format).unwrap_or_default(),
+                    row[0],
+                    row[2]
+                );
+            }
+            last.insert(status.printer_name.clone(), status);
+        }
+        std::thread::sleep(cli::settings().poll_interval);
+    }
+}
+
+/// Runs a command of the headless client through the server of `--server`, or against the
+/// printers of the config file.
+///
+/// # Errors
+/// Returns what to report if the server refuses the command, or the config file or a printer can
+/// not be used.
+pub fn run(client: Client) -> Result<(), String> {
+    if client.server.is_none() && client.command.changes_printer() && !client.yes {
+        return Err(DIRECT_CHANGE.to_string());
+    }
+    let json = client.json;
+    match &client.command {
+        ClientCommand::Status { printer: name } => {
+            let statuses = statuses(&client, name.as_deref())?;
+            if json {
+                print_json(&statuses);
+            } else {
+                let rows: Vec<_> = statuses.iter().map(status_row).collect();
+                print!(
+                    "{}",
+                    table(&["PRINTER", "ADDRESS", "PROGRESS", "PAUSED"], &rows)
+                );
+            }
+        }
+        ClientCommand::Files { printer: name } => {
+            let files: Vec<String> = match &client.server {
+                Some(server) => get(server, &["printers", name, "files"])?,
+                None => {
+                    printer(name)?;
+                    page_interface::printer_files(name).map_err(|e| e.to_string())?
+                }
+            };
+            if json {
+                print_json(&files);
+            } else {
+                files.iter().for_each(|file| println!("{file}"));
+            }
+        }
+        ClientCommand::Start {
+            printer: name,
+            file,
+        } => print_action(&client, name, "start", Some(file.clone()))?,
+        ClientCommand::Pause { printer: name } => print_action(&client, name, "pause", None)?,
+        ClientCommand::Resume { printer: name } => print_action(&client, name, "resume", None)?,
+        ClientCommand::Stop { printer: name } => print_action(&client, name, "stop", None)?,
+        ClientCommand::SendGcode { .. } if client.server.is_some() => {
+            return Err(
+                "the server does not pass on gcode, send-gcode only works without --server"
+                    .to_string(),
+            );
+        }
+        ClientCommand::SendGcode {
+            printer: name,
+            gcode,
+        } => {
+            let config = printer(name)?;
+            let params = json!({ "gcode": gcode });
+            let lines = send(&cli_user(), name, &config, "send_gcode", params, || {
+                match printer_interface::send_gcode(gcode.clone(), config.address()) {
+                    lines if lines.is_empty() => Err(format!("{name} did not answer {gcode}")),
+                    lines => Ok(lines),
+                }
+            })?;
+            if json {
+                print_json(&lines);
+            } else {
+                lines.iter().for_each(|line| println!("{line}"));
+            }
+        }
+        ClientCommand::Watch { printer: name } => watch(&client, name.as_deref())?,
+    }
+    Ok(())
+}
+
+#[test]
+fn test_status_table() {
+    let status = |name: &str, progress: &str, paused| StatusJson {
+        printer_name: name.to_string(),
+        ip_address: "192.168.1.50".to_string(),
+        files_available: Vec::new(),
+        progress: progress.to_string(),
+        paused,
+        claim: None,
+    };
+    let rows = vec![
+        status_row(&status("printer1", "57.30", true)),
+        status_row(&status("bay-2", "Not Printing", false)),
+    ];
+    assert_eq!(
+        table(&["PRINTER", "ADDRESS", "PROGRESS", "PAUSED"], &rows),
+        "PRINTER   ADDRESS       PROGRESS      PAUSED\n\
+         printer1  192.168.1.50  57.30%        yes\n\
+         bay-2     192.168.1.50  Not Printing\n"
+    );
+    let line = serde_json::to_value(WatchLine {
+        at: OffsetDateTime::UNIX_EPOCH,
+        status: &status("printer1", "57.30", false),
+    })
+    .unwrap();
+    assert_eq!(line["at"], "1970-01-01T00:00:00Z");
+    assert_eq!(line["printer_name"], "printer1");
+}
@@ -4,17 +4,141 @@ use std::fs;
 use std::io;
 use std::io::Read;
 use std::io::Write;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use once_cell::sync::Lazy;
+
+use crate::api_tokens::ApiTokenConfig;
+use crate::audit::AuditConfig;
+use crate::auth::UserConfig;
+use crate::influxdb::InfluxConfig;
+use crate::mqtt::MqttConfig;
+use crate::notifications::NotificationConfig;
+use crate::paths;
+use crate::subscriptions::Groups;
+use crate::tls::TlsConfig;
+
+static CONFIG_FILE: Lazy<PathBuf> = Lazy::new(|| paths::data_file("config.txt"));
+
+/// Held from reading the config file to writing it back, so two changes never overwrite each
+/// other.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// How often the monitor has written the config file.
+static WRITES: AtomicU64 = AtomicU64::new(0);
+
+/// Tells whether the config file changed since it was last read, by the monitor or by hand,
+/// without reading it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfigVersion {
+    writes: u64,
+    modified: Option<SystemTime>,
+}
+
+/// The current version of the config file, for caches of what it contains.
+pub fn config_version() -> ConfigVersion {
+    ConfigVersion {
+        writes: WRITES.load(Ordering::Acquire),
+        modified: fs::metadata(&*CONFIG_FILE)
+            .and_then(|metadata| metadata.modified())
+            .ok(),
+    }
+}
+
+/// The settings next to the printers in the config file, no printer may be called like them.
+pub const RESERVED_NAMES: [&str; 8] = [
+    "groups",
+    "influxdb",
+    "mqtt",
+    "notifications",
+    "users",
+    "api_tokens",
+    "audit",
+    "tls",
+];
+
+/// Printers share the top level of the config file with the settings, so a printer named after
+/// one of them could not be read back.
+///
+/// # Errors
+/// Returns an error of kind `InvalidInput` if `name` is one of `RESERVED_NAMES`.
+pub fn check_printer_name(name: &str) -> Result<(), io::Error> {
+    if RESERVED_NAMES.contains(&name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{name} is reserved for settings and can not name a printer"),
+        ));
+    }
+    Ok(())
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Printers {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: Groups,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub influxdb: Option<InfluxConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default, skip_serializing_if = "NotificationConfig::is_empty")]
+    pub notifications: NotificationConfig,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub users: BTreeMap<String, UserConfig>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub api_tokens: BTreeMap<String, ApiTokenConfig>,
+    #[serde(default, skip_serializing_if = "AuditConfig::is_default")]
+    pub audit: AuditConfig,
+    #[serde(default, skip_serializing_if = "TlsConfig::is_default")]
+    pub tls: TlsConfig,
     #[serde(flatten)]
     pub printers: BTreeMap<String, PrinterConfig>,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// The UDP port CHITU printers listen on.
+pub const DEFAULT_PRINTER_PORT: u16 = 3000;
+
+fn default_printer_port() -> u16 {
+    DEFAULT_PRINTER_PORT
+}
+
+fn is_default_printer_port(port: &u16) -> bool {
+    *port == DEFAULT_PRINTER_PORT
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct PrinterConfig {
     pub ip: IpAddr,
+    #[serde(
+        default = "default_printer_port",
+        skip_serializing_if = "is_default_printer_port"
+    )]
+    pub port: u16,
+    /// The make and model of the printer, shown to Home Assistant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Addresses that get emails about this printer, on top of the global recipients.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub email_recipients: Vec<String>,
+}
+
+impl PrinterConfig {
+    pub fn new(ip: IpAddr) -> Self {
+        PrinterConfig {
+            ip,
+            port: DEFAULT_PRINTER_PORT,
+            model: None,
+            email_recipients: Vec::new(),
+        }
+    }
+
+    /// Where gcode for this printer is sent.
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
 }
 
 /// Reads the configuration file and returns a `Printers` struct.
@@ -23,11 +147,13 @@ pub struct PrinterConfig {
 /// # Errors
 /// Returns an error if there is a problem reading or writing to the file.
 pub fn read_config_file() -> Result<Printers, io::Error> {
+    fs::create_dir_all(paths::data_dir())?;
     let mut file = fs::OpenOptions::new()
         .create(true)
+        .truncate(false)
         .write(true)
         .read(true)
-        .open("./config.txt")
+        .open(&*CONFIG_FILE)
         .unwrap();
     let printers: Printers = {
         let mut data = String::new();
@@ -36,6 +162,14 @@ pub fn read_config_file() -> Result<Printers, io::Error> {
             .expect("Config: error reading file");
         if data.is_empty() {
             let printers = Printers {
+                groups: Groups::new(),
+                influxdb: None,
+                mqtt: None,
+                notifications: NotificationConfig::default(),
+                users: BTreeMap::new(),
+                api_tokens: BTreeMap::new(),
+                audit: AuditConfig::default(),
+                tls: TlsConfig::default(),
                 printers: BTreeMap::new(),
             };
             let data = serde_json::to_string(&printers).unwrap();
@@ -43,11 +177,30 @@ pub fn read_config_file() -> Result<Printers, io::Error> {
             let _ = file.flush();
             return Ok(printers);
         }
-        serde_json::from_str(&data).unwrap()
+        serde_json::from_str(&data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not valid: {e}", CONFIG_FILE.display()),
+            )
+        })?
     };
     Ok(printers)
 }
 
+/// Replaces the config file with `printers`. The new contents are written next to it and moved
+/// into place, so a reader never sees a half written file.
+///
+/// # Errors
+/// Returns an error if the file can not be written.
+fn write_config_file(printers: &Printers) -> Result<(), io::Error> {
+    let data = serde_json::to_string(printers)?;
+    let tmp = paths::data_file("config.txt.tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, &*CONFIG_FILE)?;
+    WRITES.fetch_add(1, Ordering::Release);
+    Ok(())
+}
+
 /// Appends a new printer configuration to the config file.
 ///
 /// # Arguments
@@ -58,17 +211,11 @@ pub fn read_config_file() -> Result<Printers, io::Error> {
 /// A result containing an optional value, representing whether the operation was successful or not.
 /// If the operation was successful, the optional value is None. Otherwise, it contains a string describing the error that occurred.
 pub fn append_config_file(name: String, printer: PrinterConfig) -> Result<(), io::Error> {
-    let mut printers: Printers = read_config_file().unwrap();
-    let mut file = fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open("./config.txt")
-        .unwrap();
-    printers.printers.insert(name.parse().unwrap(), printer);
-    let data = serde_json::to_string(&printers).unwrap();
-    let _ = file.write(data.as_bytes());
-    let _ = file.flush();
-    Ok(())
+    check_printer_name(&name)?;
+    let _lock = CONFIG_LOCK.lock().unwrap();
+    let mut printers: Printers = read_config_file()?;
+    printers.printers.insert(name, printer);
+    write_config_file(&printers)
 }
 
 /// Removes a printer configuration from the config file.
@@ -80,19 +227,113 @@ pub fn append_config_file(name: String, printer: PrinterConfig) -> Result<(), io
 /// A result containing an optional value, representing whether the operation was successful or not.
 /// If the operation was successful, the optional value is None. Otherwise, it contains a string describing the error that occurred.
 pub fn remove_printer_from_config(printer: String) -> Result<(), io::Error> {
-    let mut printers: Printers = read_config_file().unwrap();
-    let mut file = fs::OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open("./config.txt")
-        .unwrap();
+    let _lock = CONFIG_LOCK.lock().unwrap();
+    let mut printers: Printers = read_config_file()?;
     printers.printers.remove(&printer);
-    let data = serde_json::to_string(&printers).unwrap();
-    let _ = file.write_all(data.as_bytes());
-    let _ = file.flush();
+    write_config_file(&printers)
+}
+
+/// Changes the user accounts in the config file, which is only written if `change` returns `true`.
+///
+/// # Errors
+/// Returns an error if the file can not be read or written.
+pub fn update_users(
+    change: impl FnOnce(&mut BTreeMap<String, UserConfig>) -> bool,
+) -> Result<(), io::Error> {
+    let _lock = CONFIG_LOCK.lock().unwrap();
+    let mut printers: Printers = read_config_file()?;
+    if change(&mut printers.users) {
+        write_config_file(&printers)?;
+    }
+    Ok(())
+}
+
+/// Changes the API tokens in the config file, which is only written if `change` returns `true`.
+///
+/// # Errors
+/// Returns an error if the file can not be read or written.
+pub fn update_api_tokens(
+    change: impl FnOnce(&mut BTreeMap<String, ApiTokenConfig>) -> bool,
+) -> Result<(), io::Error> {
+    let _lock = CONFIG_LOCK.lock().unwrap();
+    let mut printers: Printers = read_config_file()?;
+    if change(&mut printers.api_tokens) {
+        write_config_file(&printers)?;
+    }
     Ok(())
 }
 
+/// Replaces the configuration of a printer, renaming it if `new_name` differs from `name`.
+///
+/// # Arguments
+/// * `name` - The current name of the printer.
+/// * `new_name` - The name the printer has afterwards.
+/// * `printer` - The new printer configuration.
+///
+/// # Errors
+/// Returns an error of kind `NotFound` if there is no printer called `name`, `InvalidInput` if
+/// `new_name` is reserved, `AlreadyExists` if another printer already uses `new_name` or the same
+/// address, or any error writing the file.
+pub fn update_printer_in_config(
+    name: &str,
+    new_name: String,
+    printer: PrinterConfig,
+) -> Result<(), io::Error> {
+    check_printer_name(&new_name)?;
+    let _lock = CONFIG_LOCK.lock().unwrap();
+    let mut printers: Printers = read_config_file()?;
+    if printers.printers.remove(name).is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown printer {name}"),
+        ));
+    }
+    if printers.printers.contains_key(&new_name) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("A printer called {new_name} already exists"),
+        ));
+    }
+    if let Some((other, _)) = printers
+        .printers
+        .iter()
+        .find(|(_, other)| other.address() == printer.address())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{other} already uses {}", printer.address()),
+        ));
+    }
+    rename_references(&mut printers, name, &new_name);
+    printers.printers.insert(new_name, printer);
+    write_config_file(&printers)
+}
+
+/// Points the groups, notification rules and API tokens that name printer `name` at `new_name`.
+fn rename_references(printers: &mut Printers, name: &str, new_name: &str) {
+    let lists = printers
+        .groups
+        .values_mut()
+        .chain(
+            printers
+                .notifications
+                .rules
+                .iter_mut()
+                .map(|rule| &mut rule.printers),
+        )
+        .chain(
+            printers
+                .api_tokens
+                .values_mut()
+                .map(|token| &mut token.printers),
+        );
+    for list in lists {
+        list.iter_mut()
+            .filter(|printer| *printer == name)
+            .for_each(|printer| *printer = new_name.to_string());
+    }
+}
+
 #[test]
 fn test_interaction_with_config_file() {
     // Test that the config file is created if it doesn't exist
@@ -102,9 +343,7 @@ fn test_interaction_with_config_file() {
     // Test that the config file is appended with a correct printer info
     append_config_file(
         "printer1".to_string(),
-        PrinterConfig {
-            ip: "127.0.0.1".parse().unwrap(),
-        },
+        PrinterConfig::new("127.0.0.1".parse().unwrap()),
     )
     .unwrap();
     let printers = read_config_file().unwrap();
@@ -112,16 +351,12 @@ fn test_interaction_with_config_file() {
         printers.printers,
         BTreeMap::from_iter(vec![(
             "printer1".to_string(),
-            PrinterConfig {
-                ip: "127.0.0.1".parse().unwrap(),
-            }
+            PrinterConfig::new("127.0.0.1".parse().unwrap())
         )])
     );
     append_config_file(
         "printer2".to_string(),
-        PrinterConfig {
-            ip: "127.0.0.3".parse().unwrap(),
-        },
+        PrinterConfig::new("127.0.0.3".parse().unwrap()),
     )
     .unwrap();
     let printers = read_config_file().unwrap();
@@ -130,15 +365,11 @@ fn test_interaction_with_config_file() {
         BTreeMap::from_iter(vec![
             (
                 "printer1".to_string(),
-                PrinterConfig {
-                    ip: "127.0.0.1".parse().unwrap(),
-                }
+                PrinterConfig::new("127.0.0.1".parse().unwrap())
             ),
             (
                 "printer2".to_string(),
-                PrinterConfig {
-                    ip: "127.0.0.3".parse().unwrap(),
-                }
+                PrinterConfig::new("127.0.0.3".parse().unwrap())
             )
         ])
     );
@@ -149,11 +380,72 @@ fn test_interaction_with_config_file() {
         printers.printers,
         BTreeMap::from_iter(vec![(
             "printer2".to_string(),
-            PrinterConfig {
-                ip: "127.0.0.3".parse().unwrap(),
-            }
+            PrinterConfig::new("127.0.0.3".parse().unwrap())
         )])
     );
+    // Test that a printer can be renamed and moved in place
+    append_config_file(
+        "printer1".to_string(),
+        PrinterConfig::new("127.0.0.1".parse().unwrap()),
+    )
+    .unwrap();
+    let moved = PrinterConfig {
+        port: 3001,
+        model: Some("Elegoo Mars".to_string()),
+        ..PrinterConfig::new("127.0.0.1".parse().unwrap())
+    };
+    update_printer_in_config("printer2", "printer3".to_string(), moved.clone()).unwrap();
+    let printers = read_config_file().unwrap();
+    assert_eq!(printers.printers.get("printer3"), Some(&moved));
+    assert!(!printers.printers.contains_key("printer2"));
+    // Test that names and addresses of other printers are rejected
+    let error = |name: &str, new_name: &str, port| {
+        update_printer_in_config(
+            name,
+            new_name.to_string(),
+            PrinterConfig {
+                port,
+                ..PrinterConfig::new("127.0.0.1".parse().unwrap())
+            },
+        )
+        .unwrap_err()
+        .kind()
+    };
+    assert_eq!(
+        error("printer3", "printer1", 3001),
+        io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(
+        error("printer3", "printer3", 3000),
+        io::ErrorKind::AlreadyExists
+    );
+    assert_eq!(error("printer2", "printer2", 3002), io::ErrorKind::NotFound);
+    assert_eq!(error("printer3", "mqtt", 3001), io::ErrorKind::InvalidInput);
+    assert_eq!(
+        append_config_file(
+            "groups".to_string(),
+            PrinterConfig::new("127.0.0.4".parse().unwrap())
+        )
+        .unwrap_err()
+        .kind(),
+        io::ErrorKind::InvalidInput
+    );
+    assert_eq!(read_config_file().unwrap().printers.len(), 2);
     // cleanup the file
-    fs::remove_file("./config.txt").expect("Unable to remove file");
+    fs::remove_file(&*CONFIG_FILE).expect("Unable to remove file");
+}
+
+#[test]
+fn test_rename_references() {
+    let mut printers: Printers = serde_json::from_str(
+        r#"{
+            "groups": {"bay1": ["printer1", "printer2"]},
+            "notifications": {"rules": [{"name": "r", "printers": ["printer1"], "channels": []}]},
+            "printer1": {"ip": "127.0.0.1"}
+        }"#,
+    )
+    .unwrap();
+    rename_references(&mut printers, "printer1", "printer3");
+    assert_eq!(printers.groups["bay1"], ["printer3", "printer2"]);
+    assert_eq!(printers.notifications.rules[0].printers, ["printer3"]);
 }
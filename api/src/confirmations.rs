@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use time::{Duration, OffsetDateTime};
+
+use crate::auth::User;
+use crate::page_interface::CommandError;
+
+/// How long a client has to echo a confirmation token back.
+const CONFIRMATION_LIFETIME: Duration = Duration::seconds(60);
+
+/// What a client has to confirm before a destructive command is carried out.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ConfirmationRequest {
+    /// Send the command again with this as `confirmation` to carry it out.
+    pub confirmation: String,
+    /// What will be lost, to show the user.
+    pub description: String,
+    /// RFC 3339 timestamp, the token is refused after it.
+    #[serde(with = "time::serde::rfc3339")]
+    #[schemars(with = "String")]
+    pub expires_at: OffsetDateTime,
+}
+
+/// A command that was asked to be confirmed. A token only confirms the same command by the same
+/// user, once.
+#[derive(Debug, Clone, PartialEq)]
+struct Pending {
+    username: String,
+    action: String,
+    printer: String,
+    expires_at: OffsetDateTime,
+}
+
+/// Confirmation tokens that were handed out and not used yet, kept in memory.
+#[derive(Debug, Default)]
+pub struct Confirmations {
+    pending: HashMap<String, Pending>,
+}
+
+pub static CONFIRMATIONS: Lazy<Mutex<Confirmations>> =
+    Lazy::new(|| Mutex::new(Confirmations::default()));
+
+impl Confirmations {
+    /// Lets a destructive command through if `confirmation` is a token handed out for it, which
+    /// is used up.
+    ///
+    /// # Errors
+    /// `ConfirmationRequired` with a new token and `description` if `confirmation` is missing,
+    /// expired or was handed out for another command.
+    pub fn confirm(
+        &mut self,
+        user: &User,
+        action: &str,
+        printer: &str,
+        confirmation: Option<&str>,
+        description: impl FnOnce() -> String,
+        now: OffsetDateTime,
+    ) -> Result<(), CommandError> {
+        self.pending.retain(|_, pending| pending.expires_at > now);
+        let pending = Pending {
+            username: user.username.clone(),
+            action: action.to_string(),
+            printer: printer.to_string(),
+            expires_at: now + CONFIRMATION_LIFETIME,
+        };
+        if let Some(token) = confirmation {
+            let confirmed = self.pending.get(token).is_some_and(|earlier| {
+                earlier.username == pending.username
+                    && earlier.action == pending.action
+                    && earlier.printer == pending.printer
+            });
+            if confirmed {
+                self.pending.remove(token);
+                return Ok(());
+            }
+        }
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        let request = ConfirmationRequest {
+            confirmation: token.clone(),
+            description: description(),
+            expires_at: pending.expires_at,
+        };
+        self.pending.insert(token, pending);
+        Err(CommandError::ConfirmationRequired(request))
+    }
+}
+
+#[test]
+fn test_confirmations() {
+    use crate::auth::Role;
+
+    let now = OffsetDateTime::UNIX_EPOCH;
+    let alice = User::new("alice", Role::Operator);
+    let bob = User::new("bob", Role::Operator);
+    let mut confirmations = Confirmations::default();
+    let describe = || "Stopping printer1 loses part.ctb at 57.30%".to_string();
+    let ask = |confirmations: &mut Confirmations, user, printer, token, now| match confirmations
+        .confirm(user, "stop", printer, token, describe, now)
+    {
+        Err(CommandError::ConfirmationRequired(request)) => Some(request),
+        Ok(()) => None,
+        Err(e) => panic!("unexpected {e}"),
+    };
+    let request = ask(&mut confirmations, &alice, "printer1", None, now).unwrap();
+    assert_eq!(request.description, describe());
+    assert_eq!(request.expires_at, now + CONFIRMATION_LIFETIME);
+    let token = request.confirmation.as_str();
+    // only the same user confirms the same command
+    assert!(ask(&mut confirmations, &bob, "printer1", Some(token), now).is_some());
+    assert!(ask(&mut confirmations, &alice, "printer2", Some(token), now).is_some());
+    assert!(ask(&mut confirmations, &alice, "printer1", Some("made up"), now).is_some());
+    assert_eq!(
+        ask(&mut confirmations, &alice, "printer1", Some(token), now),
+        None
+    );
+    // used up
+    assert!(ask(&mut confirmations, &alice, "printer1", Some(token), now).is_some());
+    let request = ask(&mut confirmations, &alice, "printer1", None, now).unwrap();
+    let late = now + CONFIRMATION_LIFETIME;
+    assert!(ask(
+        &mut confirmations,
+        &alice,
+        "printer1",
+        Some(&request.confirmation),
+        late
+    )
+    .is_some());
+}
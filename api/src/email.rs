@@ -0,0 +1,346 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use lettre::message::header::ContentType;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::{Deserialize, Serialize};
+use time::macros::format_description;
+use time::OffsetDateTime;
+
+use crate::config_file;
+use crate::print_history::{self, HistoryFilter, JobOutcome, JobRecord};
+use crate::printer_events::{self, EventKind, PrinterEvent};
+
+/// How the connection to the SMTP server is secured.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS, normally on port 587.
+    #[default]
+    Starttls,
+    /// TLS from the start, normally on port 465.
+    Tls,
+    /// No encryption at all, only meant for a local relay or test sink.
+    None,
+}
+
+fn default_port() -> u16 {
+    587
+}
+
+fn default_events() -> Vec<EventKind> {
+    vec![EventKind::Finished, EventKind::Failed]
+}
+
+/// SMTP settings, stored under `"notifications": {"email": ...}` in the config file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EmailConfig {
+    pub server: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    pub from: String,
+    /// Recipients of every printer, on top of the `email_recipients` of each printer.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<String>,
+    /// The events that are mailed as they happen.
+    #[serde(default = "default_events")]
+    pub events: Vec<EventKind>,
+    /// Time of day as `HH:MM` in UTC to send the daily digest, no digest is sent if left out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest_at: Option<String>,
+}
+
+impl EmailConfig {
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>, String> {
+        let builder = match self.security {
+            SmtpSecurity::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&self.server)
+                    .map_err(|e| e.to_string())?
+            }
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&self.server)
+                .map_err(|e| e.to_string())?,
+            SmtpSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.server)
+            }
+        }
+        .port(self.port)
+        .timeout(Some(Duration::from_secs(30)));
+        Ok(match (&self.username, &self.password) {
+            (Some(username), Some(password)) => builder
+                .credentials(Credentials::new(username.clone(), password.clone()))
+                .build(),
+            _ => builder.build(),
+        })
+    }
+}
+
+/// Sends a plain text email to every recipient.
+///
+/// # Errors
+/// Returns a description of the problem if an address is invalid or the server rejects the mail.
+pub async fn send_email(
+    config: &EmailConfig,
+    recipients: &[String],
+    subject: &str,
+    body: String,
+) -> Result<(), String> {
+    let mut builder = Message::builder()
+        .from(
+            config
+                .from
+                .parse()
+                .map_err(|e| format!("from address: {e}"))?,
+        )
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN);
+    for recipient in recipients {
+        builder = builder.to(recipient
+            .parse()
+            .map_err(|e| format!("recipient {recipient}: {e}"))?);
+    }
+    let message = builder.body(body).map_err(|e| e.to_string())?;
+    config
+        .transport()?
+        .send(message)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// The recipients of a printer, the global recipients first and without duplicates.
+pub fn recipients_for(config: &EmailConfig, printer_recipients: &[String]) -> Vec<String> {
+    let mut recipients = config.to.clone();
+    for recipient in printer_recipients {
+        if !recipients.contains(recipient) {
+            recipients.push(recipient.clone());
+        }
+    }
+    recipients
+}
+
+/// Mails a single event, described by `message`, to the recipients of its printer.
+pub async fn send_event(
+    config: &EmailConfig,
+    recipients: Vec<String>,
+    event: &PrinterEvent,
+    message: &str,
+) {
+    if recipients.is_empty() {
+        return;
+    }
+    let subject = format!("[{}] {}", event.printer_name(), event.kind().as_str());
+    if let Err(e) = send_email(config, &recipients, &subject, format!("{message}\n")).await {
+        tracing::warn!("Failed to email event to {recipients:?}: {e}");
+    }
+}
+
+/// Builds the body of the daily digest.
+///
+/// # Arguments
+/// * `printers` - The name and current state of every printer to include.
+/// * `jobs` - Jobs of the last day, jobs of printers not in `printers` are ignored.
+pub fn build_digest(printers: &[(String, String)], jobs: &[JobRecord]) -> String {
+    let mut body = String::from("Print jobs of the last 24 hours\n");
+    for (name, state) in printers {
+        let printer_jobs: Vec<&JobRecord> = jobs
+            .iter()
+            .filter(|job| &job.printer_name == name)
+            .collect();
+        let count = |outcome| printer_jobs.iter().filter(|j| j.outcome == outcome).count();
+        body += &format!(
+            "\n{name}: {state}\n  {} finished, {} stopped, {} failed\n",
+            count(JobOutcome::Finished),
+            count(JobOutcome::Stopped),
+            count(JobOutcome::Failed)
+        );
+        for job in printer_jobs {
+            body += &format!(
+                "  - {} {} after {} minutes at {:.2}%\n",
+                if job.file.is_empty() {
+                    "unknown file"
+                } else {
+                    &job.file
+                },
+                job.outcome.as_str(),
+                job.duration_secs / 60,
+                job.progress
+            );
+        }
+    }
+    body
+}
+
+/// Sends the digest of the last 24 hours, each recipient only gets the printers they receive mail for.
+pub async fn send_digest(config: &EmailConfig) {
+    let printers = match config_file::read_config_file() {
+        Ok(config) => config.printers,
+        Err(e) => {
+            tracing::warn!("Unable to read config for the digest: {e}");
+            return;
+        }
+    };
+    let filter = HistoryFilter {
+        from: Some(OffsetDateTime::now_utc() - time::Duration::days(1)),
+        ..Default::default()
+    };
+    let jobs: Vec<JobRecord> = match print_history::read_jobs(filter) {
+        Ok(jobs) => jobs.collect(),
+        Err(e) => {
+            tracing::warn!("Unable to read history for the digest: {e}");
+            Vec::new()
+        }
+    };
+    let mut per_recipient: BTreeMap<String, Vec<(String, String)>> = BTreeMap::new();
+    let tracker = printer_events::TRACKER.lock().await;
+    for (name, printer) in &printers {
+        for recipient in recipients_for(config, &printer.email_recipients) {
+            per_recipient
+                .entry(recipient)
+                .or_default()
+                .push((name.clone(), tracker.current_state(name)));
+        }
+    }
+    drop(tracker);
+    for (recipient, printers) in per_recipient {
+        let body = build_digest(&printers, &jobs);
+        if let Err(e) = send_email(
+            config,
+            std::slice::from_ref(&recipient),
+            "Daily printer digest",
+            body,
+        )
+        .await
+        {
+            tracing::warn!("Failed to send digest to {recipient}: {e}");
+        }
+    }
+}
+
+/// Checks every minute whether the daily digest is due and sends it.
+pub async fn send_daily_digests() {
+    let time_of_day = format_description!("[hour]:[minute]");
+    let mut last_sent = None;
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let Ok(config) = config_file::read_config_file() else {
+            continue;
+        };
+        let Some(email) = config.notifications.email else {
+            continue;
+        };
+        let now = OffsetDateTime::now_utc();
+        let due = email
+            .digest_at
+            .as_deref()
+            .is_some_and(|at| now.format(time_of_day).is_ok_and(|current| current == at));
+        if due && last_sent != Some(now.date()) {
+            last_sent = Some(now.date());
+            send_digest(&email).await;
+        }
+    }
+}
+
+/// Runs a minimal SMTP server on `listener` that accepts one message and returns its data.
+#[cfg(test)]
+async fn receive_email(listener: &tokio::net::TcpListener) -> String {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    write.write_all(b"220 localhost ready\r\n").await.unwrap();
+    let mut data = String::new();
+    let mut in_data = false;
+    while let Some(line) = lines.next_line().await.unwrap() {
+        if in_data {
+            if line == "." {
+                in_data = false;
+                write.write_all(b"250 queued\r\n").await.unwrap();
+            } else {
+                data += &line;
+                data += "\n";
+            }
+            continue;
+        }
+        let reply: &[u8] = match line
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_uppercase()
+            .as_str()
+        {
+            "EHLO" | "HELO" => b"250 localhost\r\n",
+            "DATA" => {
+                in_data = true;
+                b"354 go ahead\r\n"
+            }
+            "QUIT" => {
+                write.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            }
+            _ => b"250 ok\r\n",
+        };
+        write.write_all(reply).await.unwrap();
+    }
+    data
+}
+
+#[test]
+fn test_build_digest() {
+    let job = JobRecord {
+        printer_name: "printer1".to_string(),
+        file: "part.ctb".to_string(),
+        started_at: OffsetDateTime::UNIX_EPOCH,
+        ended_at: OffsetDateTime::UNIX_EPOCH,
+        duration_secs: 600,
+        outcome: JobOutcome::Failed,
+        progress: 42.0,
+    };
+    let other = JobRecord {
+        printer_name: "printer2".to_string(),
+        ..job.clone()
+    };
+    let digest = build_digest(
+        &[("printer1".to_string(), "idle".to_string())],
+        &[job, other],
+    );
+    assert_eq!(
+        digest,
+        "Print jobs of the last 24 hours\n\
+         \nprinter1: idle\n  0 finished, 0 stopped, 1 failed\n  - part.ctb failed after 10 minutes at 42.00%\n"
+    );
+}
+
+#[tokio::test]
+async fn test_send_email_to_local_sink() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = EmailConfig {
+        server: "127.0.0.1".to_string(),
+        port: listener.local_addr().unwrap().port(),
+        security: SmtpSecurity::None,
+        username: None,
+        password: None,
+        from: "monitor@example.com".to_string(),
+        to: vec!["shop@example.com".to_string()],
+        events: default_events(),
+        digest_at: None,
+    };
+    let recipients = recipients_for(&config, &["bay1@example.com".to_string()]);
+    assert_eq!(recipients, vec!["shop@example.com", "bay1@example.com"]);
+    let sending = tokio::spawn(async move {
+        send_email(&config, &recipients, "Print finished", "done".to_string()).await
+    });
+    let data = receive_email(&listener).await;
+    assert_eq!(sending.await.unwrap(), Ok(()));
+    assert!(data.contains("Subject: Print finished"));
+    assert!(data.contains("To: shop@example.com, bay1@example.com"));
+    assert!(data.ends_with("done\n"));
+}
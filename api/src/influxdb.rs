@@ -0,0 +1,322 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::config_file;
+use crate::printer_events::{PrinterEvent, PrinterSnapshot};
+
+/// Datagrams are kept below a typical MTU so they are not fragmented.
+const MAX_DATAGRAM: usize = 1400;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Unable to build the InfluxDB client")
+});
+
+fn default_max_buffered_lines() -> usize {
+    10_000
+}
+
+/// Where to write telemetry, stored under `"influxdb"` in the config file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InfluxConfig {
+    /// An HTTP write endpoint such as `http://localhost:8086/api/v2/write?org=shop&bucket=printers`
+    /// or `http://localhost:8086/write?db=printers`, or `udp://host:port` for a UDP listener.
+    pub url: String,
+    /// Sent as `Authorization: Token <token>` on HTTP writes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// How many lines are kept while the endpoint can not be reached, the oldest are dropped first.
+    #[serde(default = "default_max_buffered_lines")]
+    pub max_buffered_lines: usize,
+}
+
+/// Lines waiting to be written.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    lines: VecDeque<String>,
+}
+
+impl LineBuffer {
+    pub fn push(&mut self, line: String, max_lines: usize) {
+        self.lines.push_back(line);
+        while self.lines.len() > max_lines {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+}
+
+/// Lines on their way from the poll to the writer task.
+struct Batch {
+    lines: Vec<String>,
+    /// Whether to write everything buffered once the lines are added, set after a poll.
+    write: bool,
+}
+
+static QUEUE: OnceCell<mpsc::UnboundedSender<Batch>> = OnceCell::new();
+
+fn queue(lines: Vec<String>, write: bool) {
+    if let Some(queue) = QUEUE.get() {
+        let _ = queue.send(Batch { lines, write });
+    }
+}
+
+/// Escapes a measurement name, tag key or tag value.
+fn escape_key(value: &str) -> String {
+    value
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Quotes and escapes a string field value.
+fn quote_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn timestamp(at: OffsetDateTime) -> i128 {
+    at.unix_timestamp_nanos()
+}
+
+/// A `printer_state` line for a single poll of a printer.
+pub fn snapshot_line(snapshot: &PrinterSnapshot, at: OffsetDateTime) -> String {
+    let mut fields = vec![
+        format!("phase={}", quote_field(snapshot.phase.as_str())),
+        format!("online={}", snapshot.state.is_some()),
+        format!("progress={}", snapshot.progress),
+        format!("elapsed={}i", snapshot.elapsed_secs),
+    ];
+    if !snapshot.file.is_empty() {
+        fields.push(format!("file={}", quote_field(&snapshot.file)));
+    }
+    if let Some(eta) = snapshot.eta_secs {
+        fields.push(format!("eta={eta}i"));
+    }
+    if let Some(state) = &snapshot.state {
+        fields.extend([
+            format!("z={}", state.z),
+            format!("fan1_pwm={}i", state.f.current),
+            format!("fan2_pwm={}i", state.f.target),
+            format!("bed_temp={}i", state.b.current),
+            format!("bed_target={}i", state.b.target),
+            format!("e1_temp={}i", state.e1.current),
+            format!("e1_target={}i", state.e1.target),
+            format!("e2_temp={}i", state.e2.current),
+            format!("e2_target={}i", state.e2.target),
+        ]);
+    }
+    format!(
+        "printer_state,printer={} {} {}",
+        escape_key(&snapshot.printer_name),
+        fields.join(","),
+        timestamp(at)
+    )
+}
+
+/// A `printer_event` line for a state transition.
+pub fn event_line(event: &PrinterEvent, at: OffsetDateTime) -> String {
+    let mut fields = vec![format!("message={}", quote_field(&event.message()))];
+    if !event.file().is_empty() {
+        fields.push(format!("file={}", quote_field(event.file())));
+    }
+    if let PrinterEvent::JobEnded(record) = event {
+        fields.push(format!("duration={}i", record.duration_secs));
+        fields.push(format!("progress={}", record.progress));
+    }
+    format!(
+        "printer_event,printer={},event={} {} {}",
+        escape_key(event.printer_name()),
+        event.kind().as_str(),
+        fields.join(","),
+        timestamp(at)
+    )
+}
+
+/// Sends lines to the endpoint in as few requests or datagrams as possible.
+///
+/// # Errors
+/// Returns a description of the first write that failed.
+pub async fn write_lines(config: &InfluxConfig, lines: &[String]) -> Result<(), String> {
+    if let Some(address) = config.url.strip_prefix("udp://") {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .await
+            .map_err(|e| e.to_string())?;
+        socket.connect(address).await.map_err(|e| e.to_string())?;
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+                socket
+                    .send(datagram.as_bytes())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                datagram.clear();
+            }
+            datagram += line;
+            datagram.push('\n');
+        }
+        if !datagram.is_empty() {
+            socket
+                .send(datagram.as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        return Ok(());
+    }
+    let mut request = CLIENT.post(&config.url).body(lines.join("\n") + "\n");
+    if let Some(token) = &config.token {
+        request = request.header(reqwest::header::AUTHORIZATION, format!("Token {token}"));
+    }
+    match request.send().await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(format!("{} answered {}", config.url, response.status())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn read_influx_config() -> Option<InfluxConfig> {
+    config_file::read_config_file()
+        .ok()
+        .and_then(|config| config.influxdb)
+}
+
+/// Queues a line for each event, they are written together with the next poll.
+pub fn record_events(events: &[PrinterEvent]) {
+    let now = OffsetDateTime::now_utc();
+    queue(
+        events.iter().map(|event| event_line(event, now)).collect(),
+        false,
+    );
+}
+
+/// Queues a line for every printer of a poll and has the writer task send everything that is
+/// buffered, so a slow endpoint never holds up the poll.
+pub fn write_snapshots(snapshots: &[PrinterSnapshot]) {
+    let now = OffsetDateTime::now_utc();
+    let lines = snapshots
+        .iter()
+        .map(|snapshot| snapshot_line(snapshot, now))
+        .collect();
+    queue(lines, true);
+}
+
+/// Buffers the queued lines and writes them after every poll. Lines stay buffered if the write
+/// fails and are sent again after the next poll, polls that finished during a write are written
+/// together.
+pub async fn write_queued_lines() {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    if QUEUE.set(sender).is_err() {
+        return;
+    }
+    let mut buffer = LineBuffer::default();
+    while let Some(batch) = receiver.recv().await {
+        let Some(config) = read_influx_config() else {
+            buffer = LineBuffer::default();
+            continue;
+        };
+        let mut write = false;
+        let mut next = Some(batch);
+        while let Some(batch) = next {
+            for line in batch.lines {
+                buffer.push(line, config.max_buffered_lines);
+            }
+            write |= batch.write;
+            next = receiver.try_recv().ok();
+        }
+        if !write || buffer.len() == 0 {
+            continue;
+        }
+        match write_lines(&config, buffer.lines.make_contiguous()).await {
+            Ok(()) => buffer.lines.clear(),
+            Err(e) => tracing::warn!(
+                "Unable to write to InfluxDB, {} lines buffered: {e}",
+                buffer.len()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_snapshot() -> PrinterSnapshot {
+    PrinterSnapshot {
+        printer_name: "bay 1,a".to_string(),
+        phase: crate::printer_events::Phase::Printing,
+        file: "part \"v2\".ctb".to_string(),
+        progress: 25.0,
+        elapsed_secs: 60,
+        eta_secs: Some(180),
+        state: Some("B:20/60 Z:12.5 F:256/0 D:25/100/0".parse().unwrap()),
+    }
+}
+
+#[test]
+fn test_lines() {
+    let at = OffsetDateTime::UNIX_EPOCH + time::Duration::seconds(1);
+    assert_eq!(
+        snapshot_line(&test_snapshot(), at),
+        "printer_state,printer=bay\\ 1\\,a phase=\"printing\",online=true,progress=25,elapsed=60i,\
+         file=\"part \\\"v2\\\".ctb\",eta=180i,z=12.5,fan1_pwm=256i,fan2_pwm=0i,bed_temp=20i,\
+         bed_target=60i,e1_temp=0i,e1_target=0i,e2_temp=0i,e2_target=0i 1000000000"
+    );
+    let event = PrinterEvent::Offline {
+        printer_name: "bay=1".to_string(),
+    };
+    assert_eq!(
+        event_line(&event, at),
+        "printer_event,printer=bay\\=1,event=offline message=\"bay=1 went offline\" 1000000000"
+    );
+}
+
+#[test]
+fn test_buffer_drops_oldest() {
+    let mut buffer = LineBuffer::default();
+    for i in 0..5 {
+        buffer.push(i.to_string(), 3);
+    }
+    assert_eq!(buffer.lines, ["2", "3", "4"]);
+}
+
+#[tokio::test]
+async fn test_write_lines() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = InfluxConfig {
+        url: format!(
+            "http://{}/api/v2/write?bucket=printers",
+            listener.local_addr().unwrap()
+        ),
+        token: Some("secret".to_string()),
+        max_buffered_lines: 10,
+    };
+    let lines = vec!["a x=1i 1".to_string(), "b x=2i 2".to_string()];
+    let writing = tokio::spawn({
+        let (config, lines) = (config.clone(), lines.clone());
+        async move { write_lines(&config, &lines).await }
+    });
+    let request = crate::webhook::receive_request(&listener, 204).await;
+    assert_eq!(writing.await.unwrap(), Ok(()));
+    assert!(request.starts_with("POST /api/v2/write?bucket=printers HTTP/1.1"));
+    assert!(request
+        .to_lowercase()
+        .contains("authorization: token secret"));
+    assert!(request.ends_with("a x=1i 1\nb x=2i 2\n"));
+
+    let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = InfluxConfig {
+        url: format!("udp://{}", receiver.local_addr().unwrap()),
+        ..config
+    };
+    write_lines(&config, &lines).await.unwrap();
+    let mut buf = [0; MAX_DATAGRAM];
+    let received = receiver.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..received], b"a x=1i 1\nb x=2i 2\n");
+}
@@ -1,28 +1,125 @@
 use salvo::prelude::*;
 use salvo::serve_static::StaticDir;
 
+mod api_docs;
+mod api_tokens;
+mod assets;
+mod audit;
+mod auth;
+mod claims;
+mod cli;
+mod client;
 mod config_file;
+mod confirmations;
+mod email;
+mod influxdb;
+mod metrics;
+mod mqtt;
+mod notification_rules;
+mod notifications;
 mod page_interface;
 mod parse_printer_state;
+mod paths;
+mod print_history;
+mod printer_events;
 mod printer_interface;
+mod rest_api;
 mod socket;
+mod sse;
+mod status_updates;
+mod subscriptions;
+mod tls;
+mod webhook;
+mod ws_protocol;
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt().init();
-    let router = Router::new()
+/// Every route of the server: the websocket, the feeds outside `/api/v1`, the REST API and the
+/// frontend.
+pub fn router(settings: &cli::Settings) -> Router {
+    Router::new()
         .push(Router::with_path("ws").goal(socket::user_connected))
         .push(
-            Router::with_path("<**path>").get(
-                StaticDir::new(["./"])
-                    .defaults("index.html")
-                    .auto_list(true),
-            ),
-        );
-    let acceptor = TcpListener::new("0.0.0.0:8000").bind().await;
+            Router::with_path("events")
+                .hoop(auth::require_login)
+                .get(sse::events),
+        )
+        .push(
+            Router::with_path("history/export")
+                .hoop(auth::require_login)
+                .get(print_history::export_history),
+        )
+        .push(
+            Router::with_path("metrics")
+                .hoop(auth::require_login)
+                .get(metrics::metrics),
+        )
+        .push(rest_api::router())
+        .push(match settings.asset_dir.as_deref() {
+            Some(dir) => {
+                Router::with_path("<**path>").get(StaticDir::new([dir]).defaults("index.html"))
+            }
+            None => Router::with_path("<**path>").get(assets::serve_asset),
+        })
+}
+
+#[tokio::main]
+async fn main() {
+    let client = cli::init();
+    let settings = cli::settings();
+    cli::init_logging(settings, client.is_some());
+    if let Some(client) = client {
+        let result = tokio::task::spawn_blocking(move || client::run(client)).await;
+        if let Err(e) = result.expect("The client command panicked") {
+            eprintln!("error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    paths::prepare_data_dir().expect("Unable to prepare the data directory");
+    auth::ensure_admin_exists();
+    let router = router(settings);
+    if settings.asset_dir.is_none() && !assets::embedded() {
+        tracing::warn!("The frontend was not built before the binary, only the API is served");
+    }
+    let tls = config_file::read_config_file()
+        .map(|config| config.tls)
+        .unwrap_or_default();
+    let rustls = if tls.enabled {
+        // never fall back to plain HTTP, logins would travel unencrypted without anyone noticing
+        match tls::load(&tls) {
+            Ok(rustls) => Some(rustls),
+            Err(e) => {
+                tracing::error!("Unable to load the TLS certificate: {e}");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let http = TcpListener::new((settings.address, settings.port));
 
     // spawn the task for getting the printer statuses on a cron and then broadcasting it
+    tokio::spawn(influxdb::write_queued_lines());
     tokio::spawn(page_interface::refresh_all_printer_info());
+    tokio::spawn(email::send_daily_digests());
+    tokio::spawn(notifications::escalate_unacknowledged());
+    tokio::spawn(mqtt::run_bridge());
+    tokio::spawn(audit::enforce_retention());
+    tokio::spawn(api_tokens::flush_last_used());
 
-    Server::new(acceptor).serve(router).await;
+    match rustls {
+        None => Server::new(http.bind().await).serve(router).await,
+        Some(rustls) if tls.redirect_http => {
+            let redirect =
+                Router::with_path("<**path>").goal(tls::RedirectToHttps { port: tls.port });
+            tokio::spawn(Server::new(http.bind().await).serve(redirect));
+            let https = TcpListener::new((settings.address, tls.port)).rustls(rustls);
+            Server::new(https.bind().await).serve(router).await;
+        }
+        Some(rustls) => {
+            let https = TcpListener::new((settings.address, tls.port)).rustls(rustls);
+            Server::new(https.join(http).bind().await)
+                .serve(router)
+                .await;
+        }
+    }
 }
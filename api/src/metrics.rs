@@ -0,0 +1,223 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_gauge_vec_with_registry, register_histogram_vec_with_registry,
+    register_int_counter_vec_with_registry, register_int_gauge_with_registry, Encoder, GaugeVec,
+    HistogramVec, IntCounterVec, IntGauge, Registry, TextEncoder,
+};
+use salvo::http::header::CONTENT_TYPE;
+use salvo::prelude::*;
+
+use crate::printer_events::{Phase, PrinterSnapshot};
+use crate::socket;
+
+/// Every metric the monitor exports, registered in their own registry so nothing else ends up on `/metrics`.
+struct Metrics {
+    registry: Registry,
+    online: GaugeVec,
+    printing: GaugeVec,
+    paused: GaugeVec,
+    progress: GaugeVec,
+    z_position: GaugeVec,
+    elapsed: GaugeVec,
+    fan_pwm: GaugeVec,
+    temperature: GaugeVec,
+    udp_requests: IntCounterVec,
+    udp_timeouts: IntCounterVec,
+    udp_latency: HistogramVec,
+    websocket_users: IntGauge,
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| {
+    let registry = Registry::new_custom(Some("rusty_printer_monitor".to_string()), None)
+        .expect("metric prefix is valid");
+    let printer_gauge = |name: &str, help: &str| {
+        register_gauge_vec_with_registry!(name, help, &["printer"], registry).unwrap()
+    };
+    Metrics {
+        online: printer_gauge("printer_online", "1 if the printer answered the last poll"),
+        printing: printer_gauge("printer_printing", "1 if the printer is running a job"),
+        paused: printer_gauge("printer_paused", "1 if the job of the printer is paused"),
+        progress: printer_gauge(
+            "printer_progress_ratio",
+            "Part of the current file that has been printed, from 0 to 1",
+        ),
+        z_position: printer_gauge("printer_z_position_mm", "Position of the Z axis"),
+        elapsed: printer_gauge(
+            "printer_job_elapsed_seconds",
+            "Time since the current job was first seen",
+        ),
+        fan_pwm: register_gauge_vec_with_registry!(
+            "printer_fan_pwm",
+            "Fan PWM as reported by the printer, out of 256",
+            &["printer", "fan"],
+            registry
+        )
+        .unwrap(),
+        temperature: register_gauge_vec_with_registry!(
+            "printer_temperature_celsius",
+            "Temperatures as reported by the printer",
+            &["printer", "sensor", "kind"],
+            registry
+        )
+        .unwrap(),
+        udp_requests: register_int_counter_vec_with_registry!(
+            "udp_requests_total",
+            "Gcode requests sent to printers",
+            &["ip", "gcode"],
+            registry
+        )
+        .unwrap(),
+        udp_timeouts: register_int_counter_vec_with_registry!(
+            "udp_timeouts_total",
+            "Gcode requests that timed out waiting for the printer",
+            &["ip", "gcode"],
+            registry
+        )
+        .unwrap(),
+        udp_latency: register_histogram_vec_with_registry!(
+            "udp_request_duration_seconds",
+            "Time from sending gcode to the final answer or timeout",
+            &["ip", "gcode"],
+            vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 2.5, 5.0],
+            registry
+        )
+        .unwrap(),
+        websocket_users: register_int_gauge_with_registry!(
+            "websocket_users",
+            "Connected websocket users",
+            registry
+        )
+        .unwrap(),
+        registry,
+    }
+});
+
+/// Records a gcode request to a printer, labelled by the gcode without its parameters.
+pub fn record_udp_request(ip_addr: IpAddr, gcode: &str, duration: Duration, timed_out: bool) {
+    let ip = ip_addr.to_string();
+    let labels = [ip.as_str(), gcode.split_whitespace().next().unwrap_or("")];
+    METRICS.udp_requests.with_label_values(&labels).inc();
+    if timed_out {
+        METRICS.udp_timeouts.with_label_values(&labels).inc();
+    }
+    METRICS
+        .udp_latency
+        .with_label_values(&labels)
+        .observe(duration.as_secs_f64());
+}
+
+/// Replaces the printer gauges with the result of the latest poll, so removed printers disappear.
+pub fn update_printer_metrics(snapshots: &[PrinterSnapshot]) {
+    let m = &*METRICS;
+    for gauge in [
+        &m.online,
+        &m.printing,
+        &m.paused,
+        &m.progress,
+        &m.z_position,
+        &m.elapsed,
+        &m.fan_pwm,
+        &m.temperature,
+    ] {
+        gauge.reset();
+    }
+    let flag = |value: bool| if value { 1.0 } else { 0.0 };
+    for snapshot in snapshots {
+        let name = snapshot.printer_name.as_str();
+        m.online
+            .with_label_values(&[name])
+            .set(flag(snapshot.phase != Phase::Offline));
+        m.printing.with_label_values(&[name]).set(flag(matches!(
+            snapshot.phase,
+            Phase::Printing | Phase::Paused
+        )));
+        m.paused
+            .with_label_values(&[name])
+            .set(flag(snapshot.phase == Phase::Paused));
+        m.progress
+            .with_label_values(&[name])
+            .set(snapshot.progress / 100.0);
+        m.elapsed
+            .with_label_values(&[name])
+            .set(snapshot.elapsed_secs as f64);
+        let Some(state) = &snapshot.state else {
+            continue;
+        };
+        m.z_position.with_label_values(&[name]).set(state.z as f64);
+        m.fan_pwm
+            .with_label_values(&[name, "1"])
+            .set(state.f.current as f64);
+        m.fan_pwm
+            .with_label_values(&[name, "2"])
+            .set(state.f.target as f64);
+        for (sensor, pair) in [("bed", &state.b), ("e1", &state.e1), ("e2", &state.e2)] {
+            m.temperature
+                .with_label_values(&[name, sensor, "current"])
+                .set(pair.current as f64);
+            m.temperature
+                .with_label_values(&[name, sensor, "target"])
+                .set(pair.target as f64);
+        }
+    }
+}
+
+/// Renders every metric in the Prometheus text format.
+pub async fn render() -> String {
+    METRICS
+        .websocket_users
+        .set(socket::ONLINE_USERS.read().await.len() as i64);
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .expect("metrics encode as text");
+    String::from_utf8(buffer).unwrap()
+}
+
+#[handler]
+pub async fn metrics(res: &mut Response) {
+    let _ = res.add_header(CONTENT_TYPE, TextEncoder::new().format_type(), true);
+    res.render(render().await);
+}
+
+#[tokio::test]
+async fn test_render_metrics() {
+    let snapshot = PrinterSnapshot {
+        printer_name: "printer1".to_string(),
+        phase: Phase::Paused,
+        file: String::new(),
+        progress: 50.0,
+        elapsed_secs: 120,
+        eta_secs: Some(120),
+        state: Some(
+            "ok B:20/60 X:0.000 Y:0.000 Z:12.5 F:256/0 D:50/100/1"
+                .parse()
+                .unwrap(),
+        ),
+    };
+    update_printer_metrics(&[snapshot]);
+    record_udp_request(
+        "127.0.0.1".parse().unwrap(),
+        "M6030 \"part.ctb\"",
+        Duration::from_millis(20),
+        true,
+    );
+    let text = render().await;
+    for line in [
+        "rusty_printer_monitor_printer_online{printer=\"printer1\"} 1",
+        "rusty_printer_monitor_printer_printing{printer=\"printer1\"} 1",
+        "rusty_printer_monitor_printer_paused{printer=\"printer1\"} 1",
+        "rusty_printer_monitor_printer_progress_ratio{printer=\"printer1\"} 0.5",
+        "rusty_printer_monitor_printer_z_position_mm{printer=\"printer1\"} 12.5",
+        "rusty_printer_monitor_printer_fan_pwm{fan=\"1\",printer=\"printer1\"} 256",
+        "rusty_printer_monitor_printer_temperature_celsius{kind=\"target\",printer=\"printer1\",sensor=\"bed\"} 60",
+        "rusty_printer_monitor_udp_requests_total{gcode=\"M6030\",ip=\"127.0.0.1\"} 1",
+        "rusty_printer_monitor_udp_timeouts_total{gcode=\"M6030\",ip=\"127.0.0.1\"} 1",
+        "rusty_printer_monitor_udp_request_duration_seconds_bucket{gcode=\"M6030\",ip=\"127.0.0.1\",le=\"0.025\"} 1",
+        "rusty_printer_monitor_websocket_users 0",
+    ] {
+        assert!(text.contains(line), "{line} missing from\n{text}");
+    }
+}
@@ -0,0 +1,461 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::{Lazy, OnceCell};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::auth::{Role, User};
+use crate::page_interface::CommandError;
+use crate::printer_events::PrinterSnapshot;
+use crate::{config_file, page_interface};
+
+/// Commands accepted on `<base_topic>/<printer>/set`.
+const COMMANDS: [&str; 3] = ["pause", "resume", "stop"];
+
+/// The commands offered as Home Assistant buttons. A button only publishes a fixed payload, so
+/// it could never send the confirmation a `stop` needs.
+const BUTTONS: [&str; 2] = ["pause", "resume"];
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "rusty_printer_monitor".to_string()
+}
+
+fn default_base_topic() -> String {
+    "rusty_printer_monitor".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_role() -> Role {
+    Role::Viewer
+}
+
+/// Broker settings, stored under `"mqtt"` in the config file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    /// Every topic of the monitor starts with this.
+    #[serde(default = "default_base_topic")]
+    pub base_topic: String,
+    /// Whether to publish Home Assistant discovery configs.
+    #[serde(default = "default_true")]
+    pub discovery: bool,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// What whoever may publish to the broker may do. Commands on `/set` topics are only
+    /// listened to from `operator` up, the default `viewer` only publishes.
+    #[serde(default = "default_role")]
+    pub role: Role,
+}
+
+impl MqttConfig {
+    /// Whether commands are taken from the broker, and offered as Home Assistant buttons.
+    fn accepts_commands(&self) -> bool {
+        self.role >= Role::Operator
+    }
+
+    fn bridge_availability_topic(&self) -> String {
+        format!("{}/status", self.base_topic)
+    }
+
+    pub fn printer_topic(&self, printer_name: &str, leaf: &str) -> String {
+        format!("{}/{}/{leaf}", self.base_topic, topic_id(printer_name))
+    }
+}
+
+struct Bridge {
+    client: AsyncClient,
+    config: MqttConfig,
+}
+
+static BRIDGE: OnceCell<Bridge> = OnceCell::new();
+
+/// Printers whose discovery configs have been published, with the model they were announced with.
+/// Only ever locked briefly and never across an await, the event loop clears it on reconnect.
+static ANNOUNCED: Lazy<Mutex<BTreeMap<String, Option<String>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Turns a printer name into something safe to use as a single topic level or object id.
+pub fn topic_id(printer_name: &str) -> String {
+    printer_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// The retained state message of a printer.
+pub fn state_payload(snapshot: &PrinterSnapshot) -> String {
+    json!({
+        "phase": snapshot.phase.as_str(),
+        "file": snapshot.file,
+        "progress": (snapshot.progress * 100.0).round() / 100.0,
+        "z": snapshot.state.as_ref().map(|s| s.z),
+        "elapsed": snapshot.elapsed_secs,
+        "eta": snapshot.eta_secs,
+    })
+    .to_string()
+}
+
+/// Home Assistant discovery configs for a printer, as `(topic, payload)` pairs.
+pub fn discovery_messages(
+    config: &MqttConfig,
+    printer_name: &str,
+    model: Option<&str>,
+) -> Vec<(String, String)> {
+    let id = topic_id(printer_name);
+    let mut device = json!({
+        "identifiers": [format!("rusty_printer_monitor_{id}")],
+        "name": printer_name,
+        "manufacturer": "CHITU",
+    });
+    if let Some(model) = model {
+        device["model"] = json!(model);
+    }
+    let availability = json!([
+        {"topic": config.bridge_availability_topic()},
+        {"topic": config.printer_topic(printer_name, "availability")},
+    ]);
+    let state_topic = config.printer_topic(printer_name, "state");
+    let sensors = [
+        ("phase", "Status", None, None),
+        ("file", "File", None, None),
+        ("progress", "Progress", Some("%"), None),
+        ("z", "Z Height", Some("mm"), Some("distance")),
+        ("eta", "Time Remaining", Some("s"), Some("duration")),
+    ];
+    let mut messages: Vec<(String, String)> = sensors
+        .iter()
+        .map(|(key, name, unit, device_class)| {
+            let mut payload = json!({
+                "name": name,
+                "unique_id": format!("rusty_printer_monitor_{id}_{key}"),
+                "state_topic": state_topic,
+                "value_template": format!("{{{{ value_json.{key} }}}}"),
+                "availability": availability,
+                "availability_mode": "all",
+                "device": device,
+            });
+            if let Some(unit) = unit {
+                payload["unit_of_measurement"] = json!(unit);
+            }
+            if let Some(device_class) = device_class {
+                payload["device_class"] = json!(device_class);
+            }
+            (
+                format!("{}/sensor/{id}/{key}/config", config.discovery_prefix),
+                payload.to_string(),
+            )
+        })
+        .collect();
+    if !config.accepts_commands() {
+        return messages;
+    }
+    messages.extend(BUTTONS.iter().map(|command| {
+        let payload = json!({
+            "name": format!("{}{}", command[..1].to_uppercase(), &command[1..]),
+            "unique_id": format!("rusty_printer_monitor_{id}_{command}"),
+            "command_topic": config.printer_topic(printer_name, "set"),
+            "payload_press": command,
+            "availability": availability,
+            "availability_mode": "all",
+            "device": device,
+        });
+        (
+            format!("{}/button/{id}/{command}/config", config.discovery_prefix),
+            payload.to_string(),
+        )
+    }));
+    messages
+}
+
+/// Finds the printer a `<base_topic>/<printer>/set` topic refers to.
+pub fn printer_for_set_topic<'a>(
+    config: &MqttConfig,
+    topic: &str,
+    printer_names: impl IntoIterator<Item = &'a String>,
+) -> Option<&'a String> {
+    let id = topic
+        .strip_prefix(&format!("{}/", config.base_topic))?
+        .strip_suffix("/set")?;
+    printer_names.into_iter().find(|name| topic_id(name) == id)
+}
+
+/// Queues a message without waiting, so neither the poll loop nor the MQTT event loop ever blocks
+/// on a full request queue while the broker is unreachable. Messages that do not fit are dropped,
+/// the retained state is published again on the next poll.
+fn publish(client: &AsyncClient, topic: String, retain: bool, payload: String) {
+    if let Err(e) = client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+        tracing::warn!("Unable to queue MQTT message: {e}");
+    }
+}
+
+/// Discovery configs to publish to go from `announced` to `current`: empty configs removing the
+/// printers that are gone, and full configs for new printers and printers whose model changed.
+fn announcements(
+    config: &MqttConfig,
+    announced: &BTreeMap<String, Option<String>>,
+    current: &BTreeMap<String, Option<String>>,
+) -> Vec<(String, String)> {
+    let mut messages = Vec::new();
+    for removed in announced.keys().filter(|name| !current.contains_key(*name)) {
+        messages.extend(
+            discovery_messages(config, removed, None)
+                .into_iter()
+                .map(|(topic, _)| (topic, String::new())),
+        );
+    }
+    for (added, model) in current
+        .iter()
+        .filter(|(name, model)| announced.get(*name) != Some(*model))
+    {
+        messages.extend(discovery_messages(config, added, model.as_deref()));
+    }
+    messages
+}
+
+/// Passes a command received on a `/set` topic on to the same handling as the websocket. A
+/// `stop` is answered on the printer's `confirmation` topic and carried out by `stop <token>`.
+async fn handle_command(client: &AsyncClient, config: &MqttConfig, topic: &str, payload: &[u8]) {
+    let Ok(printers) = config_file::read_config_file() else {
+        return;
+    };
+    let Some(name) = printer_for_set_topic(config, topic, printers.printers.keys()) else {
+        tracing::warn!("MQTT command for unknown printer on {topic}");
+        return;
+    };
+    let payload = String::from_utf8_lossy(payload);
+    let (action, confirmation) = match payload.trim().split_once(' ') {
+        Some((action, confirmation)) => (action, Some(confirmation.trim().to_string())),
+        None => (payload.trim(), None),
+    };
+    let action = action.to_lowercase();
+    if !COMMANDS.contains(&action.as_str()) {
+        tracing::warn!("MQTT command {action} on {topic} is not supported");
+        return;
+    }
+    let user = User::new("mqtt", config.role);
+    match page_interface::run_print_action(&user, name, &action, None, confirmation).await {
+        Ok(()) => {}
+        Err(CommandError::ConfirmationRequired(request)) => {
+            let topic = config.printer_topic(name, "confirmation");
+            publish(
+                client,
+                topic,
+                false,
+                serde_json::to_string(&request).unwrap(),
+            );
+        }
+        Err(e) => tracing::warn!("MQTT command {action} on {topic} failed: {e}"),
+    }
+}
+
+/// Connects to the broker from the config file, if there is one, and keeps the connection alive.
+/// The bridge is only set up at start up, so changes to the `mqtt` settings need a restart.
+pub async fn run_bridge() {
+    let Some(config) = config_file::read_config_file()
+        .ok()
+        .and_then(|config| config.mqtt)
+    else {
+        return;
+    };
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        config.bridge_availability_topic(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        options.set_credentials(username, password);
+    }
+    let (client, mut event_loop) = AsyncClient::new(options, 100);
+    let bridge = Bridge {
+        client: client.clone(),
+        config: config.clone(),
+    };
+    if BRIDGE.set(bridge).is_err() {
+        return;
+    }
+    tracing::info!("Connecting to MQTT broker {}:{}", config.host, config.port);
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker");
+                publish(
+                    &client,
+                    config.bridge_availability_topic(),
+                    true,
+                    "online".to_string(),
+                );
+                let set_topic = format!("{}/+/set", config.base_topic);
+                if config.accepts_commands() {
+                    if let Err(e) = client.try_subscribe(set_topic, QoS::AtLeastOnce) {
+                        tracing::warn!("Unable to subscribe to MQTT commands: {e}");
+                    }
+                }
+                // announce everything again on the next poll in case the broker lost retained messages
+                ANNOUNCED.lock().unwrap().clear();
+            }
+            Ok(Event::Incoming(Packet::Publish(message))) => {
+                // handled on its own task as the command publishes a refresh through this event loop
+                let config = config.clone();
+                let client = client.clone();
+                tokio::spawn(async move {
+                    handle_command(&client, &config, &message.topic, &message.payload).await
+                });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!("MQTT connection error: {e}");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    }
+}
+
+/// Publishes the state and availability of every printer, announcing new printers to Home
+/// Assistant and removing the discovery configs of printers that are gone.
+pub async fn publish_snapshots(snapshots: &[PrinterSnapshot]) {
+    let Some(bridge) = BRIDGE.get() else {
+        return;
+    };
+    let mut models = config_file::read_config_file()
+        .map(|config| config.printers)
+        .unwrap_or_default();
+    let current: BTreeMap<String, Option<String>> = snapshots
+        .iter()
+        .map(|s| {
+            let model = models
+                .remove(&s.printer_name)
+                .and_then(|printer| printer.model);
+            (s.printer_name.clone(), model)
+        })
+        .collect();
+    let previous = std::mem::replace(&mut *ANNOUNCED.lock().unwrap(), current.clone());
+    if bridge.config.discovery {
+        for (topic, payload) in announcements(&bridge.config, &previous, &current) {
+            publish(&bridge.client, topic, true, payload);
+        }
+    }
+    for snapshot in snapshots {
+        let name = &snapshot.printer_name;
+        let available = if snapshot.state.is_some() {
+            "online"
+        } else {
+            "offline"
+        };
+        publish(
+            &bridge.client,
+            bridge.config.printer_topic(name, "availability"),
+            true,
+            available.to_string(),
+        );
+        publish(
+            &bridge.client,
+            bridge.config.printer_topic(name, "state"),
+            true,
+            state_payload(snapshot),
+        );
+    }
+}
+
+#[cfg(test)]
+fn test_config() -> MqttConfig {
+    serde_json::from_str(r#"{"host": "localhost"}"#).unwrap()
+}
+
+#[test]
+fn test_topics() {
+    let config = test_config();
+    assert_eq!(topic_id("Bay 1/Printer#2"), "bay_1_printer_2");
+    assert_eq!(
+        config.printer_topic("Bay 1", "state"),
+        "rusty_printer_monitor/bay_1/state"
+    );
+    let names = ["Bay 1".to_string(), "Bay 2".to_string()];
+    assert_eq!(
+        printer_for_set_topic(&config, "rusty_printer_monitor/bay_2/set", &names),
+        Some(&names[1])
+    );
+    assert_eq!(
+        printer_for_set_topic(&config, "rusty_printer_monitor/bay_3/set", &names),
+        None
+    );
+    assert_eq!(
+        printer_for_set_topic(&config, "other/bay_2/set", &names),
+        None
+    );
+}
+
+#[test]
+fn test_discovery_messages() {
+    assert_eq!(discovery_messages(&test_config(), "Bay 1", None).len(), 5);
+    let config = MqttConfig {
+        role: Role::Operator,
+        ..test_config()
+    };
+    let messages = discovery_messages(&config, "Bay 1", Some("Elegoo Mars"));
+    assert_eq!(messages.len(), 7);
+    let (topic, payload) = &messages[2];
+    assert_eq!(topic, "homeassistant/sensor/bay_1/progress/config");
+    let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+    assert_eq!(payload["state_topic"], "rusty_printer_monitor/bay_1/state");
+    assert_eq!(payload["value_template"], "{{ value_json.progress }}");
+    assert_eq!(payload["unit_of_measurement"], "%");
+    assert_eq!(payload["device"]["name"], "Bay 1");
+    assert_eq!(payload["device"]["model"], "Elegoo Mars");
+    let (topic, payload) = &messages[6];
+    assert_eq!(topic, "homeassistant/button/bay_1/resume/config");
+    let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+    assert_eq!(payload["command_topic"], "rusty_printer_monitor/bay_1/set");
+    assert_eq!(payload["payload_press"], "resume");
+    assert_eq!(payload["name"], "Resume");
+}
+
+#[test]
+fn test_announcements() {
+    let config = test_config();
+    let announced = BTreeMap::from([
+        ("Bay 1".to_string(), None),
+        ("Bay 2".to_string(), Some("Elegoo Mars".to_string())),
+    ]);
+    let current = BTreeMap::from([
+        ("Bay 2".to_string(), Some("Elegoo Mars".to_string())),
+        ("Bay 3".to_string(), None),
+    ]);
+    let messages = announcements(&config, &announced, &current);
+    assert_eq!(messages.len(), 10);
+    assert_eq!(messages[0].0, "homeassistant/sensor/bay_1/phase/config");
+    assert!(messages[..5].iter().all(|(_, payload)| payload.is_empty()));
+    assert_eq!(messages[5].0, "homeassistant/sensor/bay_3/phase/config");
+    assert!(messages[5..].iter().all(|(_, payload)| !payload.is_empty()));
+    assert!(announcements(&config, &current, &current).is_empty());
+}
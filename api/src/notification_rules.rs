@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use time::macros::format_description;
+use time::{OffsetDateTime, Time};
+use tokio::sync::Mutex;
+
+use crate::printer_events::{EventKind, PrinterEvent};
+
+/// A time window in UTC, given as `HH:MM`, in which a rule sends nothing.
+/// The window wraps around midnight when `start` is after `end`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuietHours {
+    pub start: String,
+    pub end: String,
+}
+
+impl QuietHours {
+    pub fn contains(&self, at: OffsetDateTime) -> bool {
+        let format = format_description!("[hour]:[minute]");
+        let (Ok(start), Ok(end)) = (
+            Time::parse(&self.start, format),
+            Time::parse(&self.end, format),
+        ) else {
+            tracing::warn!(
+                "Ignoring quiet hours {}-{}, expected HH:MM",
+                self.start,
+                self.end
+            );
+            return false;
+        };
+        let now = at.time();
+        if start <= end {
+            start <= now && now < end
+        } else {
+            now >= start || now < end
+        }
+    }
+}
+
+/// Decides which channels get which events, stored under `"notifications": {"rules": [...]}`.
+///
+/// Channels are named by the `name` of a webhook, or `email` for the email settings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotificationRule {
+    pub name: String,
+    /// The events the rule applies to, every event if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<EventKind>,
+    /// The printers the rule applies to, every printer if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub printers: Vec<String>,
+    pub channels: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHours>,
+    /// The same event for the same printer is not sent again within this many minutes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_repeat_minutes: Option<i64>,
+    /// Send the event to `escalate_to` if it has not been acknowledged after this many minutes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub escalate_after_minutes: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub escalate_to: Vec<String>,
+}
+
+impl NotificationRule {
+    fn applies_to(&self, event: &PrinterEvent) -> bool {
+        (self.events.is_empty() || self.events.contains(&event.kind()))
+            && (self.printers.is_empty() || self.printers.iter().any(|p| p == event.printer_name()))
+    }
+}
+
+#[derive(Debug)]
+struct PendingEscalation {
+    rule: String,
+    event: PrinterEvent,
+    raised_at: OffsetDateTime,
+}
+
+/// What the rules remember between events: when something was last sent and what still waits
+/// for an acknowledgement. Rules themselves are read from the config for every event, so edits
+/// to the config file apply to the next event without a restart.
+#[derive(Debug, Default)]
+pub struct RulesState {
+    last_sent: HashMap<(String, String, EventKind), OffsetDateTime>,
+    pending: Vec<PendingEscalation>,
+}
+
+pub static RULES_STATE: Lazy<Mutex<RulesState>> = Lazy::new(|| Mutex::new(RulesState::default()));
+
+/// Events that make an earlier, still unacknowledged event of the same printer irrelevant.
+fn resolved_by(kind: EventKind) -> &'static [EventKind] {
+    match kind {
+        EventKind::Online => &[EventKind::Offline],
+        EventKind::Resumed => &[EventKind::Paused, EventKind::Stalled],
+        EventKind::Finished | EventKind::Stopped | EventKind::Failed => {
+            &[EventKind::Paused, EventKind::Stalled]
+        }
+        _ => &[],
+    }
+}
+
+impl RulesState {
+    /// Works out the channels an event goes to under `rules`, remembering it for repeat
+    /// suppression and escalation.
+    pub fn route(
+        &mut self,
+        rules: &[NotificationRule],
+        event: &PrinterEvent,
+        now: OffsetDateTime,
+    ) -> Vec<String> {
+        let printer_name = event.printer_name().to_string();
+        let resolved = resolved_by(event.kind());
+        self.pending.retain(|pending| {
+            pending.event.printer_name() != printer_name
+                || !resolved.contains(&pending.event.kind())
+        });
+
+        let mut channels: Vec<String> = Vec::new();
+        for rule in rules.iter().filter(|rule| rule.applies_to(event)) {
+            if rule
+                .quiet_hours
+                .as_ref()
+                .is_some_and(|quiet| quiet.contains(now))
+            {
+                continue;
+            }
+            let key = (rule.name.clone(), printer_name.clone(), event.kind());
+            if let (Some(minutes), Some(last)) = (rule.min_repeat_minutes, self.last_sent.get(&key))
+            {
+                if now - *last < time::Duration::minutes(minutes) {
+                    continue;
+                }
+            }
+            self.last_sent.insert(key, now);
+            if rule.escalate_after_minutes.is_some() && !rule.escalate_to.is_empty() {
+                self.pending.push(PendingEscalation {
+                    rule: rule.name.clone(),
+                    event: event.clone(),
+                    raised_at: now,
+                });
+            }
+            for channel in &rule.channels {
+                if !channels.contains(channel) {
+                    channels.push(channel.clone());
+                }
+            }
+        }
+        channels
+    }
+
+    /// Marks every pending event of a printer as seen, so none of them are escalated.
+    pub fn acknowledge(&mut self, printer_name: &str) {
+        self.pending
+            .retain(|pending| pending.event.printer_name() != printer_name);
+    }
+
+    /// Moves the repeat suppression and pending escalations of a printer to its new name.
+    pub fn rename(&mut self, printer_name: &str, new_name: &str) {
+        self.last_sent = self
+            .last_sent
+            .drain()
+            .map(|((rule, name, kind), at)| {
+                let name = if name == printer_name {
+                    new_name.to_string()
+                } else {
+                    name
+                };
+                ((rule, name, kind), at)
+            })
+            .collect();
+        self.pending
+            .iter_mut()
+            .filter(|pending| pending.event.printer_name() == printer_name)
+            .for_each(|pending| pending.event.rename(new_name));
+    }
+
+    /// Takes the events that have waited longer than their rule allows, with the channels to
+    /// escalate them to. Events whose rule has been removed from the config are dropped.
+    pub fn due_escalations(
+        &mut self,
+        rules: &[NotificationRule],
+        now: OffsetDateTime,
+    ) -> Vec<(Vec<String>, PrinterEvent)> {
+        let mut due = Vec::new();
+        self.pending.retain(|pending| {
+            let Some(rule) = rules.iter().find(|rule| rule.name == pending.rule) else {
+                return false;
+            };
+            let Some(minutes) = rule.escalate_after_minutes else {
+                return false;
+            };
+            if now - pending.raised_at < time::Duration::minutes(minutes) {
+                return true;
+            }
+            due.push((rule.escalate_to.clone(), pending.event.clone()));
+            false
+        });
+        due
+    }
+}
+
+#[cfg(test)]
+fn offline(printer_name: &str) -> PrinterEvent {
+    PrinterEvent::Offline {
+        printer_name: printer_name.to_string(),
+    }
+}
+
+#[cfg(test)]
+fn rule(name: &str, channels: &[&str]) -> NotificationRule {
+    NotificationRule {
+        name: name.to_string(),
+        events: Vec::new(),
+        printers: Vec::new(),
+        channels: channels.iter().map(|c| c.to_string()).collect(),
+        quiet_hours: None,
+        min_repeat_minutes: None,
+        escalate_after_minutes: None,
+        escalate_to: Vec::new(),
+    }
+}
+
+#[test]
+fn test_quiet_hours() {
+    let night = QuietHours {
+        start: "22:00".to_string(),
+        end: "07:00".to_string(),
+    };
+    let at = |hour| OffsetDateTime::UNIX_EPOCH + time::Duration::hours(hour);
+    assert!(night.contains(at(23)));
+    assert!(night.contains(at(3)));
+    assert!(!night.contains(at(7)));
+    assert!(!night.contains(at(12)));
+    let lunch = QuietHours {
+        start: "12:00".to_string(),
+        end: "13:00".to_string(),
+    };
+    assert!(lunch.contains(at(12)));
+    assert!(!lunch.contains(at(13)));
+}
+
+#[test]
+fn test_route_filters_and_deduplicates() {
+    let now = OffsetDateTime::UNIX_EPOCH;
+    let rules = vec![
+        NotificationRule {
+            events: vec![EventKind::Offline],
+            min_repeat_minutes: Some(30),
+            ..rule("offline", &["ops", "email"])
+        },
+        NotificationRule {
+            printers: vec!["printer2".to_string()],
+            ..rule("bay2", &["email", "bay2"])
+        },
+        NotificationRule {
+            quiet_hours: Some(QuietHours {
+                start: "00:00".to_string(),
+                end: "01:00".to_string(),
+            }),
+            ..rule("daytime", &["pager"])
+        },
+    ];
+    let mut state = RulesState::default();
+    assert_eq!(
+        state.route(&rules, &offline("printer2"), now),
+        vec!["ops", "email", "bay2"]
+    );
+    // the offline rule is rate limited, the bay2 rule is not
+    assert_eq!(
+        state.route(
+            &rules,
+            &offline("printer2"),
+            now + time::Duration::minutes(5)
+        ),
+        vec!["email", "bay2"]
+    );
+    assert_eq!(
+        state.route(
+            &rules,
+            &offline("printer1"),
+            now + time::Duration::minutes(5)
+        ),
+        vec!["ops", "email"]
+    );
+    assert_eq!(
+        state.route(
+            &rules,
+            &offline("printer1"),
+            now + time::Duration::minutes(95)
+        ),
+        vec!["ops", "email", "pager"]
+    );
+}
+
+#[test]
+fn test_escalation() {
+    let now = OffsetDateTime::UNIX_EPOCH;
+    let rules = vec![NotificationRule {
+        events: vec![EventKind::Offline],
+        escalate_after_minutes: Some(15),
+        escalate_to: vec!["pager".to_string()],
+        ..rule("escalating", &["ops"])
+    }];
+    let mut state = RulesState::default();
+    state.route(&rules, &offline("printer1"), now);
+    state.route(&rules, &offline("printer2"), now);
+    state.route(&rules, &offline("printer3"), now);
+    assert!(state
+        .due_escalations(&rules, now + time::Duration::minutes(10))
+        .is_empty());
+    state.acknowledge("printer1");
+    state.route(
+        &rules,
+        &PrinterEvent::Online {
+            printer_name: "printer2".to_string(),
+        },
+        now,
+    );
+    assert_eq!(
+        state.due_escalations(&rules, now + time::Duration::minutes(15)),
+        vec![(vec!["pager".to_string()], offline("printer3"))]
+    );
+    assert!(state
+        .due_escalations(&rules, now + time::Duration::minutes(30))
+        .is_empty());
+
+    state.route(&rules, &offline("printer1"), now);
+    state.rename("printer1", "printer4");
+    assert_eq!(
+        state.due_escalations(&rules, now + time::Duration::minutes(15)),
+        vec![(vec!["pager".to_string()], offline("printer4"))]
+    );
+}
@@ -0,0 +1,163 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use crate::notification_rules::{NotificationRule, RULES_STATE};
+use crate::printer_events::PrinterEvent;
+use crate::{config_file, email, webhook};
+
+/// Name that rules use to send to the email settings.
+pub const EMAIL_CHANNEL: &str = "email";
+
+/// The channels notifications are sent to, stored under `"notifications"` in the config file.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct NotificationConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<webhook::WebhookConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<email::EmailConfig>,
+    /// When there are rules they alone decide where events go and the `events` of each channel are ignored.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<NotificationRule>,
+}
+
+impl NotificationConfig {
+    pub fn is_empty(&self) -> bool {
+        self.webhooks.is_empty() && self.email.is_none() && self.rules.is_empty()
+    }
+}
+
+/// Reads the notification settings and the email recipients of one printer from the config file.
+fn read_notification_config(printer_name: &str) -> Option<(NotificationConfig, Vec<String>)> {
+    match config_file::read_config_file() {
+        Ok(mut config) => {
+            let printer_recipients = config
+                .printers
+                .remove(printer_name)
+                .map(|printer| printer.email_recipients)
+                .unwrap_or_default();
+            Some((config.notifications, printer_recipients))
+        }
+        Err(e) => {
+            tracing::warn!("Unable to read notification config: {e}");
+            None
+        }
+    }
+}
+
+/// Sends an event to channels in the background, so a slow endpoint can not hold up polling.
+///
+/// # Arguments
+/// * `channels` - The names of the channels to use, or `None` to use every channel whose own
+///   `events` list includes the event.
+/// * `message` - The description of the event that is sent.
+fn dispatch(
+    config: NotificationConfig,
+    printer_recipients: &[String],
+    event: &PrinterEvent,
+    channels: Option<&[String]>,
+    message: String,
+) {
+    let kind = event.kind();
+    let now = OffsetDateTime::now_utc();
+    for hook in config.webhooks {
+        let wanted = match channels {
+            Some(channels) => hook
+                .name
+                .as_ref()
+                .is_some_and(|name| channels.contains(name)),
+            None => hook.wants(kind),
+        };
+        if !wanted {
+            continue;
+        }
+        let body = webhook::render(
+            hook.template
+                .as_deref()
+                .unwrap_or(webhook::DEFAULT_TEMPLATE),
+            event,
+            &message,
+            now,
+        );
+        tokio::spawn(async move {
+            if let Err(e) = webhook::deliver(&hook, kind, body).await {
+                tracing::warn!("Giving up on webhook: {e}");
+            }
+        });
+    }
+    if let Some(email) = config.email {
+        let wanted = match channels {
+            Some(channels) => channels.iter().any(|c| c == EMAIL_CHANNEL),
+            None => email.events.contains(&kind),
+        };
+        if wanted {
+            let recipients = email::recipients_for(&email, printer_recipients);
+            let event = event.clone();
+            tokio::spawn(
+                async move { email::send_event(&email, recipients, &event, &message).await },
+            );
+        }
+    }
+}
+
+/// Sends an event to the channels chosen by the notification rules, or to every channel that
+/// wants it if there are no rules.
+pub async fn notify(event: &PrinterEvent) {
+    let Some((config, printer_recipients)) = read_notification_config(event.printer_name()) else {
+        return;
+    };
+    if config.rules.is_empty() {
+        dispatch(config, &printer_recipients, event, None, event.message());
+        return;
+    }
+    let channels = RULES_STATE
+        .lock()
+        .await
+        .route(&config.rules, event, OffsetDateTime::now_utc());
+    if !channels.is_empty() {
+        dispatch(
+            config,
+            &printer_recipients,
+            event,
+            Some(&channels),
+            event.message(),
+        );
+    }
+}
+
+/// Marks the pending notifications of a printer as seen so they are not escalated.
+pub async fn acknowledge(printer_name: &str) {
+    tracing::info!("Notifications for {printer_name} acknowledged");
+    RULES_STATE.lock().await.acknowledge(printer_name);
+}
+
+/// Checks every 30 seconds for events that were not acknowledged in time and escalates them.
+pub async fn escalate_unacknowledged() {
+    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+        let rules = match config_file::read_config_file() {
+            Ok(config) => config.notifications.rules,
+            Err(_) => continue,
+        };
+        let due = RULES_STATE
+            .lock()
+            .await
+            .due_escalations(&rules, OffsetDateTime::now_utc());
+        for (channels, event) in due {
+            let Some((config, printer_recipients)) = read_notification_config(event.printer_name())
+            else {
+                continue;
+            };
+            let message = format!("Unacknowledged: {}", event.message());
+            dispatch(
+                config,
+                &printer_recipients,
+                &event,
+                Some(&channels),
+                message,
+            );
+        }
+    }
+}
@@ -1,139 +1,544 @@
 use std::net::IpAddr;
-use std::time::Duration;
+use std::{fmt, io};
 
-use salvo::websocket::Message;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use time::OffsetDateTime;
 
-use crate::{config_file, printer_interface, socket};
+use crate::auth::{Role, User};
+use crate::claims::{self, Claim, CLAIMS};
+use crate::config_file::PrinterConfig;
+use crate::confirmations::{ConfirmationRequest, CONFIRMATIONS};
+use crate::notification_rules::RULES_STATE;
+use crate::parse_printer_state::PrinterState;
+use crate::status_updates::STATUS_UPDATES;
+use crate::{
+    audit, cli, config_file, influxdb, metrics, mqtt, notifications, print_history, printer_events,
+    printer_interface, socket, subscriptions,
+};
 
-pub async fn update_user_page(user_id: usize) {
-    tracing::info!("Attempting to send user {user_id} initial printer details");
-    let _ = socket::send_message_to_user(user_id, Message::text(get_all_printer_json().await));
+/// Sends a user every printer they subscribed to, as of the last poll.
+pub async fn send_snapshot(user_id: usize) {
+    let updates = STATUS_UPDATES.lock().await;
+    // held while sending so no change falls between the snapshot and the next patch
+    socket::send_to_user(user_id, &updates.snapshot()).await;
 }
 
-#[derive(Serialize, Deserialize)]
-struct PrintersStatusJson {
-    printers: Vec<StatusJson>,
+/// Adds printers and groups to what a user is sent, and sends them the printers they get now.
+///
+/// # Errors
+/// `UnknownPrinter` or `UnknownGroup` if one of them is not configured, `Config` if the config
+/// file can not be read.
+pub async fn subscribe(
+    user_id: usize,
+    printers: Vec<String>,
+    groups: Vec<String>,
+) -> Result<(), CommandError> {
+    subscriptions::check_subscription(&read_printers()?, &printers, &groups)?;
+    socket::update_subscription(user_id, |subscription| {
+        subscription.subscribe(printers, groups)
+    })
+    .await;
+    send_snapshot(user_id).await;
+    Ok(())
+}
+
+/// Removes printers and groups from what a user is sent, or all of them if both are empty,
+/// and sends them the printers they get now.
+pub async fn unsubscribe(user_id: usize, printers: Vec<String>, groups: Vec<String>) {
+    socket::update_subscription(user_id, |subscription| {
+        subscription.unsubscribe(printers, groups)
+    })
+    .await;
+    send_snapshot(user_id).await;
+}
+
+/// The status of a printer, sent to the web page as a list of every printer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct StatusJson {
+    pub printer_name: String,
+    pub ip_address: String,
+    /// Files stored on the printer, empty when it is offline.
+    pub files_available: Vec<String>,
+    /// Percentage of the current file printed with two decimals, `Not Printing`, or why the
+    /// printer could not be reached.
+    pub progress: String,
+    pub paused: bool,
+    /// Who holds the printer, missing if nobody claimed it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claim: Option<Claim>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct StatusJson {
-    printer_name: String,
-    ip_address: String,
-    files_available: Vec<String>,
-    progress: String,
-    paused: bool,
+/// Why a command could not be carried out, shared by the websocket and the REST API.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    UnknownPrinter(String),
+    UnknownGroup(String),
+    UnknownUser(String),
+    UnknownToken(String),
+    /// Not logged in, or a wrong username or password.
+    Unauthorized,
+    /// The user's role does not allow the command.
+    Forbidden(String),
+    UnsupportedAction(String),
+    MissingField(&'static str),
+    /// A field of the command is out of range.
+    InvalidValue(String),
+    /// The name or address is already used by another printer.
+    Conflict(String),
+    /// Someone else holds the printer.
+    Claimed(String, Claim),
+    /// A destructive command has to be sent again with the confirmation token.
+    ConfirmationRequired(ConfirmationRequest),
+    /// The printer did not answer or refused the command.
+    Printer(String),
+    Config(String),
 }
 
-/// Refreshes all printer information every 10 seconds.
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownPrinter(name) => write!(f, "Unknown printer {name}"),
+            CommandError::UnknownGroup(name) => write!(f, "Unknown group {name}"),
+            CommandError::UnknownUser(name) => write!(f, "Unknown user {name}"),
+            CommandError::UnknownToken(id) => write!(f, "Unknown API token {id}"),
+            CommandError::Unauthorized => write!(f, "Log in with a valid username and password"),
+            CommandError::Forbidden(e) => write!(f, "{e}"),
+            CommandError::UnsupportedAction(action) => {
+                write!(f, "Action of {action} currently not supported")
+            }
+            CommandError::MissingField(field) => write!(f, "{field} is required"),
+            CommandError::InvalidValue(e) => write!(f, "{e}"),
+            CommandError::Conflict(e) => write!(f, "{e}"),
+            CommandError::Claimed(printer, claim) => write!(
+                f,
+                "{printer} is claimed by {} until {}",
+                claim.holder,
+                claim.expires()
+            ),
+            CommandError::ConfirmationRequired(request) => write!(
+                f,
+                "{}. Send the command again with its confirmation to go ahead",
+                request.description
+            ),
+            CommandError::Printer(e) => write!(f, "{e}"),
+            CommandError::Config(e) => write!(f, "Unable to update the config file: {e}"),
+        }
+    }
+}
+
+/// Changes to the settings of a printer, fields that are left out keep their current value.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, JsonSchema)]
+pub struct PrinterUpdate {
+    /// Renames the printer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_address: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// The make and model of the printer, an empty string clears it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_recipients: Option<Vec<String>>,
+}
+
+impl PrinterUpdate {
+    pub fn apply(self, mut printer: PrinterConfig) -> PrinterConfig {
+        if let Some(ip) = self.ip_address {
+            printer.ip = ip;
+        }
+        if let Some(port) = self.port {
+            printer.port = port;
+        }
+        if let Some(model) = self.model {
+            printer.model = Some(model).filter(|model| !model.is_empty());
+        }
+        if let Some(email_recipients) = self.email_recipients {
+            printer.email_recipients = email_recipients;
+        }
+        printer
+    }
+}
+
+/// Actions that are sent to a printer as gcode.
+pub const PRINT_ACTIONS: [&str; 4] = ["resume", "pause", "stop", "start"];
+
+/// Refreshes all printer information every `--poll-interval`, 10 seconds by default.
 pub async fn refresh_all_printer_info() {
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    let mut interval = tokio::time::interval(cli::settings().poll_interval);
     loop {
         interval.tick().await;
         send_refreshed_printers().await;
     }
 }
 
+/// Polls every printer and sends everyone what changed since the last poll.
 async fn send_refreshed_printers() {
-    let status: String = get_all_printer_json().await;
-    socket::send_message_to_all(Message::text(status)).await;
+    // held while polling and sending so overlapping refreshes can not apply an older poll over
+    // a newer one, and go out in sequence order
+    let mut updates = STATUS_UPDATES.lock().await;
+    let statuses = printer_statuses().await;
+    for message in updates.update(statuses) {
+        tracing::debug!(?message);
+        socket::send_message_to_all(&message).await;
+    }
 }
 
-/// Retrieves the status of all configured printers and returns a JSON string containing their information.
-///
-/// # Return value
-/// A JSON string representing the status of each configured printer, or an empty string if there are no printers configured.
-async fn get_all_printer_json() -> String {
-    let mut printers_json = PrintersStatusJson {
-        printers: Vec::new(),
-    };
-    for printer in config_file::read_config_file().unwrap().printers {
-        let (name, config) = printer;
-        tracing::info!("Retrieving status for {} at {}", name, config.ip);
-        match printer_interface::get_print_status(config.ip) {
-            Ok(s) => printers_json.printers.push(StatusJson {
-                printer_name: name,
-                ip_address: config.ip.to_string(),
-                files_available: printer_interface::get_printer_files(config.ip),
-                progress: if s.d.max_file_position != 0 {
-                    format!(
-                        "{:.2}",
-                        (s.d.current_file_position as f64 / s.d.max_file_position as f64) * 100.0
-                    )
-                } else {
-                    "Not Printing".to_string()
-                },
-                paused: s.d.paused,
-            }),
-            Err(s) => printers_json.printers.push(StatusJson {
-                printer_name: name,
-                ip_address: config.ip.to_string(),
-                files_available: Vec::new(),
-                progress: s,
-                paused: false,
-            }),
+/// Polls every configured printer, passing what changed on to events, metrics and the bridges.
+async fn printer_statuses() -> Vec<StatusJson> {
+    let mut printers = Vec::new();
+    let mut events = Vec::new();
+    let mut snapshots = Vec::new();
+    let config = match config_file::read_config_file() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::warn!("Unable to read config file: {e}");
+            return printers;
         }
+    };
+    socket::set_groups(config.groups);
+    for (name, config) in config.printers {
+        tracing::info!("Retrieving status for {} at {}", name, config.address());
+        let status = printer_interface::get_print_status(config.address());
+        let now = OffsetDateTime::now_utc();
+        let claim = CLAIMS.lock().unwrap().get(&name, now).cloned();
+        let mut tracker = printer_events::TRACKER.lock().await;
+        events.extend(tracker.observe(&name, status.as_ref().ok(), now));
+        snapshots.push(tracker.snapshot(&name, status.as_ref().ok(), now));
+        drop(tracker);
+        printers.push(status_json(name, &config, status, claim, true));
     }
-    let status = serde_json::to_string(&printers_json.printers).unwrap();
-    tracing::debug!(status);
-    status
+    printer_events::publish(events).await;
+    metrics::update_printer_metrics(&snapshots);
+    mqtt::publish_snapshots(&snapshots).await;
+    influxdb::write_snapshots(&snapshots);
+    printers
 }
 
-#[derive(Serialize, Deserialize)]
-struct Command {
-    ip_address: Option<IpAddr>,
-    action: String,
-    file: Option<String>,
-    name: Option<String>,
+/// The status of a printer from its answer to M4000, with the files on it if `files` is set and
+/// it answered. Listing the files takes another request, M20, so only ask for them when needed.
+pub fn status_json(
+    name: String,
+    config: &PrinterConfig,
+    status: Result<PrinterState, String>,
+    claim: Option<Claim>,
+    files: bool,
+) -> StatusJson {
+    match status {
+        Ok(s) => StatusJson {
+            printer_name: name,
+            ip_address: config.ip.to_string(),
+            files_available: if files {
+                printer_interface::get_printer_files(config.address())
+            } else {
+                Vec::new()
+            },
+            progress: if s.d.max_file_position != 0 {
+                format!(
+                    "{:.2}",
+                    (s.d.current_file_position as f64 / s.d.max_file_position as f64) * 100.0
+                )
+            } else {
+                "Not Printing".to_string()
+            },
+            paused: s.d.paused,
+            claim,
+        },
+        Err(s) => StatusJson {
+            printer_name: name,
+            ip_address: config.ip.to_string(),
+            files_available: Vec::new(),
+            progress: s,
+            paused: false,
+            claim,
+        },
+    }
+}
+
+fn read_printers() -> Result<config_file::Printers, CommandError> {
+    config_file::read_config_file().map_err(|e| CommandError::Config(e.to_string()))
 }
 
-/// Issues a command to a printer.
+/// Looks up the settings of a configured printer.
 ///
-/// # Arguments
-/// * `command`: The command to issue, as a JSON string.
+/// # Errors
+/// `UnknownPrinter` if there is no printer called `name`.
+pub fn printer_config(name: &str) -> Result<PrinterConfig, CommandError> {
+    read_printers()?
+        .printers
+        .remove(name)
+        .ok_or_else(|| CommandError::UnknownPrinter(name.to_string()))
+}
+
+/// Polls a single printer for its status. Unlike `printer_statuses` nothing is passed on, so
+/// events, metrics and the bridges only ever see the regular polls.
 ///
-/// # Returns
-/// A future that resolves once the command has been issued and any necessary actions have been taken.
+/// # Errors
+/// `UnknownPrinter` if there is no printer called `name`.
+pub fn printer_status(name: &str) -> Result<StatusJson, CommandError> {
+    let config = printer_config(name)?;
+    let status = printer_interface::get_print_status(config.address());
+    let claim = CLAIMS
+        .lock()
+        .unwrap()
+        .get(name, OffsetDateTime::now_utc())
+        .cloned();
+    Ok(status_json(name.to_string(), &config, status, claim, true))
+}
+
+/// Lists the files stored on a printer.
 ///
 /// # Errors
-/// If there is an error issuing the command, it will be returned as an `Err`.
-pub async fn issue_printer_command(command: &str) {
-    tracing::info!("issue_printer_command called with {}", command);
-    match serde_json::from_str::<Command>(&command) {
-        Ok(decoded) => match decoded.action.as_str() {
-            "add" => {
-                match config_file::append_config_file(
-                    decoded.name.unwrap(),
-                    config_file::PrinterConfig {
-                        ip: decoded.ip_address.unwrap(),
-                    },
-                ) {
-                    Ok(_) => send_refreshed_printers().await,
-                    Err(_) => tracing::warn!("Failed to add printer"),
-                }
-            }
-            "remove" => match config_file::remove_printer_from_config(decoded.name.unwrap()) {
-                Ok(_) => send_refreshed_printers().await,
-                Err(_) => tracing::warn!("Failed to remove printer"),
-            },
-            "resume" | "pause" | "stop" | "start" => {
-                match printer_interface::print_action(
-                    decoded.ip_address.unwrap(),
-                    decoded.action,
-                    decoded.file,
-                ) {
-                    Ok(_) => send_refreshed_printers().await,
-                    Err(action) => tracing::warn!(
-                        "Failed to {} printer {}",
-                        action,
-                        decoded.ip_address.unwrap()
-                    ),
+/// `UnknownPrinter` if there is no printer called `name`.
+pub fn printer_files(name: &str) -> Result<Vec<String>, CommandError> {
+    Ok(printer_interface::get_printer_files(
+        printer_config(name)?.address(),
+    ))
+}
+
+/// Adds a printer to the config file and sends everyone the new list.
+///
+/// # Errors
+/// `Forbidden` unless `user` is an admin for that printer, `Conflict` if the name is reserved or
+/// the name or address is already used by another printer, `Config` if the config file can not
+/// be written.
+pub async fn add_printer(user: &User, name: String, ip: IpAddr) -> Result<(), CommandError> {
+    let params = json!({ "ip_address": ip });
+    audit::audited(user, "add_printer", Some(&name), params, async {
+        user.require_printer(Role::Admin, &name)?;
+        config_file::check_printer_name(&name)
+            .map_err(|e| CommandError::Conflict(e.to_string()))?;
+        let printer = PrinterConfig::new(ip);
+        let printers = read_printers()?.printers;
+        if printers.contains_key(&name) {
+            return Err(CommandError::Conflict(format!(
+                "A printer called {name} already exists"
+            )));
+        }
+        if let Some((other, _)) = printers
+            .iter()
+            .find(|(_, other)| other.address() == printer.address())
+        {
+            return Err(CommandError::Conflict(format!(
+                "{other} already uses {}",
+                printer.address()
+            )));
+        }
+        config_file::append_config_file(name.clone(), printer)
+            .map_err(|e| CommandError::Config(e.to_string()))?;
+        send_refreshed_printers().await;
+        Ok(())
+    })
+    .await
+}
+
+/// Asks for a destructive command to be confirmed, unless `confirmation` is the token handed
+/// out for it. `consequence` is completed with what the printer is doing.
+///
+/// # Errors
+/// `ConfirmationRequired` with a new token if the command was not confirmed.
+async fn confirm_destructive(
+    user: &User,
+    action: &str,
+    name: &str,
+    confirmation: Option<&str>,
+    consequence: String,
+) -> Result<(), CommandError> {
+    let state = printer_events::TRACKER.lock().await.current_state(name);
+    CONFIRMATIONS.lock().unwrap().confirm(
+        user,
+        action,
+        name,
+        confirmation,
+        || format!("{consequence}, it is {state}"),
+        OffsetDateTime::now_utc(),
+    )
+}
+
+/// Removes a printer from the config file and sends everyone the new list. Only carried out
+/// with the `confirmation` handed out for it.
+///
+/// # Errors
+/// `Forbidden` unless `user` is an admin for that printer, `UnknownPrinter` if there is no
+/// printer called `name`, `ConfirmationRequired` until it is confirmed, `Config` if the config
+/// file can not be written.
+pub async fn remove_printer(
+    user: &User,
+    name: String,
+    confirmation: Option<String>,
+) -> Result<(), CommandError> {
+    audit::audited(user, "remove_printer", Some(&name), Value::Null, async {
+        user.require_printer(Role::Admin, &name)?;
+        printer_config(&name)?;
+        let consequence = format!("Removing {name} deletes its settings");
+        confirm_destructive(
+            user,
+            "remove_printer",
+            &name,
+            confirmation.as_deref(),
+            consequence,
+        )
+        .await?;
+        config_file::remove_printer_from_config(name.clone())
+            .map_err(|e| CommandError::Config(e.to_string()))?;
+        printer_events::TRACKER.lock().await.forget(&name);
+        CLAIMS.lock().unwrap().forget(&name);
+        send_refreshed_printers().await;
+        Ok(())
+    })
+    .await
+}
+
+/// Changes the settings of a printer in one write of the config file, keeping everything the
+/// monitor knows about it when it is renamed, and sends everyone the new list.
+///
+/// # Errors
+/// `Forbidden` unless `user` is an admin for that printer and its new name, `UnknownPrinter` if
+/// there is no printer called `name`, `Conflict` if the new name is reserved or it or the address
+/// is used by another printer, `Config` if the config file can not be written.
+pub async fn update_printer(
+    user: &User,
+    name: &str,
+    update: PrinterUpdate,
+) -> Result<(), CommandError> {
+    let params = serde_json::to_value(&update).unwrap_or_default();
+    audit::audited(user, "update_printer", Some(name), params, async {
+        user.require_printer(Role::Admin, name)?;
+        let current = printer_config(name)?;
+        let new_name = update.new_name.clone().unwrap_or_else(|| name.to_string());
+        if new_name.trim().is_empty() {
+            return Err(CommandError::MissingField("new_name"));
+        }
+        // a caller limited to some printers must not rename one out of their reach
+        user.require_printer(Role::Admin, &new_name)?;
+        config_file::update_printer_in_config(name, new_name.clone(), update.apply(current))
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => CommandError::UnknownPrinter(name.to_string()),
+                io::ErrorKind::AlreadyExists | io::ErrorKind::InvalidInput => {
+                    CommandError::Conflict(e.to_string())
                 }
+                _ => CommandError::Config(e.to_string()),
+            })?;
+        if new_name != name {
+            printer_events::TRACKER.lock().await.rename(name, &new_name);
+            CLAIMS.lock().unwrap().rename(name, &new_name);
+            RULES_STATE.lock().await.rename(name, &new_name);
+            socket::rename_printer(name, &new_name).await;
+            if let Err(e) = print_history::rename_printer(name, &new_name) {
+                tracing::error!("Unable to move the print history of {name} to {new_name}: {e}");
             }
-            _ => tracing::warn!("Action of {} currently not supported.", decoded.action),
-        },
-        Err(_) => {
-            tracing::warn!("Unable to deserialize websocket message {:?}", &command);
         }
-    }
+        // the metrics and the MQTT topics are replaced with the new name by this poll
+        send_refreshed_printers().await;
+        Ok(())
+    })
+    .await
+}
+
+/// Marks the pending notifications of a printer as seen.
+///
+/// # Errors
+/// `Forbidden` unless `user` is an operator or admin for that printer.
+pub async fn acknowledge(user: &User, name: &str) -> Result<(), CommandError> {
+    audit::audited(user, "acknowledge", Some(name), Value::Null, async {
+        user.require_printer(Role::Operator, name)?;
+        notifications::acknowledge(name).await;
+        Ok(())
+    })
+    .await
+}
+
+/// Sends one of the `PRINT_ACTIONS` to a printer and sends everyone the result.
+///
+/// # Arguments
+/// * `file` - The file to print, required by `start`.
+/// * `confirmation` - The token handed out for a `stop`, which is only carried out with it.
+///
+/// # Errors
+/// `Forbidden` unless `user` is an operator or admin for that printer, `UnsupportedAction` or `MissingField` for
+/// a bad request, `UnknownPrinter` if there is no printer called `name`, `Claimed` if someone
+/// else holds it, `ConfirmationRequired` for a `stop` that was not confirmed, and `Printer` if
+/// the printer did not accept the command.
+pub async fn run_print_action(
+    user: &User,
+    name: &str,
+    action: &str,
+    file: Option<String>,
+    confirmation: Option<String>,
+) -> Result<(), CommandError> {
+    let params = file
+        .as_ref()
+        .map_or(Value::Null, |file| json!({ "file": file }));
+    audit::audited(user, action, Some(name), params, async {
+        user.require_printer(Role::Operator, name)?;
+        if !PRINT_ACTIONS.contains(&action) {
+            return Err(CommandError::UnsupportedAction(action.to_string()));
+        }
+        if action == "start" && file.is_none() {
+            return Err(CommandError::MissingField("file"));
+        }
+        CLAIMS
+            .lock()
+            .unwrap()
+            .check(name, user, OffsetDateTime::now_utc())?;
+        let addr = printer_config(name)?.address();
+        if action == "stop" {
+            let consequence = format!("Stopping {name} ends its print");
+            confirm_destructive(user, action, name, confirmation.as_deref(), consequence).await?;
+        }
+        printer_interface::print_action(addr, action.to_string(), file.clone())
+            .map_err(CommandError::Printer)?;
+        printer_events::TRACKER
+            .lock()
+            .await
+            .note_command(name, action, file.as_deref());
+        send_refreshed_printers().await;
+        Ok(())
+    })
+    .await
+}
+
+/// Claims a printer for `user` for `minutes`, or renews their claim, and sends everyone the
+/// new holder.
+///
+/// # Errors
+/// `Forbidden` unless `user` is an operator or admin for that printer, `InvalidValue` for a
+/// length out of range, `UnknownPrinter` if there is no printer called `name`, `Claimed` if
+/// someone else holds it.
+pub async fn claim_printer(
+    user: &User,
+    name: &str,
+    minutes: Option<u32>,
+) -> Result<(), CommandError> {
+    let params = minutes.map_or(Value::Null, |minutes| json!({ "minutes": minutes }));
+    audit::audited(user, "claim", Some(name), params, async {
+        user.require_printer(Role::Operator, name)?;
+        let now = OffsetDateTime::now_utc();
+        let expires_at = claims::claim_expiry(minutes, now)?;
+        printer_config(name)?;
+        CLAIMS.lock().unwrap().claim(name, user, expires_at, now)?;
+        send_refreshed_printers().await;
+        Ok(())
+    })
+    .await
+}
+
+/// Releases the claim on a printer and tells everyone. Admins can break the claims of others.
+///
+/// # Errors
+/// `Forbidden` unless `user` holds the claim or is an admin for that printer.
+pub async fn release_printer(user: &User, name: &str) -> Result<(), CommandError> {
+    audit::audited(user, "release", Some(name), Value::Null, async {
+        user.require_printer(Role::Operator, name)?;
+        CLAIMS
+            .lock()
+            .unwrap()
+            .release(name, user, OffsetDateTime::now_utc())?;
+        send_refreshed_printers().await;
+        Ok(())
+    })
+    .await
 }
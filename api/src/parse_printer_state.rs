@@ -2,7 +2,7 @@
 Struct for parsing and containing a pair of u8 passed as a string like "0/100".
 Normally correlates to a (current_value, target/max_value)
 */
-#[derive(Debug, Default, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Pair {
     pub current: u16,
     pub target: u16,
@@ -28,7 +28,7 @@ impl std::str::FromStr for Pair {
 Struct for parsing and containing a triple of (u64, u64, bool) passed as a string like "0/100/1".
 This will normally correlate to (current_file_position, max_file_position, paused)
 */
-#[derive(Debug, Default, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct Triple {
     pub current_file_position: u64,
     pub max_file_position: u64,
@@ -83,7 +83,7 @@ impl std::str::FromStr for Triple {
 /// };
 /// assert_eq!(state.unwrap(), compared);
 /// ```
-#[derive(Debug, Default, PartialEq)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct PrinterState {
     pub b: Pair,
     pub e1: Pair,
@@ -125,6 +125,7 @@ impl std::str::FromStr for PrinterState {
 }
 
 #[test]
+#[allow(clippy::approx_constant)]
 fn test_parse_valid_state() {
     let status_string = "B:1/2 E1:3/4 X:1.5 Y:2.0 Z:3.14 D:50/100/0 T:10";
     let expected_state = PrinterState {
@@ -0,0 +1,37 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cli;
+
+/// Files the monitor writes, which used to sit in the working directory next to the frontend.
+const DATA_FILES: [&str; 4] = ["config.txt", "history.jsonl", "audit.jsonl", "tls"];
+
+/// Where the config file, print history, audit log and certificates are kept, `--data-dir`.
+/// Never served over HTTP.
+pub fn data_dir() -> &'static Path {
+    &cli::settings().data_dir
+}
+
+/// The path of `name` in the data directory.
+pub fn data_file(name: &str) -> PathBuf {
+    data_dir().join(name)
+}
+
+/// Creates the data directory and moves the data files an older version left in the working
+/// directory into it, unless it already has them.
+///
+/// # Errors
+/// Returns an error if the directory can not be created or a file can not be moved.
+pub fn prepare_data_dir() -> Result<(), io::Error> {
+    fs::create_dir_all(data_dir())?;
+    for name in DATA_FILES {
+        let old = Path::new(".").join(name);
+        let new = data_file(name);
+        if old.exists() && !new.exists() {
+            fs::rename(&old, &new)?;
+            tracing::info!("Moved {} to {}", old.display(), new.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,320 @@
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use salvo::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::paths;
+
+static HISTORY_FILE: Lazy<PathBuf> = Lazy::new(|| paths::data_file("history.jsonl"));
+
+/// Held while the history file is written, so a rename never drops a job appended meanwhile.
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// Columns of the CSV export, in order. Must match the fields of `JobRecord`.
+pub const CSV_COLUMNS: [&str; 7] = [
+    "printer_name",
+    "file",
+    "started_at",
+    "ended_at",
+    "duration_secs",
+    "outcome",
+    "progress",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobOutcome {
+    Finished,
+    Stopped,
+    Failed,
+}
+
+impl JobOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobOutcome::Finished => "finished",
+            JobOutcome::Stopped => "stopped",
+            JobOutcome::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for JobOutcome {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "finished" => Ok(JobOutcome::Finished),
+            "stopped" => Ok(JobOutcome::Stopped),
+            "failed" => Ok(JobOutcome::Failed),
+            _ => Err(format!("Unknown outcome {s}")),
+        }
+    }
+}
+
+/// A single print job as stored in the history file, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobRecord {
+    pub printer_name: String,
+    pub file: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub ended_at: OffsetDateTime,
+    pub duration_secs: i64,
+    pub outcome: JobOutcome,
+    pub progress: f64,
+}
+
+/// Appends a finished job to the history file, creating the file if needed.
+///
+/// # Errors
+/// Returns an error if the file can not be opened or written to.
+pub fn append_job(record: &JobRecord) -> Result<(), io::Error> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&*HISTORY_FILE)?;
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    file.flush()
+}
+
+/// A line of the history file with its job moved to `new_name` if it ran on `printer_name`.
+/// Lines that can not be parsed are kept as they are.
+fn renamed_line(line: &str, printer_name: &str, new_name: &str) -> String {
+    match serde_json::from_str::<JobRecord>(line) {
+        Ok(mut record) if record.printer_name == printer_name => {
+            record.printer_name = new_name.to_string();
+            serde_json::to_string(&record).unwrap_or_else(|_| line.to_string())
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// Moves the jobs of a printer to its new name, so its history stays with it.
+///
+/// # Errors
+/// Returns an error if the history file can not be read or replaced.
+pub fn rename_printer(printer_name: &str, new_name: &str) -> Result<(), io::Error> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    let data = match fs::read_to_string(&*HISTORY_FILE) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let renamed: String = data
+        .lines()
+        .map(|line| renamed_line(line, printer_name, new_name) + "\n")
+        .collect();
+    let tmp = paths::data_file("history.jsonl.tmp");
+    fs::write(&tmp, renamed)?;
+    fs::rename(&tmp, &*HISTORY_FILE)
+}
+
+/// Criteria for selecting jobs from the history, every unset field matches all jobs.
+#[derive(Debug, Default)]
+pub struct HistoryFilter {
+    pub printer_name: Option<String>,
+    pub from: Option<OffsetDateTime>,
+    pub to: Option<OffsetDateTime>,
+    pub outcome: Option<JobOutcome>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, record: &JobRecord) -> bool {
+        self.printer_name
+            .as_ref()
+            .is_none_or(|name| &record.printer_name == name)
+            && self.from.is_none_or(|from| record.started_at >= from)
+            && self.to.is_none_or(|to| record.started_at < to)
+            && self.outcome.is_none_or(|outcome| record.outcome == outcome)
+    }
+}
+
+/// Reads the history file lazily, yielding only the jobs accepted by `filter`.
+/// Lines that can not be parsed are logged and skipped.
+///
+/// # Errors
+/// Returns an error if the history file exists but can not be opened.
+pub fn read_jobs(filter: HistoryFilter) -> Result<impl Iterator<Item = JobRecord>, io::Error> {
+    let lines = match fs::File::open(&*HISTORY_FILE) {
+        Ok(file) => Some(BufReader::new(file).lines()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    Ok(lines
+        .into_iter()
+        .flatten()
+        .filter_map(
+            |line| match serde_json::from_str::<JobRecord>(&line.ok()?) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    tracing::warn!("Skipping unreadable history entry: {e}");
+                    None
+                }
+            },
+        )
+        .filter(move |record| filter.matches(record)))
+}
+
+/// Serializes a record as a single CSV row, without the header.
+pub fn to_csv_row(record: &JobRecord) -> String {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(Vec::new());
+    writer.serialize(record).unwrap();
+    String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
+
+fn parse_time(value: &str) -> Result<OffsetDateTime, StatusError> {
+    OffsetDateTime::parse(value, &Rfc3339).map_err(|_| {
+        StatusError::bad_request().brief(format!("{value} is not an RFC 3339 timestamp"))
+    })
+}
+
+/// Streams the job history as CSV (`format=csv`, the default) or newline delimited JSON (`format=ndjson`).
+///
+/// Query parameters `printer`, `from`, `to` (RFC 3339, `to` is exclusive) and `outcome`
+/// (`finished`, `stopped` or `failed`) narrow down the exported jobs.
+#[handler]
+pub async fn export_history(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+    let filter = HistoryFilter {
+        printer_name: req.query::<String>("printer"),
+        from: req
+            .query::<String>("from")
+            .as_deref()
+            .map(parse_time)
+            .transpose()?,
+        to: req
+            .query::<String>("to")
+            .as_deref()
+            .map(parse_time)
+            .transpose()?,
+        outcome: req
+            .query::<String>("outcome")
+            .map(|o| o.parse())
+            .transpose()
+            .map_err(|e: String| StatusError::bad_request().brief(e))?,
+    };
+    let format = req.query::<String>("format").unwrap_or("csv".to_string());
+    let jobs = read_jobs(filter).map_err(|e| {
+        tracing::warn!("Unable to read history: {e}");
+        StatusError::internal_server_error()
+    })?;
+    let (content_type, rows): (_, Box<dyn Iterator<Item = String> + Send>) = match format.as_str() {
+        "csv" => (
+            "text/csv; charset=utf-8",
+            Box::new(
+                std::iter::once(CSV_COLUMNS.join(",") + "\n").chain(jobs.map(|r| to_csv_row(&r))),
+            ),
+        ),
+        "ndjson" => (
+            "application/x-ndjson",
+            Box::new(jobs.map(|r| serde_json::to_string(&r).unwrap() + "\n")),
+        ),
+        _ => return Err(StatusError::bad_request().brief(format!("Unsupported format {format}"))),
+    };
+    let extension = if format == "csv" { "csv" } else { "ndjson" };
+    let _ = res.add_header(CONTENT_TYPE, content_type, true);
+    let _ = res.add_header(
+        CONTENT_DISPOSITION,
+        format!("attachment; filename=\"print_history.{extension}\""),
+        true,
+    );
+    res.stream(futures_util::stream::iter(
+        rows.map(Ok::<_, std::convert::Infallible>),
+    ));
+    Ok(())
+}
+
+#[cfg(test)]
+fn sample_record(printer_name: &str, outcome: JobOutcome) -> JobRecord {
+    JobRecord {
+        printer_name: printer_name.to_string(),
+        file: "part, \"v2\".ctb".to_string(),
+        started_at: OffsetDateTime::parse("2024-01-02T03:04:05Z", &Rfc3339).unwrap(),
+        ended_at: OffsetDateTime::parse("2024-01-02T04:04:05Z", &Rfc3339).unwrap(),
+        duration_secs: 3600,
+        outcome,
+        progress: 100.0,
+    }
+}
+
+#[test]
+fn test_csv_columns_match_record() {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer
+        .serialize(sample_record("printer1", JobOutcome::Finished))
+        .unwrap();
+    let data = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    let header = data.lines().next().unwrap();
+    assert_eq!(header, CSV_COLUMNS.join(","));
+    assert_eq!(
+        to_csv_row(&sample_record("printer1", JobOutcome::Finished)),
+        "printer1,\"part, \"\"v2\"\".ctb\",2024-01-02T03:04:05Z,2024-01-02T04:04:05Z,3600,finished,100.0\n"
+    );
+}
+
+#[test]
+fn test_history_filter() {
+    let record = sample_record("printer1", JobOutcome::Stopped);
+    assert!(HistoryFilter::default().matches(&record));
+    assert!(HistoryFilter {
+        printer_name: Some("printer1".to_string()),
+        outcome: Some(JobOutcome::Stopped),
+        from: Some(record.started_at),
+        to: Some(record.ended_at),
+    }
+    .matches(&record));
+    assert!(!HistoryFilter {
+        printer_name: Some("printer2".to_string()),
+        ..Default::default()
+    }
+    .matches(&record));
+    assert!(!HistoryFilter {
+        outcome: Some(JobOutcome::Finished),
+        ..Default::default()
+    }
+    .matches(&record));
+    assert!(!HistoryFilter {
+        to: Some(record.started_at),
+        ..Default::default()
+    }
+    .matches(&record));
+}
+
+#[test]
+fn test_history_survives_rename() {
+    let lines = [
+        serde_json::to_string(&sample_record("printer1", JobOutcome::Finished)).unwrap(),
+        serde_json::to_string(&sample_record("printer2", JobOutcome::Failed)).unwrap(),
+        "not a job".to_string(),
+    ];
+    let renamed: Vec<String> = lines
+        .iter()
+        .map(|line| renamed_line(line, "printer1", "printer3"))
+        .collect();
+    let filter = HistoryFilter {
+        printer_name: Some("printer3".to_string()),
+        ..Default::default()
+    };
+    let jobs: Vec<JobRecord> = renamed
+        .iter()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .filter(|record| filter.matches(record))
+        .collect();
+    assert_eq!(jobs, vec![sample_record("printer3", JobOutcome::Finished)]);
+    assert_eq!(renamed[1], lines[1]);
+    assert_eq!(renamed[2], "not a job");
+}
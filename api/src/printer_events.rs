@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+
+use crate::parse_printer_state::PrinterState;
+use crate::print_history::{self, JobOutcome, JobRecord};
+use crate::ws_protocol::ServerMessage;
+use crate::{influxdb, notifications, socket};
+
+/// A job that has ended on or above this progress percentage is counted as finished.
+const FINISHED_PROGRESS: f64 = 99.0;
+
+/// A running job whose file position has not moved for this long is reported as stalled.
+const STALL_AFTER: time::Duration = time::Duration::minutes(10);
+
+/// Events derived from the difference between two polls of the same printer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PrinterEvent {
+    JobStarted { printer_name: String, file: String },
+    JobEnded(JobRecord),
+    Paused { printer_name: String },
+    Resumed { printer_name: String },
+    Offline { printer_name: String },
+    Online { printer_name: String },
+    Stalled { printer_name: String, progress: f64 },
+}
+
+/// The type of a `PrinterEvent`, as used in the config file to select events.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    JobStarted,
+    Finished,
+    Stopped,
+    Failed,
+    Paused,
+    Resumed,
+    Offline,
+    Online,
+    Stalled,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::JobStarted => "job_started",
+            EventKind::Finished => "finished",
+            EventKind::Stopped => "stopped",
+            EventKind::Failed => "failed",
+            EventKind::Paused => "paused",
+            EventKind::Resumed => "resumed",
+            EventKind::Offline => "offline",
+            EventKind::Online => "online",
+            EventKind::Stalled => "stalled",
+        }
+    }
+}
+
+impl PrinterEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            PrinterEvent::JobStarted { .. } => EventKind::JobStarted,
+            PrinterEvent::JobEnded(record) => match record.outcome {
+                JobOutcome::Finished => EventKind::Finished,
+                JobOutcome::Stopped => EventKind::Stopped,
+                JobOutcome::Failed => EventKind::Failed,
+            },
+            PrinterEvent::Paused { .. } => EventKind::Paused,
+            PrinterEvent::Resumed { .. } => EventKind::Resumed,
+            PrinterEvent::Offline { .. } => EventKind::Offline,
+            PrinterEvent::Online { .. } => EventKind::Online,
+            PrinterEvent::Stalled { .. } => EventKind::Stalled,
+        }
+    }
+
+    pub fn printer_name(&self) -> &str {
+        match self {
+            PrinterEvent::JobStarted { printer_name, .. }
+            | PrinterEvent::Paused { printer_name }
+            | PrinterEvent::Resumed { printer_name }
+            | PrinterEvent::Offline { printer_name }
+            | PrinterEvent::Online { printer_name }
+            | PrinterEvent::Stalled { printer_name, .. } => printer_name,
+            PrinterEvent::JobEnded(record) => &record.printer_name,
+        }
+    }
+
+    /// Moves the event to the new name of its printer.
+    pub fn rename(&mut self, new_name: &str) {
+        match self {
+            PrinterEvent::JobStarted { printer_name, .. }
+            | PrinterEvent::Paused { printer_name }
+            | PrinterEvent::Resumed { printer_name }
+            | PrinterEvent::Offline { printer_name }
+            | PrinterEvent::Online { printer_name }
+            | PrinterEvent::Stalled { printer_name, .. } => *printer_name = new_name.to_string(),
+            PrinterEvent::JobEnded(record) => record.printer_name = new_name.to_string(),
+        }
+    }
+
+    /// The file of the job the event belongs to, empty if it is not known or not related to a job.
+    pub fn file(&self) -> &str {
+        match self {
+            PrinterEvent::JobStarted { file, .. } => file,
+            PrinterEvent::JobEnded(record) => &record.file,
+            _ => "",
+        }
+    }
+
+    /// A short human readable description of the event.
+    pub fn message(&self) -> String {
+        let name = self.printer_name();
+        match self {
+            PrinterEvent::JobStarted { file, .. } => format!("{name} started printing {file}"),
+            PrinterEvent::JobEnded(record) => format!(
+                "{name} {} {} after {} minutes at {:.2}%",
+                record.outcome.as_str(),
+                record.file,
+                record.duration_secs / 60,
+                record.progress
+            ),
+            PrinterEvent::Paused { .. } => format!("{name} was paused"),
+            PrinterEvent::Resumed { .. } => format!("{name} resumed printing"),
+            PrinterEvent::Offline { .. } => format!("{name} went offline"),
+            PrinterEvent::Online { .. } => format!("{name} is back online"),
+            PrinterEvent::Stalled { progress, .. } => format!(
+                "{name} has not made progress for {} minutes at {progress:.2}%",
+                STALL_AFTER.whole_minutes()
+            ),
+        }
+    }
+}
+
+/// What a printer is doing, as far as the monitor can tell.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Offline,
+    Idle,
+    Printing,
+    Paused,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Offline => "offline",
+            Phase::Idle => "idle",
+            Phase::Printing => "printing",
+            Phase::Paused => "paused",
+        }
+    }
+}
+
+/// Everything known about a printer after a poll, for the parts of the monitor that export it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrinterSnapshot {
+    pub printer_name: String,
+    pub phase: Phase,
+    /// The file being printed, empty if idle or not started from the monitor.
+    pub file: String,
+    /// Percentage of the file printed, 0 when not printing.
+    pub progress: f64,
+    pub elapsed_secs: i64,
+    /// Estimated seconds left, extrapolated from the time it took to reach the current progress.
+    pub eta_secs: Option<i64>,
+    /// The raw state reported by the printer, `None` while it is offline.
+    pub state: Option<PrinterState>,
+}
+
+#[derive(Debug)]
+struct ActiveJob {
+    file: String,
+    started_at: OffsetDateTime,
+    max_file_position: u64,
+    current_file_position: u64,
+    progress: f64,
+    paused: bool,
+    last_moved_at: OffsetDateTime,
+    stalled: bool,
+}
+
+#[derive(Debug, Default)]
+struct TrackedPrinter {
+    online: Option<bool>,
+    job: Option<ActiveJob>,
+    requested_file: Option<String>,
+    stop_requested: bool,
+}
+
+/// Keeps the last known job of every printer so that each poll can be turned into events.
+#[derive(Debug, Default)]
+pub struct EventTracker {
+    printers: HashMap<String, TrackedPrinter>,
+}
+
+pub static TRACKER: Lazy<Mutex<EventTracker>> = Lazy::new(|| Mutex::new(EventTracker::default()));
+
+impl EventTracker {
+    /// Records a command the printer accepted from the monitor so the next job transition can be
+    /// attributed. The printer only reports file positions, so the file name of a job is only
+    /// known if it was started from here.
+    pub fn note_command(&mut self, printer_name: &str, action: &str, file: Option<&str>) {
+        let printer = self.printers.entry(printer_name.to_string()).or_default();
+        match action {
+            "start" => {
+                printer.requested_file = file.map(str::to_string);
+                printer.stop_requested = false;
+            }
+            "stop" => printer.stop_requested = true,
+            _ => {}
+        }
+    }
+
+    /// Compares a new poll of a printer with the previous one.
+    ///
+    /// # Arguments
+    /// * `printer_name` - The name of the printer in the config file.
+    /// * `state` - The state reported by the printer, `None` if it could not be reached.
+    /// * `now` - The time of the poll.
+    ///
+    /// # Returns
+    /// The events caused by the change, in the order they happened.
+    pub fn observe(
+        &mut self,
+        printer_name: &str,
+        state: Option<&PrinterState>,
+        now: OffsetDateTime,
+    ) -> Vec<PrinterEvent> {
+        let mut events = Vec::new();
+        let name = printer_name.to_string();
+        let printer = self.printers.entry(name.clone()).or_default();
+        let online = state.is_some();
+        if printer
+            .online
+            .is_some_and(|was_online| was_online != online)
+        {
+            events.push(if online {
+                PrinterEvent::Online {
+                    printer_name: name.clone(),
+                }
+            } else {
+                PrinterEvent::Offline {
+                    printer_name: name.clone(),
+                }
+            });
+        }
+        printer.online = Some(online);
+        // an unreachable printer may well still be printing, so the job is kept until it answers again
+        let Some(state) = state else {
+            return events;
+        };
+        let printing = state.d.max_file_position != 0;
+
+        let job_changed = printer
+            .job
+            .as_ref()
+            .is_some_and(|job| !printing || job.max_file_position != state.d.max_file_position);
+        if job_changed {
+            let job = printer.job.take().unwrap();
+            let outcome = if printer.stop_requested {
+                JobOutcome::Stopped
+            } else if job.progress >= FINISHED_PROGRESS {
+                JobOutcome::Finished
+            } else {
+                JobOutcome::Failed
+            };
+            printer.stop_requested = false;
+            events.push(PrinterEvent::JobEnded(JobRecord {
+                printer_name: printer_name.to_string(),
+                file: job.file,
+                started_at: job.started_at,
+                ended_at: now,
+                duration_secs: (now - job.started_at).whole_seconds(),
+                outcome,
+                progress: job.progress,
+            }));
+        }
+
+        if printing {
+            let progress =
+                (state.d.current_file_position as f64 / state.d.max_file_position as f64) * 100.0;
+            match printer.job.as_mut() {
+                Some(job) => {
+                    job.progress = progress;
+                    if job.paused != state.d.paused {
+                        job.paused = state.d.paused;
+                        events.push(if job.paused {
+                            PrinterEvent::Paused {
+                                printer_name: name.clone(),
+                            }
+                        } else {
+                            PrinterEvent::Resumed {
+                                printer_name: name.clone(),
+                            }
+                        });
+                    }
+                    if job.current_file_position != state.d.current_file_position || job.paused {
+                        job.current_file_position = state.d.current_file_position;
+                        job.last_moved_at = now;
+                        job.stalled = false;
+                    } else if !job.stalled && now - job.last_moved_at >= STALL_AFTER {
+                        job.stalled = true;
+                        events.push(PrinterEvent::Stalled {
+                            printer_name: name.clone(),
+                            progress,
+                        });
+                    }
+                }
+                None => {
+                    let file = printer.requested_file.take().unwrap_or_default();
+                    printer.job = Some(ActiveJob {
+                        file: file.clone(),
+                        started_at: now,
+                        max_file_position: state.d.max_file_position,
+                        current_file_position: state.d.current_file_position,
+                        progress,
+                        paused: state.d.paused,
+                        last_moved_at: now,
+                        stalled: false,
+                    });
+                    events.push(PrinterEvent::JobStarted {
+                        printer_name: printer_name.to_string(),
+                        file,
+                    });
+                }
+            }
+        }
+        events
+    }
+
+    /// Describes what a printer is doing according to the last poll, for summaries.
+    pub fn current_state(&self, printer_name: &str) -> String {
+        let Some(printer) = self.printers.get(printer_name) else {
+            return "not polled yet".to_string();
+        };
+        match (&printer.online, &printer.job) {
+            (Some(false), _) => "offline".to_string(),
+            (_, None) => "idle".to_string(),
+            (_, Some(job)) => format!(
+                "{} {} at {:.2}%",
+                if job.paused { "paused on" } else { "printing" },
+                if job.file.is_empty() {
+                    "an unknown file"
+                } else {
+                    &job.file
+                },
+                job.progress
+            ),
+        }
+    }
+
+    /// Combines the last poll of a printer with what is known about its current job.
+    /// Call after `observe` so the job is up to date.
+    pub fn snapshot(
+        &self,
+        printer_name: &str,
+        state: Option<&PrinterState>,
+        now: OffsetDateTime,
+    ) -> PrinterSnapshot {
+        let job = self
+            .printers
+            .get(printer_name)
+            .and_then(|printer| printer.job.as_ref());
+        let phase = match (state, job) {
+            (None, _) => Phase::Offline,
+            (Some(_), None) => Phase::Idle,
+            (Some(_), Some(job)) if job.paused => Phase::Paused,
+            (Some(_), Some(_)) => Phase::Printing,
+        };
+        let (file, progress, elapsed_secs) = match job {
+            Some(job) => (
+                job.file.clone(),
+                job.progress,
+                (now - job.started_at).whole_seconds(),
+            ),
+            None => (String::new(), 0.0, 0),
+        };
+        PrinterSnapshot {
+            printer_name: printer_name.to_string(),
+            phase,
+            file,
+            progress,
+            elapsed_secs,
+            eta_secs: (progress > 0.0)
+                .then(|| (elapsed_secs as f64 * (100.0 - progress) / progress) as i64),
+            state: state.cloned(),
+        }
+    }
+
+    /// Drops everything known about a printer, used when it is removed from the config.
+    pub fn forget(&mut self, printer_name: &str) {
+        self.printers.remove(printer_name);
+    }
+
+    /// Moves everything known about a printer to its new name.
+    pub fn rename(&mut self, printer_name: &str, new_name: &str) {
+        if let Some(printer) = self.printers.remove(printer_name) {
+            self.printers.insert(new_name.to_string(), printer);
+        }
+    }
+}
+
+/// Passes events on to every part of the monitor that is interested in them.
+pub async fn publish(events: Vec<PrinterEvent>) {
+    influxdb::record_events(&events);
+    for event in events {
+        tracing::info!("Printer event {:?}", event);
+        if let PrinterEvent::JobEnded(record) = &event {
+            if let Err(e) = print_history::append_job(record) {
+                tracing::warn!("Failed to record job in history: {e}");
+            }
+        }
+        socket::send_message_to_all(&ServerMessage::from(&event)).await;
+        notifications::notify(&event).await;
+    }
+}
+
+#[cfg(test)]
+fn printing_state(current: u64, max: u64) -> PrinterState {
+    paused_state(current, max, false)
+}
+
+#[cfg(test)]
+fn paused_state(current: u64, max: u64, paused: bool) -> PrinterState {
+    PrinterState {
+        d: crate::parse_printer_state::Triple {
+            current_file_position: current,
+            max_file_position: max,
+            paused,
+        },
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_job_started_and_finished() {
+    let mut tracker = EventTracker::default();
+    let start = OffsetDateTime::UNIX_EPOCH;
+    let end = start + time::Duration::minutes(90);
+    tracker.note_command("printer1", "start", Some("part.ctb"));
+    let events = tracker.observe("printer1", Some(&printing_state(10, 1000)), start);
+    assert_eq!(
+        events,
+        vec![PrinterEvent::JobStarted {
+            printer_name: "printer1".to_string(),
+            file: "part.ctb".to_string(),
+        }]
+    );
+    assert!(tracker
+        .observe("printer1", Some(&printing_state(995, 1000)), start)
+        .is_empty());
+    // an unreachable printer does not end the job
+    assert_eq!(
+        tracker.observe("printer1", None, start),
+        vec![PrinterEvent::Offline {
+            printer_name: "printer1".to_string()
+        }]
+    );
+    let events = tracker.observe("printer1", Some(&printing_state(0, 0)), end);
+    assert_eq!(
+        events,
+        vec![
+            PrinterEvent::Online {
+                printer_name: "printer1".to_string()
+            },
+            PrinterEvent::JobEnded(JobRecord {
+                printer_name: "printer1".to_string(),
+                file: "part.ctb".to_string(),
+                started_at: start,
+                ended_at: end,
+                duration_secs: 5400,
+                outcome: JobOutcome::Finished,
+                progress: 99.5,
+            })
+        ]
+    );
+}
+
+#[test]
+fn test_job_stopped_and_failed() {
+    let mut tracker = EventTracker::default();
+    let now = OffsetDateTime::UNIX_EPOCH;
+    tracker.observe("printer1", Some(&printing_state(10, 1000)), now);
+    tracker.note_command("printer1", "stop", None);
+    let events = tracker.observe("printer1", Some(&printing_state(0, 0)), now);
+    assert!(matches!(&events[..], [PrinterEvent::JobEnded(r)] if r.outcome == JobOutcome::Stopped));
+    tracker.observe("printer1", Some(&printing_state(10, 1000)), now);
+    let events = tracker.observe("printer1", Some(&printing_state(0, 0)), now);
+    assert!(matches!(&events[..], [PrinterEvent::JobEnded(r)] if r.outcome == JobOutcome::Failed));
+}
+
+#[test]
+fn test_online_paused_and_stalled() {
+    let mut tracker = EventTracker::default();
+    let start = OffsetDateTime::UNIX_EPOCH;
+    let printer_name = "printer1".to_string();
+    assert!(tracker.observe("printer1", None, start).is_empty());
+    assert_eq!(
+        tracker.observe("printer1", Some(&printing_state(0, 0)), start),
+        vec![PrinterEvent::Online {
+            printer_name: printer_name.clone()
+        }]
+    );
+    tracker.observe("printer1", Some(&printing_state(10, 1000)), start);
+    assert_eq!(
+        tracker.observe("printer1", Some(&paused_state(10, 1000, true)), start),
+        vec![PrinterEvent::Paused {
+            printer_name: printer_name.clone()
+        }]
+    );
+    // a paused printer is not stalled
+    let later = start + STALL_AFTER * 2;
+    assert!(tracker
+        .observe("printer1", Some(&paused_state(10, 1000, true)), later)
+        .is_empty());
+    assert_eq!(
+        tracker.observe("printer1", Some(&printing_state(10, 1000)), later),
+        vec![PrinterEvent::Resumed {
+            printer_name: printer_name.clone()
+        }]
+    );
+    let stalled_at = later + STALL_AFTER;
+    assert_eq!(
+        tracker.observe("printer1", Some(&printing_state(10, 1000)), stalled_at),
+        vec![PrinterEvent::Stalled {
+            printer_name: printer_name.clone(),
+            progress: 1.0,
+        }]
+    );
+    // stalls are only reported once
+    assert!(tracker
+        .observe(
+            "printer1",
+            Some(&printing_state(10, 1000)),
+            stalled_at + STALL_AFTER
+        )
+        .is_empty());
+    assert_eq!(
+        tracker.observe("printer1", None, stalled_at),
+        vec![PrinterEvent::Offline { printer_name }]
+    );
+}
+
+#[test]
+fn test_snapshot() {
+    let mut tracker = EventTracker::default();
+    let start = OffsetDateTime::UNIX_EPOCH;
+    let now = start + time::Duration::minutes(30);
+    assert_eq!(
+        tracker.snapshot("printer1", None, now).phase,
+        Phase::Offline
+    );
+    tracker.observe("printer1", Some(&printing_state(0, 0)), start);
+    assert_eq!(
+        tracker
+            .snapshot("printer1", Some(&printing_state(0, 0)), start)
+            .phase,
+        Phase::Idle
+    );
+    tracker.note_command("printer1", "start", Some("part.ctb"));
+    tracker.observe("printer1", Some(&printing_state(10, 1000)), start);
+    tracker.observe("printer1", Some(&printing_state(250, 1000)), now);
+    let snapshot = tracker.snapshot("printer1", Some(&printing_state(250, 1000)), now);
+    assert_eq!(snapshot.phase, Phase::Printing);
+    assert_eq!(snapshot.file, "part.ctb");
+    assert_eq!(snapshot.progress, 25.0);
+    assert_eq!(snapshot.elapsed_secs, 1800);
+    assert_eq!(snapshot.eta_secs, Some(5400));
+}
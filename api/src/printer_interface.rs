@@ -1,9 +1,11 @@
+use crate::metrics;
 use crate::parse_printer_state::PrinterState;
 use std::collections::HashMap;
-use std::net::{IpAddr, UdpSocket};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
 use std::str;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 // Gcode | parameters      | return value                                         | description
 // ----- | --------------- | ---------------------------------------------------- | -----------
@@ -15,15 +17,15 @@ use std::time::Duration;
 // M4000 |                 | "ok B:0/0 X:0.000 Y:0.000 Z:-45.796 F:256/0 D:0/0/1" | get printer status
 // M6030 | {file_to_print} |                                                      | start selected file
 
-pub fn send_gcode(gcode: String, ip_addr: IpAddr) -> Vec<String> {
+pub fn send_gcode(gcode: String, addr: SocketAddr) -> Vec<String> {
     let mut output = Vec::new();
     let socket = UdpSocket::bind("0.0.0.0:0").expect("couldn't bind to address");
     socket
         .set_read_timeout(Some(Duration::new(2, 0)))
         .expect("set_read_timeout call failed");
-    socket
-        .connect(ip_addr.to_string() + ":3000")
-        .expect("connect function failed");
+    socket.connect(addr).expect("connect function failed");
+    let started = Instant::now();
+    let mut timed_out = false;
     match socket.send(gcode.as_bytes()) {
         Ok(_received) => {
             let mut buf = [0; 4096];
@@ -39,6 +41,7 @@ pub fn send_gcode(gcode: String, ip_addr: IpAddr) -> Vec<String> {
                     }
                     Err(e) => {
                         tracing::warn!("recv function failed: {e:?}");
+                        timed_out = matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
                         break;
                     }
                 }
@@ -49,10 +52,11 @@ pub fn send_gcode(gcode: String, ip_addr: IpAddr) -> Vec<String> {
         }
     }
     drop(socket);
+    metrics::record_udp_request(addr.ip(), &gcode, started.elapsed(), timed_out);
     output
 }
 
-pub fn get_print_status(ip_addr: IpAddr) -> Result<PrinterState, String> {
+pub fn get_print_status(addr: SocketAddr) -> Result<PrinterState, String> {
     // ok B:0/0 X:0.000 Y:0.000 Z:-45.796 F:256/0 D:0/0/1
     // Breakdown:
     // B: Heated Bed current temp / target temp
@@ -66,15 +70,15 @@ pub fn get_print_status(ip_addr: IpAddr) -> Result<PrinterState, String> {
     //     File Paused
     //         0: False
     //         1: True
-    let output = send_gcode("M4000".to_string(), ip_addr);
+    let output = send_gcode("M4000".to_string(), addr);
     if output.is_empty() {
         return Err("Unable to connect".to_string());
     }
     Ok(PrinterState::from_str(&output[0]).unwrap())
 }
 
-pub fn get_printer_files(ip_addr: IpAddr) -> Vec<String> {
-    let mut output = send_gcode("M20".to_string(), ip_addr);
+pub fn get_printer_files(addr: SocketAddr) -> Vec<String> {
+    let mut output = send_gcode("M20".to_string(), addr);
     if !output.is_empty() {
         // removing last 2 elements of vec that are ["End file list", "ok L:14"]
         output.truncate(output.len().saturating_sub(2));
@@ -90,7 +94,7 @@ pub fn get_printer_files(ip_addr: IpAddr) -> Vec<String> {
 }
 
 pub fn print_action(
-    ip_addr: IpAddr,
+    addr: SocketAddr,
     action: String,
     file_name: Option<String>,
 ) -> Result<String, String> {
@@ -107,8 +111,8 @@ pub fn print_action(
     ]);
     match gcode_map.get(&*action) {
         Some(gcode) => {
-            tracing::info!("Calling {ip_addr} with {gcode}");
-            let output = send_gcode(gcode.to_string(), ip_addr);
+            tracing::info!("Calling {addr} with {gcode}");
+            let output = send_gcode(gcode.to_string(), addr);
             if !output.is_empty() {
                 tracing::info!("{}", output[0].clone());
                 return Ok(output[0].clone());
@@ -119,6 +123,6 @@ pub fn print_action(
             return Ok(format!("Action of {action} not supported").to_string());
         }
     }
-    tracing::warn!("Failed to {action} printer at {ip_addr}");
-    Err(format!("Failed to {action} printer at {ip_addr}"))
+    tracing::warn!("Failed to {action} printer at {addr}");
+    Err(format!("Failed to {action} printer at {addr}"))
 }
@@ -0,0 +1,461 @@
+use salvo::async_trait;
+use salvo::http::uri::Scheme;
+use salvo::prelude::*;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::api_docs;
+use crate::api_tokens::{self, ApiTokenInfo, CreatedApiToken, NewApiToken};
+use crate::audit::{self, AuditFilter, AuditRecord};
+use crate::auth::{self, NewUser, User, UserUpdate};
+use crate::confirmations::ConfirmationRequest;
+use crate::page_interface::{self, CommandError, PrinterUpdate, StatusJson};
+use crate::status_updates::STATUS_UPDATES;
+
+/// Every error of the API is answered with a status code and a body of `{"error": "..."}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+    confirmation: Option<ConfirmationRequest>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            message: message.into(),
+            confirmation: None,
+        }
+    }
+}
+
+impl From<CommandError> for ApiError {
+    fn from(e: CommandError) -> Self {
+        let status = match e {
+            CommandError::UnknownPrinter(_)
+            | CommandError::UnknownGroup(_)
+            | CommandError::UnknownUser(_)
+            | CommandError::UnknownToken(_) => StatusCode::NOT_FOUND,
+            CommandError::Unauthorized => StatusCode::UNAUTHORIZED,
+            CommandError::Forbidden(_) => StatusCode::FORBIDDEN,
+            CommandError::UnsupportedAction(_)
+            | CommandError::MissingField(_)
+            | CommandError::InvalidValue(_) => StatusCode::BAD_REQUEST,
+            CommandError::Conflict(_) | CommandError::Claimed(..) => StatusCode::CONFLICT,
+            CommandError::Printer(_) => StatusCode::BAD_GATEWAY,
+            CommandError::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            CommandError::ConfirmationRequired(_) => StatusCode::PRECONDITION_REQUIRED,
+        };
+        let mut error = ApiError::new(status, e.to_string());
+        if let CommandError::ConfirmationRequired(request) = e {
+            error.confirmation = Some(request);
+        }
+        error
+    }
+}
+
+/// The body of every error response.
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorBody {
+    pub error: String,
+    /// Only for `428 Precondition Required`, send the request again with the `confirmation`.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub confirmation: Option<ConfirmationRequest>,
+}
+
+#[async_trait]
+impl Writer for ApiError {
+    async fn write(self, _req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+        res.status_code(self.status);
+        res.render(Json(ErrorBody {
+            error: self.message,
+            confirmation: self.confirmation,
+        }));
+    }
+}
+
+/// The optional body of an action, only `start` needs a file.
+#[derive(Deserialize, Default, JsonSchema)]
+pub struct ActionBody {
+    /// The file to print.
+    pub file: Option<String>,
+    /// The token from the `428` answer to a `stop`, which is only carried out with it.
+    pub confirmation: Option<String>,
+}
+
+fn printer_name(req: &Request) -> Result<String, ApiError> {
+    req.param::<String>("name")
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Missing printer name"))
+}
+
+/// Reads a JSON body whatever its content type, an empty body gives the default value.
+async fn parse_body<T: DeserializeOwned + Default>(req: &mut Request) -> Result<T, ApiError> {
+    match req.payload().await {
+        Ok(payload) if !payload.is_empty() => serde_json::from_slice(payload)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid body: {e}"))),
+        _ => Ok(T::default()),
+    }
+}
+
+/// The optional body of `POST /api/v1/printers/{name}/claim`.
+#[derive(Deserialize, Default, JsonSchema)]
+pub struct ClaimBody {
+    /// How long to hold the printer, 30 minutes if left out.
+    pub minutes: Option<u32>,
+}
+
+/// The credentials of `POST /api/v1/login`.
+#[derive(Deserialize, Default, JsonSchema)]
+pub struct LoginBody {
+    pub username: String,
+    pub password: String,
+}
+
+/// The routes of version 1 of the API, to be mounted at the root. Everything but logging in and
+/// the API documents needs a session.
+pub fn router() -> Router {
+    Router::with_path("api/v1")
+        .push(Router::with_path("login").post(login))
+        .push(Router::with_path("logout").post(logout))
+        .push(
+            Router::new()
+                .hoop(auth::require_login)
+                .push(Router::with_path("me").get(me))
+                .push(Router::with_path("audit").get(query_audit))
+                .push(
+                    Router::with_path("printers").get(list_printers).push(
+                        Router::with_path("<name>")
+                            .get(get_printer)
+                            .patch(update_printer)
+                            .push(Router::with_path("files").get(printer_files))
+                            .push(
+                                Router::with_path("claim")
+                                    .post(claim_printer)
+                                    .delete(release_printer),
+                            )
+                            .push(Router::with_path("actions/<action>").post(printer_action)),
+                    ),
+                )
+                .push(
+                    Router::with_path("tokens")
+                        .get(list_tokens)
+                        .post(create_token)
+                        .push(Router::with_path("<id>").delete(revoke_token)),
+                )
+                .push(
+                    Router::with_path("users")
+                        .get(list_users)
+                        .post(add_user)
+                        .push(
+                            Router::with_path("<username>")
+                                .patch(update_user)
+                                .delete(remove_user),
+                        ),
+                ),
+        )
+        .push(Router::with_path("openapi.json").get(api_docs::openapi_json))
+        .push(Router::with_path("schemas/<name>").get(api_docs::json_schema))
+        .push(Router::with_path("<**rest>").goal(not_found))
+}
+
+/// `POST /api/v1/login` with a `LoginBody`, sets the session cookie and answers with the `User`.
+#[handler]
+pub async fn login(req: &mut Request, res: &mut Response) -> Result<Json<User>, ApiError> {
+    let body = parse_body::<LoginBody>(req).await?;
+    let (user, token) = auth::login(&body.username, &body.password)?;
+    res.add_cookie(auth::session_cookie(&token, req.scheme() == &Scheme::HTTPS));
+    Ok(Json(user))
+}
+
+/// `POST /api/v1/logout`, ends the session and clears its cookie.
+#[handler]
+pub async fn logout(req: &mut Request, res: &mut Response) {
+    if let Some(cookie) = req.cookie(auth::SESSION_COOKIE) {
+        auth::SESSIONS.lock().unwrap().end(cookie.value());
+    }
+    res.add_cookie(auth::session_cookie("", req.scheme() == &Scheme::HTTPS));
+    res.status_code(StatusCode::NO_CONTENT);
+}
+
+/// `GET /api/v1/me`, the logged in user.
+#[handler]
+pub async fn me(depot: &mut Depot) -> Result<Json<User>, ApiError> {
+    Ok(Json(auth::depot_user(depot)?))
+}
+
+/// `GET /api/v1/users`, every account, for admins.
+#[handler]
+pub async fn list_users(depot: &mut Depot) -> Result<Json<Vec<User>>, ApiError> {
+    Ok(Json(auth::list_users(&auth::depot_user(depot)?)?))
+}
+
+/// `POST /api/v1/users` with a `NewUser`, answers `201 Created`.
+#[handler]
+pub async fn add_user(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let new_user = parse_body::<NewUser>(req).await?;
+    auth::add_user(&auth::depot_user(depot)?, new_user)?;
+    res.status_code(StatusCode::CREATED);
+    Ok(())
+}
+
+/// `PATCH /api/v1/users/{username}` with a `UserUpdate`.
+#[handler]
+pub async fn update_user(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let username = req.param::<String>("username").unwrap_or_default();
+    let update = parse_body::<UserUpdate>(req).await?;
+    auth::update_user(&auth::depot_user(depot)?, &username, update)?;
+    res.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+/// `DELETE /api/v1/users/{username}`
+#[handler]
+pub async fn remove_user(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let username = req.param::<String>("username").unwrap_or_default();
+    auth::remove_user(&auth::depot_user(depot)?, &username)?;
+    res.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+/// `GET /api/v1/tokens`, every API token without its secret, for admins.
+#[handler]
+pub async fn list_tokens(depot: &mut Depot) -> Result<Json<Vec<ApiTokenInfo>>, ApiError> {
+    Ok(Json(api_tokens::list_tokens(&auth::depot_user(depot)?)?))
+}
+
+/// `POST /api/v1/tokens` with a `NewApiToken`, answers `201 Created` with the token, which is
+/// not shown again.
+#[handler]
+pub async fn create_token(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<Json<CreatedApiToken>, ApiError> {
+    let new_token = parse_body::<NewApiToken>(req).await?;
+    let created = api_tokens::create_token(&auth::depot_user(depot)?, new_token)?;
+    res.status_code(StatusCode::CREATED);
+    Ok(Json(created))
+}
+
+/// `DELETE /api/v1/tokens/{id}`
+#[handler]
+pub async fn revoke_token(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let id = req.param::<String>("id").unwrap_or_default();
+    api_tokens::revoke_token(&auth::depot_user(depot)?, &id)?;
+    res.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+/// `GET /api/v1/audit`, the newest audit records first, for admins. Query parameters `actor`,
+/// `printer`, `action`, `outcome`, `from` and `to` (RFC 3339, `to` is exclusive) narrow them
+/// down and `limit` caps their number.
+#[handler]
+pub async fn query_audit(
+    req: &mut Request,
+    depot: &mut Depot,
+) -> Result<Json<Vec<AuditRecord>>, ApiError> {
+    let limit = req
+        .query::<usize>("limit")
+        .unwrap_or(audit::DEFAULT_QUERY_LIMIT);
+    let filter = req
+        .parse_queries::<AuditFilter>()
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid query: {e}")))?;
+    Ok(Json(audit::query(
+        &auth::depot_user(depot)?,
+        &filter,
+        limit,
+    )?))
+}
+
+/// `GET /api/v1/printers`, the status of every printer as of the last poll. Scripts calling it
+/// never poll the printers themselves, so they add no samples or events.
+#[handler]
+pub async fn list_printers() -> Json<Vec<StatusJson>> {
+    Json(STATUS_UPDATES.lock().await.printers())
+}
+
+/// `GET /api/v1/printers/{name}`, the current status of one printer.
+#[handler]
+pub async fn get_printer(req: &mut Request) -> Result<Json<StatusJson>, ApiError> {
+    let name = printer_name(req)?;
+    Ok(Json(page_interface::printer_status(&name)?))
+}
+
+/// `PATCH /api/v1/printers/{name}` with a `PrinterUpdate`, answers `204 No Content` once the
+/// config file has been changed.
+#[handler]
+pub async fn update_printer(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let name = printer_name(req)?;
+    let update = parse_body::<PrinterUpdate>(req).await?;
+    page_interface::update_printer(&auth::depot_user(depot)?, &name, update).await?;
+    res.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+/// `GET /api/v1/printers/{name}/files`, the files stored on a printer.
+#[handler]
+pub async fn printer_files(req: &mut Request) -> Result<Json<Vec<String>>, ApiError> {
+    let name = printer_name(req)?;
+    Ok(Json(page_interface::printer_files(&name)?))
+}
+
+/// `POST /api/v1/printers/{name}/actions/{action}` for `pause`, `resume`, `stop` and `start`.
+/// `start` takes the file to print as `{"file": "..."}`. Answers `204 No Content` once the
+/// printer has accepted the command.
+#[handler]
+pub async fn printer_action(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let name = printer_name(req)?;
+    let action = req.param::<String>("action").unwrap_or_default();
+    let body = parse_body::<ActionBody>(req).await?;
+    let user = auth::depot_user(depot)?;
+    page_interface::run_print_action(&user, &name, &action, body.file, body.confirmation).await?;
+    res.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+/// `POST /api/v1/printers/{name}/claim` with a `ClaimBody`, answers `204 No Content` once the
+/// printer is held by the user.
+#[handler]
+pub async fn claim_printer(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let name = printer_name(req)?;
+    let body = parse_body::<ClaimBody>(req).await?;
+    let user = auth::depot_user(depot)?;
+    page_interface::claim_printer(&user, &name, body.minutes).await?;
+    res.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+/// `DELETE /api/v1/printers/{name}/claim`
+#[handler]
+pub async fn release_printer(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), ApiError> {
+    let name = printer_name(req)?;
+    let user = auth::depot_user(depot)?;
+    page_interface::release_printer(&user, &name).await?;
+    res.status_code(StatusCode::NO_CONTENT);
+    Ok(())
+}
+
+#[handler]
+async fn not_found(req: &mut Request) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        format!("No API endpoint at {}", req.uri().path()),
+    )
+}
+
+#[tokio::test]
+async fn test_bad_requests() {
+    use salvo::test::{ResponseExt, TestClient};
+
+    let service = Service::new(router());
+    let base = "http://127.0.0.1:8000/api/v1";
+    let res = TestClient::post(format!("{base}/printers/printer1/actions/stop"))
+        .send(&service)
+        .await;
+    assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+    let token = auth::SESSIONS
+        .lock()
+        .unwrap()
+        .start(User::new("operator", auth::Role::Operator));
+    let cookie = format!("{}={token}", auth::SESSION_COOKIE);
+    let res = TestClient::get(format!("{base}/users"))
+        .add_header("cookie", &cookie, true)
+        .send(&service)
+        .await;
+    assert_eq!(res.status_code, Some(StatusCode::FORBIDDEN));
+    let res = TestClient::get(format!("{base}/audit?from=yesterday"))
+        .add_header("cookie", &cookie, true)
+        .send(&service)
+        .await;
+    assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+    let res = TestClient::get(format!("{base}/printers"))
+        .add_header("authorization", "Bearer rpm_nope", true)
+        .add_header("cookie", &cookie, true)
+        .send(&service)
+        .await;
+    assert_eq!(res.status_code, Some(StatusCode::UNAUTHORIZED));
+    let mut res = TestClient::post(format!("{base}/printers/printer1/actions/explode"))
+        .add_header("cookie", &cookie, true)
+        .send(&service)
+        .await;
+    assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+    assert_eq!(
+        res.take_string().await.unwrap(),
+        r#"{"error":"Action of explode currently not supported"}"#
+    );
+    let mut res = TestClient::post(format!("{base}/printers/printer1/actions/start"))
+        .add_header("cookie", &cookie, true)
+        .json(&serde_json::json!({}))
+        .send(&service)
+        .await;
+    assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+    assert_eq!(
+        res.take_string().await.unwrap(),
+        r#"{"error":"file is required"}"#
+    );
+    let res = TestClient::post(format!("{base}/printers/printer1/actions/start"))
+        .add_header("cookie", &cookie, true)
+        .raw_json("{")
+        .send(&service)
+        .await;
+    assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+    let mut res = TestClient::get(format!("{base}/nothing"))
+        .send(&service)
+        .await;
+    assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+    assert_eq!(
+        res.take_string().await.unwrap(),
+        r#"{"error":"No API endpoint at /api/v1/nothing"}"#
+    );
+}
+
+#[test]
+fn test_error_status() {
+    let status = |e: CommandError| ApiError::from(e).status;
+    assert_eq!(
+        status(CommandError::UnknownPrinter("printer1".to_string())),
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        status(CommandError::Printer("timed out".to_string())),
+        StatusCode::BAD_GATEWAY
+    );
+    assert_eq!(
+        status(CommandError::Config("denied".to_string())),
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+}
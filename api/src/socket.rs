@@ -1,5 +1,7 @@
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use futures_util::{FutureExt, StreamExt};
 
@@ -11,19 +13,44 @@ use salvo::websocket::{Message, WebSocket, WebSocketUpgrade};
 use tokio::sync::{mpsc, RwLock};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 
-use crate::page_interface;
+use crate::auth::Credential;
+use crate::subscriptions::{Groups, Subscriber, Subscription};
+use crate::ws_protocol::{self, ServerMessage, Session};
+use crate::{auth, page_interface, sse};
 
 static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
 
-type Users = RwLock<HashMap<usize, mpsc::UnboundedSender<Result<Message, salvo::Error>>>>;
+/// A connected websocket client.
+pub struct User {
+    tx: mpsc::UnboundedSender<Result<Message, salvo::Error>>,
+    subscriber: Subscriber,
+}
+
+type Users = RwLock<HashMap<usize, User>>;
 
 pub static ONLINE_USERS: Lazy<Users> = Lazy::new(Users::default);
 
+/// The printer groups as of the last poll, so sending a message never reads the config file.
+static GROUPS: Lazy<std::sync::RwLock<Arc<Groups>>> = Lazy::new(Default::default);
+
+/// Replaces the groups subscriptions are matched against, with those just read from the config
+/// file.
+pub fn set_groups(groups: Groups) {
+    let mut current = GROUPS.write().unwrap();
+    if **current != groups {
+        *current = Arc::new(groups);
+    }
+}
+
+fn groups() -> Arc<Groups> {
+    GROUPS.read().unwrap().clone()
+}
+
 pub async fn send_message_to_user(user_id: usize, msg: Message) {
     tracing::info!("Sending message to user {user_id}");
     match ONLINE_USERS.read().await.get(&user_id) {
-        Some(tx) => {
-            if let Err(_disconnected) = tx.send(Ok(msg.clone())) {
+        Some(user) => {
+            if let Err(_disconnected) = user.tx.send(Ok(msg.clone())) {
                 // ignore disconnect
             }
         }
@@ -31,19 +58,68 @@ pub async fn send_message_to_user(user_id: usize, msg: Message) {
     }
 }
 
-pub async fn send_message_to_all(msg: Message) {
-    for (_, tx) in ONLINE_USERS.read().await.iter() {
-        if let Err(_disconnected) = tx.send(Ok(msg.clone())) {
-            // ignore disconnection
+/// Sends a user the part of `message` they subscribed to.
+pub async fn send_to_user(user_id: usize, message: &ServerMessage) {
+    let groups = groups();
+    match ONLINE_USERS.read().await.get(&user_id) {
+        Some(user) => {
+            if let Some(message) = user.subscriber.filter(message, &groups) {
+                if let Err(_disconnected) = user.tx.send(Ok(message.to_message())) {
+                    // ignore disconnect
+                }
+            }
+        }
+        _ => tracing::warn!("Did not find the user {user_id} in ONLINE_USERS"),
+    }
+}
+
+/// Sends `message` to every user subscribed to its printer, and to the SSE clients.
+pub async fn send_message_to_all(message: &ServerMessage) {
+    sse::record(&serde_json::to_string(message).unwrap());
+    let groups = groups();
+    for user in ONLINE_USERS.read().await.values() {
+        if let Some(message) = user.subscriber.filter(message, &groups) {
+            if let Err(_disconnected) = user.tx.send(Ok(message.to_message())) {
+                // ignore disconnection
+            }
+        }
+    }
+}
+
+/// Changes what a user is subscribed to.
+///
+/// # Returns
+/// `false` if the user is not connected.
+pub async fn update_subscription(user_id: usize, change: impl FnOnce(&mut Subscription)) -> bool {
+    match ONLINE_USERS.write().await.get_mut(&user_id) {
+        Some(user) => {
+            change(&mut user.subscriber.subscription);
+            true
         }
+        None => false,
+    }
+}
+
+/// Moves every subscription to a printer to its new name.
+pub async fn rename_printer(printer_name: &str, new_name: &str) {
+    for user in ONLINE_USERS.write().await.values_mut() {
+        user.subscriber.subscription.rename(printer_name, new_name);
     }
 }
 
+/// `GET /ws`, only upgraded for browsers that are logged in and clients with an API token.
 #[handler]
 pub async fn user_connected(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
     tracing::info!("User connected from {}", req.remote_addr());
+    let credential = auth::request_credential(req)
+        .filter(|credential| credential.user().is_some())
+        .ok_or_else(|| {
+            StatusError::unauthorized()
+                .brief("Log in or send an API token before opening the websocket")
+        })?;
+    let address = auth::request_address(req);
     WebSocketUpgrade::new()
-        .upgrade(req, res, handle_socket)
+        .upgrade(req, res, move |ws| handle_socket(ws, credential, address))
         .await
 }
 
@@ -52,7 +128,7 @@ pub async fn user_disconnected(user_id: usize) {
     ONLINE_USERS.write().await.remove(&user_id);
 }
 
-async fn handle_socket(ws: WebSocket) {
+async fn handle_socket(ws: WebSocket, credential: Credential, address: Option<IpAddr>) {
     // Use a counter to assign a new unique ID for this user.
     // might change this to uuid
     let user_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
@@ -72,14 +148,22 @@ async fn handle_socket(ws: WebSocket) {
     // spawn thread to handle the user socket
     tokio::task::spawn(fut);
     let fut = async move {
-        ONLINE_USERS.write().await.insert(user_id, tx);
-        let _ = page_interface::update_user_page(user_id).await;
+        ONLINE_USERS.write().await.insert(
+            user_id,
+            User {
+                tx,
+                subscriber: Subscriber::default(),
+            },
+        );
+        let mut session = Session::new(credential, address);
+        // the printers as of the last poll, the next one sends what changed since
+        page_interface::send_snapshot(user_id).await;
         while let Some(result) = user_ws_rx.next().await {
             match result {
                 Ok(msg) => match msg.to_str() {
                     Ok(m) => {
                         tracing::debug!("{m}");
-                        page_interface::issue_printer_command(m).await;
+                        ws_protocol::handle_message(user_id, m, &mut session).await;
                     }
                     Err(msg_e) => tracing::warn!("{msg_e}"),
                 },
@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures_util::{stream, StreamExt};
+use once_cell::sync::Lazy;
+use salvo::prelude::*;
+use salvo::sse::{SseEvent, SseKeepAlive};
+use tokio::sync::broadcast;
+
+use crate::status_updates::STATUS_UPDATES;
+
+/// How many broadcasts are kept for clients that reconnect with `Last-Event-ID`.
+const REPLAY_LIMIT: usize = 200;
+
+/// A message sent to every user, numbered so clients can resume after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeedItem {
+    pub id: u64,
+    /// The `type` of the websocket message, used as the SSE event name.
+    pub kind: String,
+    pub data: String,
+}
+
+impl FeedItem {
+    fn to_event(&self) -> SseEvent {
+        SseEvent::default()
+            .id(self.id.to_string())
+            .name(&self.kind)
+            .text(&self.data)
+    }
+}
+
+/// The broadcasts of the websocket, in order, for SSE clients.
+pub struct Feed {
+    next_id: u64,
+    recent: VecDeque<FeedItem>,
+    sender: broadcast::Sender<FeedItem>,
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Feed {
+            next_id: 1,
+            recent: VecDeque::new(),
+            sender: broadcast::channel(REPLAY_LIMIT).0,
+        }
+    }
+}
+
+impl Feed {
+    /// Numbers a broadcast and passes it on to every connected SSE client.
+    pub fn record(&mut self, text: &str) -> FeedItem {
+        let kind = serde_json::from_str::<serde_json::Value>(text)
+            .ok()
+            .and_then(|value| value["type"].as_str().map(String::from))
+            .unwrap_or_else(|| "message".to_string());
+        let item = FeedItem {
+            id: self.next_id,
+            kind,
+            data: text.to_string(),
+        };
+        self.next_id += 1;
+        self.recent.push_back(item.clone());
+        if self.recent.len() > REPLAY_LIMIT {
+            self.recent.pop_front();
+        }
+        // no receivers is fine, nobody is listening
+        let _ = self.sender.send(item.clone());
+        item
+    }
+
+    /// The id of the last broadcast, 0 before the first.
+    pub fn last_id(&self) -> u64 {
+        self.next_id - 1
+    }
+
+    /// The broadcasts after `last_event_id`, or `None` if the client needs a snapshot instead:
+    /// when it has not seen anything yet, when the broadcasts it missed are no longer kept, or
+    /// when the id was never handed out, as ids are only unique while the server runs.
+    pub fn replay(&self, last_event_id: Option<u64>) -> Option<Vec<FeedItem>> {
+        let last = last_event_id?;
+        let oldest = self.recent.front().map_or(self.next_id, |item| item.id);
+        if last >= self.next_id || last + 1 < oldest {
+            return None;
+        }
+        Some(
+            self.recent
+                .iter()
+                .filter(|item| item.id > last)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+static FEED: Lazy<Mutex<Feed>> = Lazy::new(|| Mutex::new(Feed::default()));
+
+/// Records a broadcast, called for every message sent to all websocket users.
+pub fn record(text: &str) {
+    FEED.lock().unwrap().record(text);
+}
+
+/// `GET /events`, the websocket broadcasts as Server-Sent Events named by their `type`.
+/// A client reconnecting with `Last-Event-ID` gets the broadcasts it missed first, as long as
+/// they are among the last `REPLAY_LIMIT`, any other client starts with a `printers` snapshot.
+#[handler]
+pub async fn events(req: &mut Request, res: &mut Response) {
+    let last_event_id = req
+        .header::<String>("last-event-id")
+        .and_then(|id| id.trim().parse::<u64>().ok());
+    tracing::info!("SSE client connected from {}", req.remote_addr());
+    let (replay, last_id, receiver) = {
+        let feed = FEED.lock().unwrap();
+        // subscribing while holding the lock means nothing falls between the replay and live items
+        (
+            feed.replay(last_event_id),
+            feed.last_id(),
+            feed.sender.subscribe(),
+        )
+    };
+    // taken after subscribing, so a change is at worst sent twice and skipped by its `seq`
+    let replay = match replay {
+        Some(replay) => replay,
+        None => {
+            let snapshot = STATUS_UPDATES.lock().await.snapshot();
+            vec![FeedItem {
+                id: last_id,
+                kind: "printers".to_string(),
+                data: serde_json::to_string(&snapshot).unwrap(),
+            }]
+        }
+    };
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(item) => return Some((item, receiver)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("SSE client fell behind and missed {skipped} messages");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    let items = stream::iter(replay)
+        .chain(live)
+        .map(|item| Ok::<_, Infallible>(item.to_event()));
+    SseKeepAlive::new(items)
+        .max_interval(Duration::from_secs(15))
+        .stream(res);
+}
+
+#[test]
+fn test_replay() {
+    let mut feed = Feed::default();
+    assert_eq!(feed.replay(None), None);
+    assert_eq!(feed.replay(Some(0)), Some(Vec::new()));
+    feed.record(r#"{"type": "printer_changed", "seq": 1}"#);
+    let event = feed.record(r#"{"type": "event", "kind": "offline"}"#);
+    assert_eq!(event.id, 2);
+    assert_eq!(event.kind, "event");
+    feed.record(r#"{"type": "printer_removed", "seq": 2}"#);
+    let ids = |items: Option<Vec<FeedItem>>| {
+        items.map(|items| items.iter().map(|item| item.id).collect::<Vec<_>>())
+    };
+    assert_eq!(ids(feed.replay(None)), None);
+    assert_eq!(ids(feed.replay(Some(1))), Some(vec![2, 3]));
+    assert_eq!(ids(feed.replay(Some(3))), Some(Vec::new()));
+    // from before a restart
+    assert_eq!(ids(feed.replay(Some(90))), None);
+    for _ in 0..REPLAY_LIMIT {
+        feed.record("{}");
+    }
+    assert_eq!(feed.last_id(), 3 + REPLAY_LIMIT as u64);
+    // the broadcasts after 1 are gone
+    assert_eq!(ids(feed.replay(Some(1))), None);
+    let replay = feed.replay(Some(3)).unwrap();
+    assert_eq!(replay.len(), REPLAY_LIMIT);
+    assert_eq!(replay[0].kind, "message");
+}
@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+
+use once_cell::sync::Lazy;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::sync::Mutex;
+
+use crate::claims::Claim;
+use crate::page_interface::StatusJson;
+use crate::ws_protocol::ServerMessage;
+
+/// The fields of a printer's status that changed since the last broadcast. A printer that was
+/// not known before gets every field.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Default, Clone, PartialEq)]
+pub struct StatusPatch {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files_available: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused: Option<bool>,
+    /// The new holder of the printer, `null` once it is released or the claim expired.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "present"
+    )]
+    pub claim: Option<Option<Claim>>,
+}
+
+/// Reads a field that is there, even if it is `null`, as `Some`, so a missing field stays `None`.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl StatusPatch {
+    /// The changes from `old` to `new`, or `None` if nothing changed.
+    pub fn between(old: Option<&StatusJson>, new: &StatusJson) -> Option<StatusPatch> {
+        fn changed<T: PartialEq + Clone>(old: Option<&T>, new: &T) -> Option<T> {
+            (old != Some(new)).then(|| new.clone())
+        }
+        let patch = StatusPatch {
+            ip_address: changed(old.map(|o| &o.ip_address), &new.ip_address),
+            files_available: changed(old.map(|o| &o.files_available), &new.files_available),
+            progress: changed(old.map(|o| &o.progress), &new.progress),
+            paused: changed(old.map(|o| &o.paused), &new.paused),
+            claim: changed(old.map(|o| &o.claim), &new.claim),
+        };
+        (patch != StatusPatch::default()).then_some(patch)
+    }
+}
+
+/// The status last broadcast to users, so the next poll only has to send what changed.
+#[derive(Debug, Default)]
+pub struct StatusUpdates {
+    /// The sequence number of the last change, every change message gets the next one.
+    seq: u64,
+    printers: BTreeMap<String, StatusJson>,
+}
+
+pub static STATUS_UPDATES: Lazy<Mutex<StatusUpdates>> =
+    Lazy::new(|| Mutex::new(StatusUpdates::default()));
+
+impl StatusUpdates {
+    /// Takes the result of a poll and returns a numbered message for every printer that was
+    /// added, changed or removed since the last one.
+    pub fn update(&mut self, statuses: Vec<StatusJson>) -> Vec<ServerMessage> {
+        let mut messages = Vec::new();
+        let mut current = BTreeMap::new();
+        for status in statuses {
+            let old = self.printers.get(&status.printer_name);
+            if let Some(changes) = StatusPatch::between(old, &status) {
+                self.seq += 1;
+                messages.push(ServerMessage::PrinterChanged {
+                    seq: self.seq,
+                    printer_name: status.printer_name.clone(),
+                    changes,
+                });
+            }
+            current.insert(status.printer_name.clone(), status);
+        }
+        for printer_name in self.printers.keys() {
+            if !current.contains_key(printer_name) {
+                self.seq += 1;
+                messages.push(ServerMessage::PrinterRemoved {
+                    seq: self.seq,
+                    printer_name: printer_name.clone(),
+                });
+            }
+        }
+        self.printers = current;
+        messages
+    }
+
+    /// Every printer as of the last poll.
+    pub fn printers(&self) -> Vec<StatusJson> {
+        self.printers.values().cloned().collect()
+    }
+
+    /// Every printer as of the last change, for clients that are starting or lost track.
+    pub fn snapshot(&self) -> ServerMessage {
+        ServerMessage::Printers {
+            seq: self.seq,
+            printers: self.printers(),
+        }
+    }
+}
+
+#[cfg(test)]
+fn status(name: &str, progress: &str) -> StatusJson {
+    StatusJson {
+        printer_name: name.to_string(),
+        ip_address: "10.0.0.2".to_string(),
+        files_available: vec!["part.ctb".to_string()],
+        progress: progress.to_string(),
+        paused: false,
+        claim: None,
+    }
+}
+
+#[test]
+fn test_updates() {
+    let mut updates = StatusUpdates::default();
+    let messages = updates.update(vec![status("a", "Not Printing"), status("b", "10.00")]);
+    assert_eq!(messages.len(), 2);
+    assert_eq!(
+        messages[1],
+        ServerMessage::PrinterChanged {
+            seq: 2,
+            printer_name: "b".to_string(),
+            changes: StatusPatch {
+                ip_address: Some("10.0.0.2".to_string()),
+                files_available: Some(vec!["part.ctb".to_string()]),
+                progress: Some("10.00".to_string()),
+                paused: Some(false),
+                claim: Some(None),
+            },
+        }
+    );
+    // nothing changed
+    assert!(updates
+        .update(vec![status("a", "Not Printing"), status("b", "10.00")])
+        .is_empty());
+    let messages = updates.update(vec![status("b", "20.00")]);
+    assert_eq!(
+        messages,
+        vec![
+            ServerMessage::PrinterChanged {
+                seq: 3,
+                printer_name: "b".to_string(),
+                changes: StatusPatch {
+                    progress: Some("20.00".to_string()),
+                    ..StatusPatch::default()
+                },
+            },
+            ServerMessage::PrinterRemoved {
+                seq: 4,
+                printer_name: "a".to_string(),
+            },
+        ]
+    );
+    assert_eq!(
+        updates.snapshot(),
+        ServerMessage::Printers {
+            seq: 4,
+            printers: vec![status("b", "20.00")],
+        }
+    );
+    assert_eq!(
+        serde_json::to_string(&messages[0]).unwrap(),
+        r#"{"type":"printer_changed","seq":3,"printer_name":"b","changes":{"progress":"20.00"}}"#
+    );
+}
+
+#[test]
+fn test_released_claim_patch() {
+    let released = StatusPatch {
+        claim: Some(None),
+        ..StatusPatch::default()
+    };
+    let json = serde_json::to_string(&released).unwrap();
+    assert_eq!(json, r#"{"claim":null}"#);
+    assert_eq!(
+        serde_json::from_str::<StatusPatch>(&json).unwrap(),
+        released
+    );
+    assert_eq!(
+        serde_json::from_str::<StatusPatch>("{}").unwrap(),
+        StatusPatch::default()
+    );
+}
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config_file::Printers;
+use crate::page_interface::CommandError;
+use crate::ws_protocol::ServerMessage;
+
+/// Named sets of printers, stored under `"groups"` in the config file.
+pub type Groups = BTreeMap<String, Vec<String>>;
+
+/// The printers a websocket client asked for. A client without any gets every printer.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Subscription {
+    printers: BTreeSet<String>,
+    groups: BTreeSet<String>,
+}
+
+impl Subscription {
+    pub fn subscribe(&mut self, printers: Vec<String>, groups: Vec<String>) {
+        self.printers.extend(printers);
+        self.groups.extend(groups);
+    }
+
+    /// Drops the given printers and groups, or every subscription if both are empty.
+    pub fn unsubscribe(&mut self, printers: Vec<String>, groups: Vec<String>) {
+        if printers.is_empty() && groups.is_empty() {
+            *self = Subscription::default();
+            return;
+        }
+        for printer in printers {
+            self.printers.remove(&printer);
+        }
+        for group in groups {
+            self.groups.remove(&group);
+        }
+    }
+
+    /// Keeps a subscription to a printer when it is renamed.
+    pub fn rename(&mut self, printer_name: &str, new_name: &str) {
+        if self.printers.remove(printer_name) {
+            self.printers.insert(new_name.to_string());
+        }
+    }
+
+    pub fn includes(&self, printer_name: &str, groups: &Groups) -> bool {
+        if self.printers.is_empty() && self.groups.is_empty() {
+            return true;
+        }
+        self.printers.contains(printer_name)
+            || self.groups.iter().any(|group| {
+                groups
+                    .get(group)
+                    .is_some_and(|members| members.iter().any(|member| member == printer_name))
+            })
+    }
+}
+
+/// Checks that everything a client subscribes to is configured, so a typo is not mistaken for
+/// a quiet printer.
+///
+/// # Errors
+/// `UnknownPrinter` or `UnknownGroup` for the first name that is not in `config`.
+pub fn check_subscription(
+    config: &Printers,
+    printers: &[String],
+    groups: &[String],
+) -> Result<(), CommandError> {
+    if let Some(printer) = printers
+        .iter()
+        .find(|printer| !config.printers.contains_key(*printer))
+    {
+        return Err(CommandError::UnknownPrinter(printer.clone()));
+    }
+    if let Some(group) = groups
+        .iter()
+        .find(|group| !config.groups.contains_key(*group))
+    {
+        return Err(CommandError::UnknownGroup(group.clone()));
+    }
+    Ok(())
+}
+
+/// What a websocket user is sent. Status changes are numbered per user, so a client that only
+/// gets some printers still sees its `seq` go up by one with every change.
+#[derive(Debug, Default)]
+pub struct Subscriber {
+    pub subscription: Subscription,
+    /// The `seq` of the last change sent to this user, atomic so every user can be sent a
+    /// message under a read lock.
+    seq: AtomicU64,
+}
+
+impl Subscriber {
+    /// The message as this user should get it, or `None` if it is about a printer they did not
+    /// subscribe to. Messages that are not about printers pass through unchanged.
+    pub fn filter(&self, message: &ServerMessage, groups: &Groups) -> Option<ServerMessage> {
+        let includes = |name: &str| self.subscription.includes(name, groups);
+        match message {
+            ServerMessage::Printers { printers, .. } => Some(ServerMessage::Printers {
+                seq: self.seq.load(Ordering::Relaxed),
+                printers: printers
+                    .iter()
+                    .filter(|status| includes(&status.printer_name))
+                    .cloned()
+                    .collect(),
+            }),
+            ServerMessage::PrinterChanged {
+                printer_name,
+                changes,
+                ..
+            } if includes(printer_name) => Some(ServerMessage::PrinterChanged {
+                seq: self.seq.fetch_add(1, Ordering::Relaxed) + 1,
+                printer_name: printer_name.clone(),
+                changes: changes.clone(),
+            }),
+            ServerMessage::PrinterRemoved { printer_name, .. } if includes(printer_name) => {
+                Some(ServerMessage::PrinterRemoved {
+                    seq: self.seq.fetch_add(1, Ordering::Relaxed) + 1,
+                    printer_name: printer_name.clone(),
+                })
+            }
+            ServerMessage::Event { printer_name, .. } if includes(printer_name) => {
+                Some(message.clone())
+            }
+            ServerMessage::PrinterChanged { .. }
+            | ServerMessage::PrinterRemoved { .. }
+            | ServerMessage::Event { .. } => None,
+            _ => Some(message.clone()),
+        }
+    }
+}
+
+#[test]
+fn test_subscriptions() {
+    let groups = Groups::from([("bay1".to_string(), vec!["a".to_string(), "b".to_string()])]);
+    let mut subscription = Subscription::default();
+    assert!(subscription.includes("c", &groups));
+    subscription.subscribe(vec!["c".to_string()], vec!["bay1".to_string()]);
+    assert!(subscription.includes("a", &groups));
+    assert!(subscription.includes("c", &groups));
+    assert!(!subscription.includes("d", &groups));
+    subscription.unsubscribe(Vec::new(), vec!["bay1".to_string()]);
+    assert!(!subscription.includes("a", &groups));
+    assert!(subscription.includes("c", &groups));
+    subscription.rename("c", "d");
+    assert!(subscription.includes("d", &groups));
+    assert!(!subscription.includes("c", &groups));
+    subscription.unsubscribe(Vec::new(), Vec::new());
+    assert_eq!(subscription, Subscription::default());
+}
+
+#[test]
+fn test_subscriber_numbering() {
+    use crate::status_updates::StatusPatch;
+
+    let changed = |seq: u64, printer_name: &str| ServerMessage::PrinterChanged {
+        seq,
+        printer_name: printer_name.to_string(),
+        changes: StatusPatch::default(),
+    };
+    let mut subscriber = Subscriber::default();
+    subscriber
+        .subscription
+        .subscribe(vec!["b".to_string()], Vec::new());
+    let groups = Groups::new();
+    assert_eq!(subscriber.filter(&changed(7, "a"), &groups), None);
+    assert_eq!(
+        subscriber.filter(&changed(8, "b"), &groups),
+        Some(changed(1, "b"))
+    );
+    assert_eq!(
+        subscriber.filter(
+            &ServerMessage::PrinterRemoved {
+                seq: 9,
+                printer_name: "b".to_string()
+            },
+            &groups
+        ),
+        Some(ServerMessage::PrinterRemoved {
+            seq: 2,
+            printer_name: "b".to_string()
+        })
+    );
+    assert_eq!(
+        subscriber.filter(
+            &ServerMessage::Printers {
+                seq: 9,
+                printers: Vec::new()
+            },
+            &groups
+        ),
+        Some(ServerMessage::Printers {
+            seq: 2,
+            printers: Vec::new()
+        })
+    );
+}
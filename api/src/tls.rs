@@ -0,0 +1,242 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::Lazy;
+use salvo::conn::rustls::{Keycert, RustlsConfig};
+use salvo::http::uri::Authority;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::paths;
+
+/// Where the self-signed certificate is kept when the config file does not name one.
+static GENERATED_CERT: Lazy<PathBuf> = Lazy::new(|| paths::data_file("tls").join("cert.pem"));
+static GENERATED_KEY: Lazy<PathBuf> = Lazy::new(|| paths::data_file("tls").join("key.pem"));
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn is_default_enabled(enabled: &bool) -> bool {
+    *enabled == default_enabled()
+}
+
+/// The port HTTPS and WSS are served on.
+pub const DEFAULT_TLS_PORT: u16 = 8443;
+
+fn default_port() -> u16 {
+    DEFAULT_TLS_PORT
+}
+
+fn is_default_port(port: &u16) -> bool {
+    *port == DEFAULT_TLS_PORT
+}
+
+fn default_hostnames() -> Vec<String> {
+    vec!["localhost".to_string(), "127.0.0.1".to_string()]
+}
+
+fn default_redirect_http() -> bool {
+    true
+}
+
+fn is_default_redirect_http(redirect_http: &bool) -> bool {
+    *redirect_http == default_redirect_http()
+}
+
+fn is_default_hostnames(hostnames: &Vec<String>) -> bool {
+    *hostnames == default_hostnames()
+}
+
+/// Settings of HTTPS, stored under `"tls"` in the config file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    #[serde(
+        default = "default_enabled",
+        skip_serializing_if = "is_default_enabled"
+    )]
+    pub enabled: bool,
+    #[serde(default = "default_port", skip_serializing_if = "is_default_port")]
+    pub port: u16,
+    /// PEM certificate chain. Without it a self-signed certificate is generated and kept in the
+    /// `tls` directory of the data directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
+    /// PEM private key of `cert`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Answer plain HTTP with a redirect to HTTPS instead of serving the monitor on it, so
+    /// passwords never travel unencrypted. `false` serves the monitor on both.
+    #[serde(
+        default = "default_redirect_http",
+        skip_serializing_if = "is_default_redirect_http"
+    )]
+    pub redirect_http: bool,
+    /// Names and addresses the self-signed certificate is made for.
+    #[serde(
+        default = "default_hostnames",
+        skip_serializing_if = "is_default_hostnames"
+    )]
+    pub hostnames: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: default_enabled(),
+            port: default_port(),
+            cert: None,
+            key: None,
+            redirect_http: default_redirect_http(),
+            hostnames: default_hostnames(),
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn is_default(&self) -> bool {
+        *self == TlsConfig::default()
+    }
+}
+
+/// A self-signed certificate for `hostnames`, as PEM certificate and private key.
+///
+/// # Errors
+/// Returns an error if the certificate can not be generated.
+pub fn generate_self_signed(hostnames: &[String]) -> Result<(String, String), io::Error> {
+    let to_io = |e: rcgen::Error| io::Error::other(e.to_string());
+    let certificate = rcgen::generate_simple_self_signed(hostnames.to_vec()).map_err(to_io)?;
+    Ok((
+        certificate.serialize_pem().map_err(to_io)?,
+        certificate.serialize_private_key_pem(),
+    ))
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &str) -> Result<(), io::Error> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &str) -> Result<(), io::Error> {
+    fs::write(path, data)
+}
+
+/// Reads the self-signed certificate made on an earlier run, or makes and keeps one.
+fn self_signed_keycert(config: &TlsConfig) -> Result<(Vec<u8>, Vec<u8>), io::Error> {
+    if GENERATED_CERT.exists() && GENERATED_KEY.exists() {
+        return Ok((fs::read(&*GENERATED_CERT)?, fs::read(&*GENERATED_KEY)?));
+    }
+    let (cert, key) = generate_self_signed(&config.hostnames)?;
+    fs::create_dir_all(paths::data_file("tls"))?;
+    write_private(&GENERATED_KEY, &key)?;
+    fs::write(&*GENERATED_CERT, &cert)?;
+    tracing::info!(
+        "Generated a self-signed certificate for {} in {}",
+        config.hostnames.join(", "),
+        GENERATED_CERT.display()
+    );
+    Ok((cert.into_bytes(), key.into_bytes()))
+}
+
+/// Builds the TLS settings from a PEM certificate chain and private key.
+///
+/// # Errors
+/// Returns an error if they are not a valid certificate and matching key.
+pub fn rustls_config(cert: Vec<u8>, key: Vec<u8>) -> Result<RustlsConfig, io::Error> {
+    let config = RustlsConfig::new(Keycert::new().cert(cert).key(key));
+    // salvo only parses them when the listener binds, check them now to fail early
+    let _: salvo::conn::rustls::ServerConfig = config.clone().try_into()?;
+    Ok(config)
+}
+
+/// The TLS settings for the certificate of the config file, or the self-signed one.
+///
+/// # Errors
+/// Returns an error if only one of `cert` and `key` is set, if they can not be read, or if the
+/// self-signed certificate can not be generated or stored.
+pub fn load(config: &TlsConfig) -> Result<RustlsConfig, io::Error> {
+    let (cert, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (fs::read(cert)?, fs::read(key)?),
+        (None, None) => self_signed_keycert(config)?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tls needs both cert and key, or neither for a self-signed certificate",
+            ))
+        }
+    };
+    rustls_config(cert, key)
+}
+
+/// Where a plain HTTP request to `host` and `path` lives on HTTPS.
+fn https_location(host: Option<&str>, port: u16, path_and_query: &str) -> String {
+    let host = host
+        .and_then(|host| host.parse::<Authority>().ok())
+        .map_or_else(|| "localhost".to_string(), |host| host.host().to_string());
+    if port == 443 {
+        format!("https://{host}{path_and_query}")
+    } else {
+        format!("https://{host}:{port}{path_and_query}")
+    }
+}
+
+/// Sends every plain HTTP request to the same place on HTTPS.
+pub struct RedirectToHttps {
+    pub port: u16,
+}
+
+#[handler]
+impl RedirectToHttps {
+    async fn handle(&self, req: &mut Request, res: &mut Response) {
+        let host = req.header::<String>("host");
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        res.render(Redirect::permanent(https_location(
+            host.as_deref(),
+            self.port,
+            path,
+        )));
+    }
+}
+
+#[test]
+fn test_tls_config() {
+    let config: TlsConfig = serde_json::from_str("{}").unwrap();
+    assert!(config.is_default());
+    assert!(config.enabled);
+    assert_eq!(config.port, 8443);
+    assert!(config.redirect_http);
+    assert_eq!(serde_json::to_string(&config).unwrap(), "{}");
+    let config: TlsConfig = serde_json::from_str(
+        r#"{"port": 443, "cert": "/etc/monitor/cert.pem", "key": "/etc/monitor/key.pem",
+            "redirect_http": false}"#,
+    )
+    .unwrap();
+    assert!(!config.redirect_http);
+    assert_eq!(config.cert.as_deref(), Some("/etc/monitor/cert.pem"));
+
+    let (cert, key) = generate_self_signed(&default_hostnames()).unwrap();
+    assert!(cert.starts_with("-----BEGIN CERTIFICATE-----"));
+    assert!(rustls_config(cert.clone().into_bytes(), key.into_bytes()).is_ok());
+    assert!(rustls_config(cert.into_bytes(), b"not a key".to_vec()).is_err());
+
+    assert_eq!(
+        https_location(Some("printers.local:8000"), 8443, "/api/v1/me?x=1"),
+        "https://printers.local:8443/api/v1/me?x=1"
+    );
+    assert_eq!(
+        https_location(Some("[::1]:8000"), 443, "/"),
+        "https://[::1]/"
+    );
+    assert_eq!(https_location(None, 8443, "/"), "https://localhost:8443/");
+}
@@ -0,0 +1,217 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::printer_events::{EventKind, PrinterEvent};
+
+/// Payload sent when a webhook does not define its own template.
+pub const DEFAULT_TEMPLATE: &str = r#"{"event":"{{event}}","printer_name":"{{printer_name}}","message":"{{message}}","file":"{{file}}","timestamp":"{{timestamp}}"}"#;
+
+/// Header holding `sha256=<hex HMAC of the body>` when the webhook has a secret.
+pub const SIGNATURE_HEADER: &str = "X-Printer-Monitor-Signature";
+pub const EVENT_HEADER: &str = "X-Printer-Monitor-Event";
+
+/// Delay before the first retry, doubled for every retry after that.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Unable to build the webhook client")
+});
+
+fn default_retries() -> u32 {
+    3
+}
+
+/// A URL that gets a JSON POST for every event it is interested in.
+///
+/// `template` is the body to send, `{{event}}`, `{{printer_name}}`, `{{message}}`, `{{file}}` and
+/// `{{timestamp}}` are replaced with JSON escaped values, so `{"content": "{{message}}"}` works for
+/// Discord and `{"text": "{{message}}"}` for Slack.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebhookConfig {
+    /// Used by notification rules to send to this webhook.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// The events to send, every event if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<EventKind>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+impl WebhookConfig {
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+/// Escapes a value for use inside a JSON string.
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Fills in the placeholders of a template for `event`, described by `message`.
+pub fn render(template: &str, event: &PrinterEvent, message: &str, at: OffsetDateTime) -> String {
+    [
+        ("{{event}}", event.kind().as_str().to_string()),
+        ("{{printer_name}}", event.printer_name().to_string()),
+        ("{{message}}", message.to_string()),
+        ("{{file}}", event.file().to_string()),
+        ("{{timestamp}}", at.format(&Rfc3339).unwrap()),
+    ]
+    .iter()
+    .fold(template.to_string(), |body, (placeholder, value)| {
+        body.replace(placeholder, &json_escape(value))
+    })
+}
+
+/// Signs a body the same way receivers are expected to verify it: `sha256=` followed by the hex
+/// encoded HMAC-SHA256 of the raw body.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Posts a rendered body to a webhook, retrying with a growing delay if it fails.
+///
+/// # Errors
+/// Returns the last error once all retries are used up.
+pub async fn deliver(webhook: &WebhookConfig, kind: EventKind, body: String) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        let mut request = CLIENT
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, kind.as_str());
+        if let Some(secret) = &webhook.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, body.as_bytes()));
+        }
+        let error = match request.body(body.clone()).send().await {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => format!("{} answered {}", webhook.url, response.status()),
+            Err(e) => format!("{} failed: {e}", webhook.url),
+        };
+        if attempt >= webhook.retries {
+            return Err(error);
+        }
+        tracing::warn!(
+            "Webhook attempt {} of {}: {error}",
+            attempt + 1,
+            webhook.retries + 1
+        );
+        tokio::time::sleep(RETRY_DELAY * 2u32.pow(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Accepts one HTTP request on `listener`, answers it with `status` and returns the raw request.
+#[cfg(test)]
+pub(crate) async fn receive_request(listener: &tokio::net::TcpListener, status: u16) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        let read = stream.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..read]);
+        let text = String::from_utf8_lossy(&request).to_lowercase();
+        if let Some(header_end) = text.find("\r\n\r\n") {
+            let length = text
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length: "))
+                .map_or(0, |l| l.trim().parse::<usize>().unwrap());
+            if request.len() >= header_end + 4 + length {
+                break;
+            }
+        }
+    }
+    stream
+        .write_all(format!("HTTP/1.1 {status} OK\r\ncontent-length: 0\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    String::from_utf8(request).unwrap()
+}
+
+#[test]
+fn test_render_template() {
+    let event = PrinterEvent::JobStarted {
+        printer_name: "printer \"1\"".to_string(),
+        file: "part.ctb".to_string(),
+    };
+    let body = render(
+        DEFAULT_TEMPLATE,
+        &event,
+        &event.message(),
+        OffsetDateTime::UNIX_EPOCH,
+    );
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["event"], "job_started");
+    assert_eq!(json["printer_name"], "printer \"1\"");
+    assert_eq!(json["file"], "part.ctb");
+    assert_eq!(json["timestamp"], "1970-01-01T00:00:00Z");
+    assert_eq!(
+        render(
+            r#"{"content": "{{message}}"}"#,
+            &event,
+            &event.message(),
+            OffsetDateTime::UNIX_EPOCH
+        ),
+        r#"{"content": "printer \"1\" started printing part.ctb"}"#
+    );
+}
+
+#[test]
+fn test_sign() {
+    // RFC 4231 test case 2
+    assert_eq!(
+        sign("Jefe", b"what do ya want for nothing?"),
+        "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+}
+
+#[tokio::test]
+async fn test_deliver_signs_and_retries() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let webhook = WebhookConfig {
+        name: None,
+        url: format!("http://{}/hook", listener.local_addr().unwrap()),
+        secret: Some("secret".to_string()),
+        events: Vec::new(),
+        template: None,
+        retries: 1,
+    };
+    let body = r#"{"event":"finished"}"#.to_string();
+    let delivery = tokio::spawn({
+        let webhook = webhook.clone();
+        let body = body.clone();
+        async move { deliver(&webhook, EventKind::Finished, body).await }
+    });
+    let first = receive_request(&listener, 500).await;
+    let second = receive_request(&listener, 200).await;
+    assert_eq!(first, second);
+    assert!(second.starts_with("POST /hook HTTP/1.1"));
+    assert!(second.to_lowercase().contains(&format!(
+        "{}: {}",
+        SIGNATURE_HEADER.to_lowercase(),
+        sign("secret", body.as_bytes())
+    )));
+    assert!(second.ends_with(&body));
+    assert_eq!(delivery.await.unwrap(), Ok(()));
+}
@@ -0,0 +1,470 @@
+use std::net::IpAddr;
+
+use salvo::websocket::Message;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::auth::{Credential, User};
+use crate::confirmations::ConfirmationRequest;
+use crate::page_interface::{self, CommandError, PrinterUpdate, StatusJson};
+use crate::printer_events::{EventKind, PrinterEvent};
+use crate::socket;
+use crate::status_updates::StatusPatch;
+
+/// Version of the websocket messages, sent by the client in `hello`.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// What a client can ask for. Everything except `hello` needs a finished handshake.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientCommand {
+    /// Starts the session, answered by `welcome` if the server speaks `protocol_version`.
+    Hello {
+        protocol_version: u32,
+    },
+    /// Asks for every printer again, a `printers` snapshot is sent before the `ack`.
+    Resync,
+    /// Only sends changes and events of these printers and the printers in these groups, on
+    /// top of earlier subscriptions. A `printers` snapshot of them is sent before the `ack`.
+    Subscribe {
+        #[serde(default)]
+        printers: Vec<String>,
+        #[serde(default)]
+        groups: Vec<String>,
+    },
+    /// Takes printers and groups out of the subscriptions, or all of them if both are empty,
+    /// which means every printer is sent again. A new snapshot is sent before the `ack`.
+    Unsubscribe {
+        #[serde(default)]
+        printers: Vec<String>,
+        #[serde(default)]
+        groups: Vec<String>,
+    },
+    Add {
+        name: String,
+        ip_address: IpAddr,
+    },
+    /// Removes a printer, answered by `confirmation_required` until it is sent again with the
+    /// `confirmation` from that answer.
+    Remove {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        confirmation: Option<String>,
+    },
+    /// Changes the settings of the printer called `name`, see `PrinterUpdate`.
+    Update {
+        name: String,
+        #[serde(flatten)]
+        changes: PrinterUpdate,
+    },
+    /// Marks the pending notifications of a printer as seen.
+    Acknowledge {
+        name: String,
+    },
+    Resume {
+        name: String,
+    },
+    Pause {
+        name: String,
+    },
+    /// Stops the print, answered by `confirmation_required` until it is sent again with the
+    /// `confirmation` from that answer.
+    Stop {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        confirmation: Option<String>,
+    },
+    Start {
+        name: String,
+        file: String,
+    },
+    /// Claims the printer for `minutes`, 30 if left out, so only this user can start, pause,
+    /// resume or stop prints on it. Claiming it again renews the claim.
+    Claim {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        minutes: Option<u32>,
+    },
+    /// Gives up a claim, admins can release the claims of others.
+    Release {
+        name: String,
+    },
+}
+
+/// A message from the web page.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ClientMessage {
+    /// Chosen by the client and repeated in the reply to this message.
+    pub request_id: String,
+    #[serde(flatten)]
+    pub command: ClientCommand,
+}
+
+/// Why a message failed, for clients to act on without parsing `message`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidMessage,
+    UnsupportedProtocolVersion,
+    HandshakeRequired,
+    UnknownPrinter,
+    UnknownGroup,
+    UnknownUser,
+    UnknownToken,
+    Unauthorized,
+    Forbidden,
+    UnsupportedAction,
+    MissingField,
+    InvalidValue,
+    Conflict,
+    /// Someone else claimed the printer, `message` says who.
+    PrinterClaimed,
+    ConfirmationRequired,
+    PrinterUnavailable,
+    ConfigError,
+}
+
+impl From<&CommandError> for ErrorCode {
+    fn from(e: &CommandError) -> Self {
+        match e {
+            CommandError::UnknownPrinter(_) => ErrorCode::UnknownPrinter,
+            CommandError::UnknownGroup(_) => ErrorCode::UnknownGroup,
+            CommandError::UnknownUser(_) => ErrorCode::UnknownUser,
+            CommandError::UnknownToken(_) => ErrorCode::UnknownToken,
+            CommandError::Unauthorized => ErrorCode::Unauthorized,
+            CommandError::Forbidden(_) => ErrorCode::Forbidden,
+            CommandError::UnsupportedAction(_) => ErrorCode::UnsupportedAction,
+            CommandError::MissingField(_) => ErrorCode::MissingField,
+            CommandError::InvalidValue(_) => ErrorCode::InvalidValue,
+            CommandError::Conflict(_) => ErrorCode::Conflict,
+            CommandError::Claimed(..) => ErrorCode::PrinterClaimed,
+            CommandError::ConfirmationRequired(_) => ErrorCode::ConfirmationRequired,
+            CommandError::Printer(_) => ErrorCode::PrinterUnavailable,
+            CommandError::Config(_) => ErrorCode::ConfigError,
+        }
+    }
+}
+
+/// A message to the web page.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome {
+        request_id: String,
+        protocol_version: u32,
+    },
+    /// The status of every subscribed printer as of change `seq`, sent on connect and in answer
+    /// to `resync`, `subscribe` and `unsubscribe`.
+    Printers {
+        seq: u64,
+        printers: Vec<StatusJson>,
+    },
+    /// Fields of a printer that changed, or every field of a new printer. `seq` is counted per
+    /// connection and goes up by one with every change, a client that misses one should `resync`.
+    PrinterChanged {
+        seq: u64,
+        printer_name: String,
+        changes: StatusPatch,
+    },
+    PrinterRemoved {
+        seq: u64,
+        printer_name: String,
+    },
+    /// Something happened to a printer, see `EventKind`.
+    Event {
+        kind: EventKind,
+        printer_name: String,
+        /// The file of the job, empty if it is not known or the event is not about a job.
+        file: String,
+        message: String,
+    },
+    /// The destructive command with `request_id` was not carried out. It is once it is sent
+    /// again with `confirmation` before `expires_at`.
+    ConfirmationRequired {
+        request_id: String,
+        #[serde(flatten)]
+        request: ConfirmationRequest,
+    },
+    /// The command with `request_id` was carried out.
+    Ack {
+        request_id: String,
+    },
+    /// The message with `request_id` failed, `request_id` is missing if it could not be read.
+    Error {
+        request_id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    fn error(request_id: Option<String>, code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            request_id,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).unwrap())
+    }
+}
+
+impl From<&PrinterEvent> for ServerMessage {
+    fn from(event: &PrinterEvent) -> Self {
+        ServerMessage::Event {
+            kind: event.kind(),
+            printer_name: event.printer_name().to_string(),
+            file: event.file().to_string(),
+            message: event.message(),
+        }
+    }
+}
+
+/// What the server remembers about a connection.
+#[derive(Debug)]
+pub struct Session {
+    /// Set once the client has said `hello`.
+    pub protocol_version: Option<u32>,
+    /// The login or API token the connection was opened with, looked up for every message so
+    /// logging out, a new role or a revoked token applies to open connections too.
+    pub credential: Credential,
+    /// Where the connection came from.
+    pub address: Option<IpAddr>,
+}
+
+impl Session {
+    pub fn new(credential: Credential, address: Option<IpAddr>) -> Self {
+        Session {
+            protocol_version: None,
+            credential,
+            address,
+        }
+    }
+
+    /// Who sends the messages, `None` once the login or token is gone.
+    pub fn user(&self) -> Option<User> {
+        let mut user = self.credential.user()?;
+        user.address = self.address;
+        Some(user)
+    }
+}
+
+/// Carries out a message from a user and sends them the reply.
+pub async fn handle_message(user_id: usize, text: &str, session: &mut Session) {
+    let reply = reply_to(user_id, text, session).await;
+    if let ServerMessage::Error { code, message, .. } = &reply {
+        tracing::warn!("Message from user {user_id} failed with {code:?}: {message}");
+    }
+    socket::send_message_to_user(user_id, reply.to_message()).await;
+}
+
+async fn reply_to(user_id: usize, text: &str, session: &mut Session) -> ServerMessage {
+    let value: Value = match serde_json::from_str(text) {
+        Ok(value) => value,
+        Err(e) => return ServerMessage::error(None, ErrorCode::InvalidMessage, e.to_string()),
+    };
+    let request_id = value
+        .get("request_id")
+        .and_then(Value::as_str)
+        .map(String::from);
+    let message: ClientMessage = match serde_json::from_value(value) {
+        Ok(message) => message,
+        Err(e) => {
+            return ServerMessage::error(request_id, ErrorCode::InvalidMessage, e.to_string())
+        }
+    };
+    let request_id = message.request_id;
+    let Some(user) = session.user() else {
+        return ServerMessage::error(
+            Some(request_id),
+            ErrorCode::Unauthorized,
+            CommandError::Unauthorized.to_string(),
+        );
+    };
+    let result = match message.command {
+        ClientCommand::Hello { protocol_version } if protocol_version == PROTOCOL_VERSION => {
+            session.protocol_version = Some(protocol_version);
+            return ServerMessage::Welcome {
+                request_id,
+                protocol_version,
+            };
+        }
+        ClientCommand::Hello { protocol_version } => {
+            return ServerMessage::error(
+                Some(request_id),
+                ErrorCode::UnsupportedProtocolVersion,
+                format!(
+                    "Protocol version {protocol_version} is not supported, use {PROTOCOL_VERSION}"
+                ),
+            );
+        }
+        _ if session.protocol_version.is_none() => {
+            return ServerMessage::error(
+                Some(request_id),
+                ErrorCode::HandshakeRequired,
+                "Send hello before any other message",
+            );
+        }
+        ClientCommand::Resync => {
+            page_interface::send_snapshot(user_id).await;
+            Ok(())
+        }
+        ClientCommand::Subscribe { printers, groups } => {
+            page_interface::subscribe(user_id, printers, groups).await
+        }
+        ClientCommand::Unsubscribe { printers, groups } => {
+            page_interface::unsubscribe(user_id, printers, groups).await;
+            Ok(())
+        }
+        ClientCommand::Add { name, ip_address } => {
+            page_interface::add_printer(&user, name, ip_address).await
+        }
+        ClientCommand::Remove { name, confirmation } => {
+            page_interface::remove_printer(&user, name, confirmation).await
+        }
+        ClientCommand::Update { name, changes } => {
+            page_interface::update_printer(&user, &name, changes).await
+        }
+        ClientCommand::Acknowledge { name } => page_interface::acknowledge(&user, &name).await,
+        ClientCommand::Resume { name } => {
+            page_interface::run_print_action(&user, &name, "resume", None, None).await
+        }
+        ClientCommand::Pause { name } => {
+            page_interface::run_print_action(&user, &name, "pause", None, None).await
+        }
+        ClientCommand::Stop { name, confirmation } => {
+            page_interface::run_print_action(&user, &name, "stop", None, confirmation).await
+        }
+        ClientCommand::Start { name, file } => {
+            page_interface::run_print_action(&user, &name, "start", Some(file), None).await
+        }
+        ClientCommand::Claim { name, minutes } => {
+            page_interface::claim_printer(&user, &name, minutes).await
+        }
+        ClientCommand::Release { name } => page_interface::release_printer(&user, &name).await,
+    };
+    match result {
+        Ok(()) => ServerMessage::Ack { request_id },
+        Err(CommandError::ConfirmationRequired(request)) => ServerMessage::ConfirmationRequired {
+            request_id,
+            request,
+        },
+        Err(e) => ServerMessage::error(Some(request_id), ErrorCode::from(&e), e.to_string()),
+    }
+}
+
+#[test]
+fn test_message_format() {
+    let message: ClientMessage = serde_json::from_str(
+        r#"{"type": "start", "request_id": "7", "name": "printer1", "file": "part.ctb"}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        message,
+        ClientMessage {
+            request_id: "7".to_string(),
+            command: ClientCommand::Start {
+                name: "printer1".to_string(),
+                file: "part.ctb".to_string(),
+            },
+        }
+    );
+    // printers are only addressed by name, several can share an address
+    assert!(serde_json::from_str::<ClientMessage>(
+        r#"{"type": "pause", "request_id": "8", "ip_address": "10.0.0.2"}"#
+    )
+    .is_err());
+    assert_eq!(
+        serde_json::to_string(&ServerMessage::error(
+            Some("7".to_string()),
+            ErrorCode::UnknownPrinter,
+            "Unknown printer printer3"
+        ))
+        .unwrap(),
+        r#"{"type":"error","request_id":"7","code":"unknown_printer","message":"Unknown printer printer3"}"#
+    );
+}
+
+#[tokio::test]
+async fn test_replies() {
+    let token = crate::auth::SESSIONS
+        .lock()
+        .unwrap()
+        .start(crate::auth::User::new("viewer", crate::auth::Role::Viewer));
+    let address = IpAddr::from([10, 0, 0, 2]);
+    let mut session = Session::new(Credential::Session(token.clone()), Some(address));
+    let code = |reply: ServerMessage| match reply {
+        ServerMessage::Error {
+            request_id, code, ..
+        } => (request_id, code),
+        other => panic!("expected an error, got {other:?}"),
+    };
+    assert_eq!(
+        code(reply_to(0, "not json", &mut session).await),
+        (None, ErrorCode::InvalidMessage)
+    );
+    assert_eq!(
+        code(reply_to(0, r#"{"type": "explode", "request_id": "1"}"#, &mut session).await),
+        (Some("1".to_string()), ErrorCode::InvalidMessage)
+    );
+    let pause = r#"{"type": "pause", "request_id": "2", "name": "printer1"}"#;
+    assert_eq!(
+        code(reply_to(0, pause, &mut session).await),
+        (Some("2".to_string()), ErrorCode::HandshakeRequired)
+    );
+    assert_eq!(
+        code(
+            reply_to(
+                0,
+                r#"{"type": "hello", "request_id": "3", "protocol_version": 99}"#,
+                &mut session
+            )
+            .await
+        ),
+        (Some("3".to_string()), ErrorCode::UnsupportedProtocolVersion)
+    );
+    assert_eq!(
+        reply_to(
+            0,
+            r#"{"type": "hello", "request_id": "4", "protocol_version": 4}"#,
+            &mut session
+        )
+        .await,
+        ServerMessage::Welcome {
+            request_id: "4".to_string(),
+            protocol_version: PROTOCOL_VERSION
+        }
+    );
+    assert_eq!(session.protocol_version, Some(PROTOCOL_VERSION));
+    assert_eq!(
+        code(
+            reply_to(
+                0,
+                r#"{"type": "start", "request_id": "5", "name": "printer1"}"#,
+                &mut session
+            )
+            .await
+        ),
+        (Some("5".to_string()), ErrorCode::InvalidMessage)
+    );
+    assert_eq!(
+        code(reply_to(0, pause, &mut session).await),
+        (Some("2".to_string()), ErrorCode::Forbidden)
+    );
+    assert!(crate::audit::RECORDED
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|record| record.actor == "viewer"
+            && record.action == "pause"
+            && record.address == Some(address)
+            && record.outcome == crate::audit::AuditOutcome::Denied));
+    crate::auth::SESSIONS.lock().unwrap().end(&token);
+    assert_eq!(
+        code(reply_to(0, pause, &mut session).await),
+        (Some("2".to_string()), ErrorCode::Unauthorized)
+    );
+}
@@ -1,8 +1,9 @@
 import './App.css';
 import PrinterWidget from "./widget/printer";
-import React, {useEffect, useState} from "react";
-import useWebSocket from 'react-use-websocket';
+import React, {useEffect, useRef, useState} from "react";
+import useWebSocket, {ReadyState} from 'react-use-websocket';
 import AddPrinterWidget from "./widget/add_printer";
+import LoginWidget from "./widget/login";
 
 
 
@@ -18,7 +19,46 @@ export const useMyWebSocket = () => {
     )
 }
 
-export const App = () => {
+// version of the websocket messages this page speaks, sent to the server in "hello"
+const PROTOCOL_VERSION = 4;
+let nextRequestId = 1;
+// commands waiting for their answer by request_id, so a "confirmation_required" can be sent again
+const sentCommands = new Map();
+
+// sends a command with a fresh request_id, the server answers it with an "ack", an "error" or a
+// "confirmation_required"
+export const useSendCommand = () => {
+    const {sendJsonMessage} = useMyWebSocket();
+    return (command) => {
+        const request_id = String(nextRequestId++);
+        sentCommands.set(request_id, command);
+        sendJsonMessage({...command, request_id});
+    };
+}
+
+// applies a "printer_changed" or "printer_removed" message to the list of printers
+const applyChange = (printers, message) => {
+    const rest = printers.filter((p) => p.printer_name !== message.printer_name);
+    if (message.type === "printer_removed") {
+        return rest;
+    }
+    const old = printers.find((p) => p.printer_name === message.printer_name);
+    if (old === undefined) {
+        return [...rest, {printer_name: message.printer_name, ...message.changes}]
+            .sort((a, b) => a.printer_name.localeCompare(b.printer_name));
+    }
+    return printers.map((p) => p === old ? {...p, ...message.changes} : p);
+}
+
+// printers and groups to watch from "?printers=a,b&groups=bay1", every printer when both are left out
+const subscriptionFromUrl = () => {
+    const params = new URLSearchParams(window.location.search);
+    const list = (name) => (params.get(name) || "").split(",").filter((i) => i !== "");
+    return {printers: list("printers"), groups: list("groups")};
+}
+
+// everything the logged in user sees, the websocket is only opened once they are logged in
+const Monitor = ({user, onLogout, onSessionEnded}) => {
     const [printers, setPrinters] = useState(
         [
             // {"printer_name": "a",
@@ -30,13 +70,78 @@ export const App = () => {
             // "progress": 50},
         ]
     );
-    const {lastJsonMessage} = useMyWebSocket();
+    const [error, setError] = useState(null);
+    // seq of the last change applied to printers, patches have to follow it without a gap
+    const seq = useRef(null);
+    const {lastJsonMessage, readyState} = useMyWebSocket();
+    const sendCommand = useSendCommand();
     useEffect(() => {
-        if (lastJsonMessage !== null && Array.isArray(lastJsonMessage)) {
-            console.log(lastJsonMessage);
-            setPrinters(lastJsonMessage)
+        if (readyState === ReadyState.OPEN) {
+            sendCommand({type: "hello", protocol_version: PROTOCOL_VERSION});
+        }
+        if (readyState === ReadyState.CLOSED) {
+            // the websocket is refused once the session is gone, for example after a restart
+            fetch("/api/v1/me").then((res) => res.status === 401 && onSessionEnded());
         }
-    }, [lastJsonMessage, setPrinters])
+        // eslint-disable-next-line
+    }, [readyState])
+    useEffect(() => {
+        if (lastJsonMessage === null) {
+            return;
+        }
+        console.log(lastJsonMessage);
+        switch (lastJsonMessage.type) {
+            case "welcome": {
+                const {printers, groups} = subscriptionFromUrl();
+                if (printers.length > 0 || groups.length > 0) {
+                    sendCommand({type: "subscribe", printers, groups});
+                }
+                break;
+            }
+            case "printers":
+                seq.current = lastJsonMessage.seq;
+                setPrinters(lastJsonMessage.printers);
+                break;
+            case "printer_changed":
+            case "printer_removed":
+                if (seq.current === null || lastJsonMessage.seq <= seq.current) {
+                    break;
+                }
+                if (lastJsonMessage.seq !== seq.current + 1) {
+                    // a change was missed, start over from a fresh snapshot
+                    seq.current = null;
+                    sendCommand({type: "resync"});
+                    break;
+                }
+                seq.current = lastJsonMessage.seq;
+                setPrinters((printers) => applyChange(printers, lastJsonMessage));
+                break;
+            case "confirmation_required": {
+                const command = sentCommands.get(lastJsonMessage.request_id);
+                sentCommands.delete(lastJsonMessage.request_id);
+                // stop and remove only go ahead once they are sent again with the token
+                if (command && window.confirm(lastJsonMessage.description + ". Go ahead?")) {
+                    sendCommand({...command, confirmation: lastJsonMessage.confirmation});
+                }
+                break;
+            }
+            case "ack":
+                sentCommands.delete(lastJsonMessage.request_id);
+                setError(null);
+                break;
+            case "error":
+                sentCommands.delete(lastJsonMessage.request_id);
+                if (lastJsonMessage.code === "unauthorized") {
+                    onSessionEnded();
+                    break;
+                }
+                setError(lastJsonMessage.message);
+                break;
+            default:
+                break;
+        }
+        // eslint-disable-next-line
+    }, [lastJsonMessage, setPrinters, setError])
 
     return (
         <div style={{
@@ -45,14 +150,46 @@ export const App = () => {
             flexDirection: "row",
             flexWrap: "wrap",
         }}>
+            <div style={{width: "100%", textAlign: "right"}}>
+                {user.username} ({user.role})
+                <button style={{margin: "0 .5em"}} onClick={onLogout}>Log out</button>
+            </div>
+            {error &&
+                <div className={"error_banner"} role={"alert"} style={{width: "100%"}}>
+                    {error}
+                    <button style={{margin: "0 .5em"}} onClick={() => setError(null)}>Dismiss</button>
+                </div>
+            }
             {
                 printers.map((i) =>
-                    < PrinterWidget key={i.printer_name} {...i} />
+                    < PrinterWidget key={i.printer_name} role={user.role} username={user.username} {...i} />
                 )
             }
-            < AddPrinterWidget />
+            {user.role === "admin" && < AddPrinterWidget />}
         </div>
     );
 }
 
+export const App = () => {
+    // undefined until the server has said who is logged in, null when nobody is
+    const [user, setUser] = useState(undefined);
+    useEffect(() => {
+        fetch("/api/v1/me")
+            .then((res) => res.ok ? res.json() : null)
+            .then(setUser)
+            .catch(() => setUser(null));
+    }, [])
+    if (user === undefined) {
+        return null;
+    }
+    if (user === null) {
+        return <LoginWidget onLogin={setUser}/>;
+    }
+    return <Monitor
+        user={user}
+        onLogout={() => fetch("/api/v1/logout", {method: "POST"}).finally(() => setUser(null))}
+        onSessionEnded={() => setUser(null)}
+    />;
+}
+
 export default App;
@@ -1,8 +1,8 @@
-import {useMyWebSocket} from "../App";
+import {useSendCommand} from "../App";
 import {useState} from "react";
 
 function AddPrinterWidget() {
-    const {sendJsonMessage} = useMyWebSocket();
+    const sendCommand = useSendCommand();
     const [printerName, setPrinterName] = useState("")
     const [printerIP, setPrinterIP] = useState("")
 
@@ -11,7 +11,7 @@ function AddPrinterWidget() {
             <h1 title={"Add new printer"}>Add new printer</h1>
             <form onSubmit={
                 e => {
-                    sendJsonMessage({action: "add", ip_address: printerIP, name: printerName});
+                    sendCommand({type: "add", ip_address: printerIP, name: printerName});
                     setPrinterName("");
                     setPrinterIP("");
                     e.preventDefault();
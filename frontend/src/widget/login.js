@@ -0,0 +1,69 @@
+import {useState} from "react";
+
+function LoginWidget(props) {
+    const [username, setUsername] = useState("")
+    const [password, setPassword] = useState("")
+    const [error, setError] = useState(null)
+
+    return (
+        <div className={"printer_widget"} style={{margin: "2em auto", minHeight: "14em"}}>
+            <h1 title={"Log in"}>Log in</h1>
+            {error && <div className={"error_banner"} role={"alert"}>{error}</div>}
+            <form onSubmit={
+                e => {
+                    e.preventDefault();
+                    fetch("/api/v1/login", {
+                        method: "POST",
+                        headers: {"Content-Type": "application/json"},
+                        body: JSON.stringify({username, password}),
+                    })
+                        .then((res) => res.json().then((body) => {
+                            if (res.ok) {
+                                props.onLogin(body);
+                            } else {
+                                setError(body.error);
+                            }
+                        }))
+                        .catch(() => setError("Unable to reach the server"));
+                    setPassword("");
+                }
+            }>
+                <div style={{textAlign: "left", margin: "1em 0"}}>
+                    <label>
+                        Username
+                        <input
+                            value={username}
+                            required={true}
+                            style={{float: "right"}}
+                            name={"username"}
+                            autoComplete={"username"}
+                            onChange={e => setUsername(e.target.value)}
+                        />
+                    </label>
+                </div>
+                <div style={{textAlign: "left", margin: "1em 0"}}>
+                    <label>
+                        Password
+                        <input
+                            value={password}
+                            required={true}
+                            style={{float: "right"}}
+                            name={"password"}
+                            type={"password"}
+                            autoComplete={"current-password"}
+                            onChange={e => setPassword(e.target.value)}
+                        />
+                    </label>
+                </div>
+                <button
+                    style={{margin: "0 .5em", position: "absolute", bottom: ".5em", right: ".5em", left: ".5em"}}
+                    disabled={!username || !password}
+                >
+                    Log in
+                </button>
+            </form>
+        </div>
+    )
+}
+
+export default LoginWidget;
@@ -1,30 +1,55 @@
 import ProgressBar from "./progress_bar"
 import {useState} from "react";
-import {useMyWebSocket} from "../App";
+import {useSendCommand} from "../App";
 import { isWindows } from "react-device-detect"
 
 function PrinterWidget(props) {
-    const {sendJsonMessage} = useMyWebSocket();
+    const sendCommand = useSendCommand();
     const [fileDropDown, setFileDropDown] = useState()
     const handleChange = (e) => {
         setFileDropDown(e.target.value);
     }
     let fileWindowSubtract = isWindows ? "13em" : "11em";
+    // viewers only watch
+    const canOperate = props.role === "operator" || props.role === "admin";
+    // while someone else holds the printer only they can control its prints
+    const claimedByOther = props.claim && props.claim.holder !== props.username;
+    const canControl = canOperate && !claimedByOther;
 
     return (
         <div className={"printer_widget"}>
             <h1 style={{display: "inline"}} title={props.printer_name}>{props.printer_name}</h1>
-            <button style={{float: "right"}} onClick={() =>
-                sendJsonMessage({action: "remove", name: props.printer_name})}>X
-            </button>
+            {props.role === "admin" &&
+                <button style={{float: "right"}} onClick={() =>
+                    sendCommand({type: "remove", name: props.printer_name})}>X
+                </button>
+            }
             <p><strong>IP Address:</strong> {props.ip_address}</p>
-            <div>
+            <p>
+                {props.claim
+                    ? <span><strong>Claimed by</strong> {props.claim.holder} until {
+                        new Date(props.claim.expires_at).toLocaleTimeString()}</span>
+                    : "Not claimed"}
+                {canOperate && !claimedByOther &&
+                    <button style={{margin: "0 .5em"}} onClick={() =>
+                        sendCommand({type: "claim", name: props.printer_name})}>
+                        {props.claim ? "Renew Claim" : "Claim"}
+                    </button>
+                }
+                {props.claim && (!claimedByOther || props.role === "admin") &&
+                    <button style={{margin: "0 .5em"}} onClick={() =>
+                        sendCommand({type: "release", name: props.printer_name})}>
+                        {claimedByOther ? "Break Claim" : "Release"}
+                    </button>
+                }
+            </p>
+            <div hidden={!canControl}>
                 <button style={{margin: "0 .5em"}} onClick={() =>
-                    sendJsonMessage({action: (props.paused ? "resume" : "pause"), ip_address: props.ip_address})}>
+                    sendCommand({type: (props.paused ? "resume" : "pause"), name: props.printer_name})}>
                     {props.paused ? "Resume Printer" : "Pause Printer"}
                 </button>
                 <button style={{margin: "0 .5em"}} onClick={() =>
-                    sendJsonMessage({action: "stop", ip_address: props.ip_address})}>Stop Printer
+                    sendCommand({type: "stop", name: props.printer_name})}>Stop Printer
                 </button>
             </div>
             <div style={{inset: ".5em", width: "100%", height: `calc(100% - ${fileWindowSubtract}`}}>
@@ -41,9 +66,10 @@ function PrinterWidget(props) {
                 <ProgressBar progress={props.progress}/>
                 <button
                     style={{margin: "0 .5em"}}
+                    hidden={!canControl}
                     disabled={!fileDropDown}
                     onClick={() =>
-                        sendJsonMessage({action: "start", ip_address: props.ip_address, file: fileDropDown})
+                        sendCommand({type: "start", name: props.printer_name, file: fileDropDown})
                 }>
                     Start Print
                 </button>